@@ -0,0 +1,451 @@
+/*!
+Measures the `Stream`s per second that a peer handles, while thousands of peers are connected to
+the same bearer.
+
+The benchmark starts a bearer, a peer that offers the benchmark services and the given number of
+client peers. Every client peer connects over the bearer to the service peer, starts one
+instance of the `bench-echo` service and opens new `Stream`s to it in a loop until the duration
+elapsed. Every `Stream` sends one message, that is echoed by the service peer, and is closed
+afterwards. The first `--slow_peers` client peers start `bench-slow` in a loop instead, its
+`Server::start` blocks for `--slow_start_ms`, to show that slow starts do not stall the other
+`Stream`s.
+
+The client peers use certificates that are signed by the test peer CA on startup.
+
+    cargo run --release --example stream_benchmark -- --peers 2000 --duration 30
+*/
+extern crate bytes;
+extern crate carrier;
+extern crate futures;
+extern crate openssl;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    service::{Client, Server, ServiceContext, Streams},
+    Error, FileFormat, NewStreamHandle, Peer, PubKeyHash, RemotePeer, SendFuture,
+};
+
+use bytes::Bytes;
+
+use futures::{
+    future::{self, Either, FutureResult, Loop},
+    Future, Sink, Stream,
+};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    x509::{X509NameBuilder, X509},
+};
+
+use tokio::{
+    runtime::{Runtime, TaskExecutor},
+    timer::Delay,
+};
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "stream_benchmark")]
+struct Options {
+    /// The number of client peers that are connected to the bearer.
+    #[structopt(long = "peers", default_value = "1000")]
+    peers: usize,
+    /// The duration of the benchmark in seconds.
+    #[structopt(long = "duration", default_value = "30")]
+    duration: u64,
+    /// The number of client peers that start the slow service.
+    #[structopt(long = "slow_peers", default_value = "0")]
+    slow_peers: usize,
+    /// The time in milliseconds `Server::start` of the slow service blocks.
+    #[structopt(long = "slow_start_ms", default_value = "100")]
+    slow_start_ms: u64,
+}
+
+/// The message that is echoed over every `Stream`.
+const ECHO_DATA: &[u8] = b"carrier stream benchmark";
+
+/// Sends back everything it receives, on every `Stream`.
+struct EchoService;
+
+impl Server for EchoService {
+    type Args = ();
+
+    fn start(&mut self, _: ServiceContext, streams: Streams, _: NewStreamHandle, _: ()) {
+        tokio::spawn(
+            streams
+                .for_each(|stream| {
+                    let (sink, stream) = stream.split();
+                    tokio::spawn(
+                        stream
+                            .map(|data| data.freeze())
+                            .forward(sink)
+                            .map(|_| ())
+                            .map_err(|_| ()),
+                    );
+                    Ok(())
+                })
+                .map_err(|e| println!("Echo service error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        "bench-echo"
+    }
+}
+
+/// Opens new `Stream`s to the `EchoService` until the deadline is reached.
+/// Finishes early, when a `Stream` fails.
+struct EchoClient {
+    deadline: Instant,
+    counters: Arc<Counters>,
+}
+
+impl Client for EchoClient {
+    type Error = Error;
+    type Future = Box<SendFuture<Item = (), Error = Error>>;
+    type Args = ();
+
+    fn args(&self) {}
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future, Error> {
+        let deadline = self.deadline;
+        let counters = self.counters;
+
+        // The `Streams` are kept, so the instance keeps running between two `Stream`s.
+        Ok(Box::new(future::loop_fn(
+            (streams, new_stream_handle),
+            move |(streams, mut new_stream_handle)| {
+                if Instant::now() >= deadline {
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+
+                let counters = counters.clone();
+                Either::B(
+                    new_stream_handle
+                        .new_stream()
+                        .and_then(|stream| stream.send(Bytes::from(ECHO_DATA)))
+                        .and_then(|stream| stream.into_future().map_err(|e| e.0))
+                        .then(move |res| match res {
+                            Ok((Some(_), _)) => {
+                                counters.streams.fetch_add(1, Ordering::SeqCst);
+                                Ok(Loop::Continue((streams, new_stream_handle)))
+                            }
+                            _ => {
+                                counters.failed.fetch_add(1, Ordering::SeqCst);
+                                Ok(Loop::Break(()))
+                            }
+                        }),
+                )
+            },
+        )))
+    }
+
+    fn name(&self) -> &'static str {
+        "bench-echo"
+    }
+}
+
+/// Blocks in `start`, like a service that does expensive work before it runs.
+struct SlowService {
+    delay: Duration,
+}
+
+impl Server for SlowService {
+    type Args = ();
+
+    fn start(&mut self, _: ServiceContext, _: Streams, _: NewStreamHandle, _: ()) {
+        thread::sleep(self.delay);
+    }
+
+    fn name(&self) -> &'static str {
+        "bench-slow"
+    }
+}
+
+/// Finishes as soon as the remote side started the `SlowService`.
+struct SlowClient;
+
+impl Client for SlowClient {
+    type Error = Error;
+    type Future = FutureResult<(), Error>;
+    type Args = ();
+
+    fn args(&self) {}
+
+    fn start(
+        self,
+        _: ServiceContext,
+        _: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future, Error> {
+        Ok(future::ok(()))
+    }
+
+    fn name(&self) -> &'static str {
+        "bench-slow"
+    }
+}
+
+/// The results of the benchmark, shared by all client peers.
+#[derive(Default)]
+struct Counters {
+    /// The echoed `Stream`s.
+    streams: AtomicUsize,
+    /// The started slow service instances.
+    slow: AtomicUsize,
+    /// The failed `Stream`s and service starts.
+    failed: AtomicUsize,
+}
+
+fn main() {
+    let options = Options::from_args();
+    let mut runtime = Runtime::new().unwrap();
+
+    let bearer_port = start_bearer(runtime.executor());
+    let bearer_addr: SocketAddr = ([127, 0, 0, 1], bearer_port).into();
+    start_service_peer(
+        bearer_addr,
+        Duration::from_millis(options.slow_start_ms),
+        runtime.executor(),
+    );
+
+    let peer_cert = include_bytes!("../test_certs/peer.cert.pem");
+    let service_peer = PubKeyHash::from_x509_pem(peer_cert, false).unwrap();
+
+    println!("Connecting {} peers to the bearer", options.peers);
+    let (peers, remote_peers) =
+        create_client_peers(options.peers, bearer_addr, service_peer, &mut runtime);
+
+    println!("Running for {} seconds", options.duration);
+    let counters = Arc::new(Counters::default());
+    let duration = Duration::from_secs(options.duration);
+    let deadline = Instant::now() + duration;
+
+    let clients = remote_peers
+        .into_iter()
+        .enumerate()
+        .map(|(i, remote_peer)| {
+            run_client(
+                remote_peer,
+                i < options.slow_peers,
+                deadline,
+                counters.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    runtime.block_on(future::join_all(clients)).unwrap();
+
+    let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9;
+    let streams = counters.streams.load(Ordering::SeqCst);
+    let slow = counters.slow.load(Ordering::SeqCst);
+
+    println!("Peers:             {}", peers.len());
+    println!(
+        "Streams:           {} ({:.1}/s)",
+        streams,
+        streams as f64 / secs
+    );
+    println!("Slow starts:       {} ({:.1}/s)", slow, slow as f64 / secs);
+    println!(
+        "Failed:            {}",
+        counters.failed.load(Ordering::SeqCst)
+    );
+}
+
+/// Starts the bearer.
+/// Returns the port the bearer is listening on.
+fn start_bearer(executor: TaskExecutor) -> u16 {
+    let cert = include_bytes!("../test_certs/bearer.cert.pem");
+    let key = include_bytes!("../test_certs/bearer.key.pem");
+
+    let bearer = Peer::builder(executor.clone())
+        .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
+        .set_private_key(key.to_vec(), FileFormat::PEM)
+        .set_client_ca_cert_files(certificates("trusted_peer_cas"))
+        .build()
+        .unwrap();
+
+    let port = bearer.quic_local_addr().port();
+    executor.spawn(bearer.map_err(|e| panic!("Bearer failed: {:?}", e)));
+    port
+}
+
+/// Starts the peer that offers the benchmark services.
+fn start_service_peer(bearer_addr: SocketAddr, slow_start: Duration, executor: TaskExecutor) {
+    let cert = include_bytes!("../test_certs/peer.cert.pem");
+    let key = include_bytes!("../test_certs/peer.key.pem");
+
+    let peer = Peer::builder(executor.clone())
+        .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
+        .set_private_key(key.to_vec(), FileFormat::PEM)
+        .set_client_ca_cert_files(certificates("trusted_peer_cas"))
+        .set_server_ca_cert_files(certificates("trusted_cas"))
+        .register_service(EchoService)
+        .register_service(SlowService { delay: slow_start })
+        .add_remote_peer(bearer_addr)
+        .build()
+        .unwrap();
+
+    executor.spawn(peer.map_err(|e| panic!("Service peer failed: {:?}", e)));
+}
+
+/// Creates the client peers and connects them to the service peer.
+/// The `Peer`s need to be kept alive, while the `RemotePeer`s are used.
+fn create_client_peers(
+    num: usize,
+    bearer_addr: SocketAddr,
+    service_peer: PubKeyHash,
+    runtime: &mut Runtime,
+) -> (Vec<Peer>, Vec<RemotePeer>) {
+    let ca_cert =
+        X509::from_pem(include_bytes!("../test_certs/trusted_peer_cas/peer_ca.pem")).unwrap();
+    let ca_key =
+        PKey::private_key_from_pem(include_bytes!("../test_certs/trusted_peer_cas/peer_ca.key"))
+            .unwrap();
+
+    let connects = (0..num)
+        .map(|i| {
+            let (cert, key) = create_peer_certificate(&ca_cert, &ca_key, i).unwrap();
+
+            let peer = Peer::builder(runtime.executor())
+                .set_certificate_chain(vec![cert], FileFormat::PEM)
+                .set_private_key(key, FileFormat::PEM)
+                .set_server_ca_cert_files(certificates("trusted_cas"))
+                .add_remote_peer(bearer_addr)
+                .build()
+                .unwrap();
+
+            connect(peer, service_peer.clone())
+        })
+        .collect::<Vec<_>>();
+
+    runtime
+        .block_on(future::join_all(connects))
+        .unwrap()
+        .into_iter()
+        .unzip()
+}
+
+/// Connects the peer to the service peer.
+/// Retries, while the service peer is not yet known by the bearer.
+fn connect(
+    peer: Peer,
+    service_peer: PubKeyHash,
+) -> impl Future<Item = (Peer, RemotePeer), Error = Error> {
+    future::loop_fn((peer, 0), move |(mut peer, attempt)| {
+        peer.connect(service_peer.clone())
+            .then(move |res| match res {
+                Ok(remote_peer) => Either::A(future::ok(Loop::Break((peer, remote_peer)))),
+                Err(Error::PeerNotFound(_)) if attempt < 10 => Either::B(
+                    Delay::new(Instant::now() + Duration::from_secs(1))
+                        .map_err(|_| Error::from("Retry timer failed"))
+                        .map(move |_| Loop::Continue((peer, attempt + 1))),
+                ),
+                Err(e) => Either::A(future::err(e)),
+            })
+    })
+}
+
+/// Runs the echo or slow service over the connection, until the deadline is reached.
+/// The echo service is started again, when one of its `Stream`s failed.
+fn run_client(
+    remote_peer: RemotePeer,
+    slow: bool,
+    deadline: Instant,
+    counters: Arc<Counters>,
+) -> impl Future<Item = (), Error = Error> {
+    future::loop_fn(remote_peer, move |mut remote_peer| {
+        if Instant::now() >= deadline {
+            return Either::A(future::ok(Loop::Break(())));
+        }
+
+        let run = if slow {
+            Either::A(remote_peer.run_service(SlowClient))
+        } else {
+            Either::B(remote_peer.run_service(EchoClient {
+                deadline,
+                counters: counters.clone(),
+            }))
+        };
+
+        let counters = counters.clone();
+        Either::B(run.then(move |res| {
+            match res {
+                Ok(()) if slow => {
+                    counters.slow.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(()) => {}
+                Err(_) => {
+                    counters.failed.fetch_add(1, Ordering::SeqCst);
+                }
+            };
+
+            Ok(Loop::Continue(remote_peer))
+        }))
+    })
+}
+
+/// Creates a certificate and private key in `PEM` format that are signed by the given CA.
+fn create_peer_certificate(
+    ca_cert: &X509,
+    ca_key: &PKey<Private>,
+    num: usize,
+) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let rsa = Rsa::generate(2048)?;
+    let key = PKey::from_rsa(rsa.clone())?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", &format!("bench-peer-{}", num))?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = serial.to_asn1_integer()?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(ca_cert.subject_name())?;
+    cert.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(1)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    cert.sign(ca_key, MessageDigest::sha256())?;
+
+    Ok((cert.build().to_pem()?, rsa.private_key_to_pem()?))
+}
+
+/// Returns the certificates in the given directory of `test_certs`.
+fn certificates(dir: &str) -> Vec<std::path::PathBuf> {
+    carrier::util::glob_for_certificates(&format!(
+        "{}/test_certs/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        dir
+    ))
+    .unwrap()
+}
@@ -2,6 +2,7 @@ extern crate carrier;
 #[allow(unused)]
 #[macro_use]
 extern crate structopt;
+extern crate futures;
 extern crate pretty_env_logger;
 extern crate tokio;
 #[macro_use]
@@ -9,7 +10,9 @@ extern crate log;
 
 use tokio::runtime::Runtime;
 
-use std::path::PathBuf;
+use futures::future::{Either, Future};
+
+use std::{path::PathBuf, time::Duration};
 
 use structopt::StructOpt;
 
@@ -28,6 +31,9 @@ struct Options {
     /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
     #[structopt(long = "incoming_con_ca_path", parse(from_os_str))]
     incoming_con_ca_path: PathBuf,
+    /// The time in seconds the running services have to finish on `SIGTERM` or `SIGINT`.
+    #[structopt(long = "shutdown_timeout", default_value = "30")]
+    shutdown_timeout: u64,
 }
 
 fn main() {
@@ -39,7 +45,7 @@ fn main() {
         carrier::util::glob_for_certificates(&options.incoming_con_ca_path.display())
             .expect("Globbing for incoming connection certificate authorities(*.pem).");
 
-    let evt_loop = Runtime::new().unwrap();
+    let mut evt_loop = Runtime::new().unwrap();
 
     let builder = carrier::Peer::builder(evt_loop.executor())
         .set_quic_listen_port(options.listen_port)
@@ -52,5 +58,14 @@ fn main() {
     info!("Bearer running (Port: {})", options.listen_port);
     let bearer = builder.build().unwrap();
 
-    evt_loop.block_on_all(bearer).unwrap();
+    match evt_loop.block_on(bearer.select2(carrier::util::termination_signal())) {
+        Ok(Either::A(_)) => {}
+        Ok(Either::B((_, bearer))) => {
+            info!("Shutting down");
+            evt_loop
+                .block_on(bearer.shutdown(Duration::from_secs(options.shutdown_timeout)))
+                .unwrap();
+        }
+        Err(e) => panic!("{:?}", e.split().0),
+    }
 }
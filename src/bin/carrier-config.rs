@@ -0,0 +1,153 @@
+extern crate carrier;
+extern crate futures;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    builtin_services::{ConfigChange, ConfigStoreClient, ConfigStoreResult},
+    PubKeyHash,
+};
+
+use tokio::runtime::Runtime;
+
+use futures::{sync::mpsc::unbounded, Stream};
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Show the value and version of a key.
+    #[structopt(name = "get")]
+    Get { key: String },
+    /// Set the value of a key.
+    #[structopt(name = "set")]
+    Set {
+        key: String,
+        value: String,
+        /// Only set the value, if the current version of the key matches (0 if the key should
+        /// not exist).
+        #[structopt(long = "version")]
+        version: Option<u64>,
+    },
+    /// Delete a key.
+    #[structopt(name = "delete")]
+    Delete {
+        key: String,
+        /// Only delete the key, if its current version matches.
+        #[structopt(long = "version")]
+        version: Option<u64>,
+    },
+    /// List all keys with the given prefix.
+    #[structopt(name = "list")]
+    List {
+        #[structopt(default_value = "")]
+        prefix: String,
+    },
+    /// Show all keys with the given prefix and follow their changes.
+    #[structopt(name = "watch")]
+    Watch {
+        #[structopt(default_value = "")]
+        prefix: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-config")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let mut evt_loop = Runtime::new().unwrap();
+
+    let client = match options.command {
+        Command::Get { key } => ConfigStoreClient::get(key),
+        Command::Set {
+            key,
+            value,
+            version: Some(version),
+        } => ConfigStoreClient::compare_and_set(key, value, version),
+        Command::Set { key, value, .. } => ConfigStoreClient::set(key, value),
+        Command::Delete {
+            key,
+            version: Some(version),
+        } => ConfigStoreClient::compare_and_delete(key, version),
+        Command::Delete { key, .. } => ConfigStoreClient::delete(key),
+        Command::List { prefix } => ConfigStoreClient::list(prefix),
+        Command::Watch { prefix } => {
+            let (changes, changes_receiver) = unbounded();
+
+            evt_loop.spawn(changes_receiver.for_each(|change: ConfigChange| {
+                match change.entry {
+                    Some(entry) => println!("{} = {} ({})", change.key, entry.value, entry.version),
+                    None => println!("{} deleted", change.key),
+                }
+                Ok(())
+            }));
+
+            ConfigStoreClient::watch(prefix, changes)
+        }
+    };
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    match evt_loop
+        .block_on(peer.run_service(client, peer_key))
+        .unwrap()
+    {
+        ConfigStoreResult::Entry(Some(entry)) | ConfigStoreResult::Written(entry) => {
+            println!("{} ({})", entry.value, entry.version)
+        }
+        ConfigStoreResult::Entry(None) => println!("Not set"),
+        ConfigStoreResult::Entries(entries) => {
+            for (key, entry) in entries {
+                println!("{} = {} ({})", key, entry.value, entry.version);
+            }
+        }
+        ConfigStoreResult::Deleted => println!("Deleted"),
+        ConfigStoreResult::WatchClosed => {}
+    }
+}
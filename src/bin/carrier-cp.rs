@@ -0,0 +1,108 @@
+extern crate carrier;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    builtin_services::{FileTransferClient, FileTransferResult},
+    PubKeyHash,
+};
+
+use tokio::runtime::Runtime;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Download a file from the peer.
+    #[structopt(name = "get")]
+    Get { remote: String, local: String },
+    /// Upload a file to the peer.
+    #[structopt(name = "put")]
+    Put { local: String, remote: String },
+    /// List the entries of a directory at the peer.
+    #[structopt(name = "ls")]
+    List { remote: String },
+    /// Show the information about a file at the peer.
+    #[structopt(name = "stat")]
+    Stat { remote: String },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-cp")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let client = match options.command {
+        Command::Get { remote, local } => FileTransferClient::get(remote, local),
+        Command::Put { local, remote } => FileTransferClient::put(local, remote),
+        Command::List { remote } => FileTransferClient::list(remote),
+        Command::Stat { remote } => FileTransferClient::stat(remote),
+    };
+
+    let mut evt_loop = Runtime::new().unwrap();
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    match evt_loop
+        .block_on(peer.run_service(client, peer_key))
+        .unwrap()
+    {
+        FileTransferResult::Transferred { size } => println!("Transferred {} bytes", size),
+        FileTransferResult::Entries(entries) => {
+            for entry in entries {
+                println!(
+                    "{:>12} {}{}",
+                    entry.size,
+                    entry.name,
+                    if entry.is_dir { "/" } else { "" }
+                );
+            }
+        }
+        FileTransferResult::Info(info) => println!("{:?}", info),
+    }
+}
@@ -0,0 +1,105 @@
+extern crate carrier;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{builtin_services::ExecClient, PubKeyHash};
+
+use tokio::runtime::Runtime;
+
+use std::process;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-exec")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    /// The working directory of the command at the peer.
+    #[structopt(long = "cwd")]
+    cwd: Option<String>,
+    /// An environment variable of the command (KEY=VALUE).
+    #[structopt(short = "e", long = "env", parse(try_from_str = parse_env))]
+    env: Vec<(String, String)>,
+    /// The command that is executed at the peer.
+    #[structopt(required = true, last = true)]
+    command: Vec<String>,
+}
+
+fn parse_env(env: &str) -> Result<(String, String), String> {
+    let mut parts = env.splitn(2, '=');
+
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => Ok((key.into(), value.into())),
+        _ => Err(format!("Expected `KEY=VALUE`, got `{}`", env)),
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let client = options
+        .env
+        .into_iter()
+        .fold(ExecClient::new(options.command), |client, (key, value)| {
+            client.env(key, value)
+        });
+    let client = match options.cwd {
+        Some(cwd) => client.current_dir(cwd),
+        None => client,
+    };
+
+    let mut evt_loop = Runtime::new().unwrap();
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    let status = evt_loop
+        .block_on(peer.run_service(client, peer_key))
+        .unwrap();
+
+    // Like a shell, report a termination by a signal as 128 + signal.
+    process::exit(
+        status
+            .code
+            .or_else(|| status.signal.map(|s| 128 + s))
+            .unwrap_or(1),
+    );
+}
@@ -0,0 +1,104 @@
+extern crate carrier;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    builtin_services::{PortForwardClient, UdpForwardClient},
+    PubKeyHash,
+};
+
+use tokio::runtime::Runtime;
+
+use std::net::SocketAddr;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-forward")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    /// Listen on the local address and forward connections to the target of the peer
+    /// (LOCAL_ADDR=TARGET_ADDR).
+    #[structopt(short = "L", long = "local", parse(try_from_str = parse_forward))]
+    local: Option<(SocketAddr, SocketAddr)>,
+    /// Let the peer listen on the address and forward connections to the local target
+    /// (PEER_ADDR=TARGET_ADDR).
+    #[structopt(short = "R", long = "remote", parse(try_from_str = parse_forward))]
+    remote: Option<(SocketAddr, SocketAddr)>,
+    /// Listen on the local UDP address and forward datagrams to the target of the peer
+    /// (LOCAL_ADDR=TARGET_ADDR).
+    #[structopt(short = "U", long = "udp", parse(try_from_str = parse_forward))]
+    udp: Option<(SocketAddr, SocketAddr)>,
+}
+
+fn parse_forward(forward: &str) -> Result<(SocketAddr, SocketAddr), String> {
+    let mut addrs = forward.splitn(2, '=');
+
+    match (addrs.next(), addrs.next()) {
+        (Some(listen), Some(target)) => Ok((
+            listen.parse().map_err(|e| format!("{}: {}", listen, e))?,
+            target.parse().map_err(|e| format!("{}: {}", target, e))?,
+        )),
+        _ => Err(format!("Expected `ADDR=TARGET_ADDR`, got `{}`", forward)),
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let evt_loop = Runtime::new().unwrap();
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    match (options.local, options.remote, options.udp) {
+        (Some((listen, target)), None, None) => evt_loop
+            .block_on_all(peer.run_service(PortForwardClient::local(listen, target), peer_key))
+            .unwrap(),
+        (None, Some((bind, target)), None) => evt_loop
+            .block_on_all(peer.run_service(PortForwardClient::remote(bind, target), peer_key))
+            .unwrap(),
+        (None, None, Some((listen, target))) => evt_loop
+            .block_on_all(peer.run_service(UdpForwardClient::new(listen, target), peer_key))
+            .unwrap(),
+        _ => panic!("Please give one of `--local`, `--remote` or `--udp`."),
+    }
+}
@@ -0,0 +1,126 @@
+extern crate carrier;
+extern crate futures;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    builtin_services::{LogSource, LogsClient},
+    PubKeyHash,
+};
+
+use tokio::runtime::Runtime;
+
+use futures::{sync::mpsc::unbounded, Stream};
+
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-logs")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    /// A file at the peer that is followed.
+    #[structopt(long = "file", parse(from_os_str))]
+    files: Vec<PathBuf>,
+    /// Follow the systemd journal of the peer.
+    #[structopt(long = "journal")]
+    journal: bool,
+    /// Only show journal entries of the given systemd unit.
+    #[structopt(long = "unit")]
+    unit: Option<String>,
+    /// Only show journal entries with the given priority or a more important one (0-7).
+    #[structopt(long = "priority")]
+    priority: Option<u8>,
+    /// Only show entries that match the given regular expression.
+    #[structopt(long = "pattern")]
+    pattern: Option<String>,
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let mut sources = options
+        .files
+        .into_iter()
+        .map(LogSource::File)
+        .collect::<Vec<_>>();
+    if options.journal {
+        sources.push(LogSource::Journal);
+    }
+
+    let (entries, entries_receiver) = unbounded();
+
+    let client = LogsClient::new(sources, entries);
+    let client = match options.unit {
+        Some(unit) => client.unit(unit),
+        None => client,
+    };
+    let client = match options.priority {
+        Some(priority) => client.priority(priority),
+        None => client,
+    };
+    let client = match options.pattern {
+        Some(pattern) => client.pattern(pattern),
+        None => client,
+    };
+
+    let mut evt_loop = Runtime::new().unwrap();
+
+    evt_loop.spawn(entries_receiver.for_each(|entry| {
+        match entry.source {
+            LogSource::File(path) => println!("{}: {}", path.display(), entry.message),
+            LogSource::Journal => println!(
+                "{}: {}",
+                entry.unit.as_ref().map(|u| u.as_str()).unwrap_or("journal"),
+                entry.message
+            ),
+        }
+        Ok(())
+    }));
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    evt_loop
+        .block_on(peer.run_service(client, peer_key))
+        .unwrap();
+}
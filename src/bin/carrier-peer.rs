@@ -1,51 +1,474 @@
 extern crate carrier;
 extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate futures;
 extern crate tokio;
 #[macro_use]
 extern crate log;
 
+use carrier::{
+    builtin_services::{
+        self, ConfigStore, Exec, FileTransfer, Lifeline, Logs, PortForward, Shell, Telemetry,
+        UdpForward, Update,
+    },
+    service::ServiceAcl,
+    PubKeyHash,
+};
+
 use tokio::runtime::Runtime;
 
-use std::env::var;
+use futures::future::{Either, Future};
 
-fn main() {
-    pretty_env_logger::init();
+use std::{fs, net::SocketAddr, time::Duration};
 
-    let bearer_addr = var("CARRIER_SERVER_ADDR")
-        .expect("Please give carrier server address via `CARRIER_SERVER_ADDR`");
-    let certificate_path =
-        var("CARRIER_CERT_PATH").expect("Please give path to cert file via `CARRIER_CERT_PATH`");
-    let key_path = var("CARRIER_KEY_PATH")
-        .expect("Please give path to private key file via `CARRIER_KEY_PATH`");
+use structopt::StructOpt;
 
-    let peer_ca_path = var("CARRIER_CLIENT_CA_PATH").expect(
-        "Please give path to client certificate authorities(*.pem) via `CARRIER_CLIENT_CA_PATH`",
-    );
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-peer")]
+struct Options {
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr", env = "CARRIER_SERVER_ADDR")]
+    server_addr: String,
+    /// The path to the certificate of this peer.
+    #[structopt(long = "certificate", env = "CARRIER_CERT_PATH")]
+    certificate: String,
+    /// The path to the private key of this peer.
+    #[structopt(long = "private_key", env = "CARRIER_KEY_PATH")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path", env = "CARRIER_CLIENT_CA_PATH")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path", env = "CARRIER_SERVER_CA_PATH")]
+    server_ca_path: String,
+    /// The target lifeline connects to, if the client does not request a target.
+    #[structopt(
+        long = "lifeline_target",
+        env = "CARRIER_LIFELINE_TARGET",
+        default_value = "127.0.0.1:22"
+    )]
+    lifeline_target: SocketAddr,
+    /// Other targets lifeline clients are allowed to request.
+    #[structopt(
+        long = "lifeline_allow_target",
+        env = "CARRIER_LIFELINE_ALLOWED_TARGETS",
+        use_delimiter = true
+    )]
+    lifeline_allowed_targets: Vec<SocketAddr>,
+    /// Close lifeline sessions that did not transfer any data for the given number of seconds.
+    #[structopt(long = "lifeline_idle_timeout", env = "CARRIER_LIFELINE_IDLE_TIMEOUT")]
+    lifeline_idle_timeout: Option<u64>,
+    /// Check every given number of seconds that the client of a lifeline session is still
+    /// running, the session is closed otherwise.
+    #[structopt(long = "lifeline_keepalive", env = "CARRIER_LIFELINE_KEEPALIVE")]
+    lifeline_keepalive: Option<u64>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to start lifeline.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "lifeline_allow_peer",
+        env = "CARRIER_LIFELINE_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    lifeline_allowed_peers: Vec<String>,
+    /// The targets port forward clients are allowed to connect to.
+    #[structopt(
+        long = "port_forward_allow_target",
+        env = "CARRIER_PORT_FORWARD_ALLOWED_TARGETS",
+        use_delimiter = true
+    )]
+    port_forward_allowed_targets: Vec<SocketAddr>,
+    /// The addresses port forward clients are allowed to listen on.
+    #[structopt(
+        long = "port_forward_allow_bind",
+        env = "CARRIER_PORT_FORWARD_ALLOWED_BINDS",
+        use_delimiter = true
+    )]
+    port_forward_allowed_binds: Vec<SocketAddr>,
+    /// The targets UDP forward clients are allowed to send datagrams to.
+    #[structopt(
+        long = "udp_forward_allow_target",
+        env = "CARRIER_UDP_FORWARD_ALLOWED_TARGETS",
+        use_delimiter = true
+    )]
+    udp_forward_allowed_targets: Vec<SocketAddr>,
+    /// The programs exec clients are allowed to execute. Exec is only enabled, if at least one
+    /// program is allowed.
+    #[structopt(
+        long = "exec_allow_program",
+        env = "CARRIER_EXEC_ALLOWED_PROGRAMS",
+        use_delimiter = true
+    )]
+    exec_allowed_programs: Vec<String>,
+    /// Allow exec clients to execute any program.
+    #[structopt(long = "exec_allow_all_programs")]
+    exec_allow_all_programs: bool,
+    /// The environment variables exec clients are allowed to set.
+    #[structopt(
+        long = "exec_allow_env",
+        env = "CARRIER_EXEC_ALLOWED_ENV",
+        use_delimiter = true
+    )]
+    exec_allowed_env: Vec<String>,
+    /// The working directory of executed programs, exec clients can only request directories
+    /// inside of it.
+    #[structopt(long = "exec_working_dir", env = "CARRIER_EXEC_WORKING_DIR")]
+    exec_working_dir: Option<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to start exec.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "exec_allow_peer",
+        env = "CARRIER_EXEC_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    exec_allowed_peers: Vec<String>,
+    /// Enable the interactive shell service.
+    #[structopt(long = "enable_shell")]
+    enable_shell: bool,
+    /// The shell that is started, by default the login shell of the user that runs the peer.
+    #[structopt(long = "shell", env = "CARRIER_SHELL")]
+    shell: Option<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to start a shell.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "shell_allow_peer",
+        env = "CARRIER_SHELL_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    shell_allowed_peers: Vec<String>,
+    /// The directory file transfer clients have access to. File transfer is only enabled, if
+    /// the directory is given.
+    #[structopt(long = "file_transfer_root", env = "CARRIER_FILE_TRANSFER_ROOT")]
+    file_transfer_root: Option<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to transfer files.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "file_transfer_allow_peer",
+        env = "CARRIER_FILE_TRANSFER_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    file_transfer_allowed_peers: Vec<String>,
+    /// The path to the public key in PEM format that verifies the update images. Update is only
+    /// enabled, if the public key is given.
+    #[structopt(long = "update_public_key", env = "CARRIER_UPDATE_PUBLIC_KEY")]
+    update_public_key: Option<String>,
+    /// The directory the update images are written to, it is only accessible by the peer.
+    #[structopt(long = "update_staging_dir", env = "CARRIER_UPDATE_STAGING_DIR")]
+    update_staging_dir: Option<String>,
+    /// The program that applies a verified update image.
+    #[structopt(long = "update_apply_hook", env = "CARRIER_UPDATE_APPLY_HOOK")]
+    update_apply_hook: Option<String>,
+    /// The program that commits an applied update image.
+    #[structopt(long = "update_commit_hook", env = "CARRIER_UPDATE_COMMIT_HOOK")]
+    update_commit_hook: Option<String>,
+    /// The program that rolls back an applied update image.
+    #[structopt(long = "update_rollback_hook", env = "CARRIER_UPDATE_ROLLBACK_HOOK")]
+    update_rollback_hook: Option<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to update this peer.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "update_allow_peer",
+        env = "CARRIER_UPDATE_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    update_allowed_peers: Vec<String>,
+    /// Enable the telemetry service.
+    #[structopt(long = "enable_telemetry")]
+    enable_telemetry: bool,
+    /// The file systems telemetry reports the usage of, by default `/`.
+    #[structopt(
+        long = "telemetry_disk",
+        env = "CARRIER_TELEMETRY_DISKS",
+        use_delimiter = true
+    )]
+    telemetry_disks: Vec<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to start telemetry.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "telemetry_allow_peer",
+        env = "CARRIER_TELEMETRY_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    telemetry_allowed_peers: Vec<String>,
+    /// The files logs clients are allowed to follow.
+    #[structopt(
+        long = "logs_allow_file",
+        env = "CARRIER_LOGS_ALLOWED_FILES",
+        use_delimiter = true
+    )]
+    logs_allowed_files: Vec<String>,
+    /// Allow logs clients to follow the systemd journal.
+    #[structopt(long = "logs_enable_journal")]
+    logs_enable_journal: bool,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to follow logs.
+    /// If not given, all peers are allowed.
+    #[structopt(
+        long = "logs_allow_peer",
+        env = "CARRIER_LOGS_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    logs_allowed_peers: Vec<String>,
+    /// The file the config store is persisted to. The config store is only enabled, if the file
+    /// is given.
+    #[structopt(long = "config_store_path", env = "CARRIER_CONFIG_STORE_PATH")]
+    config_store_path: Option<String>,
+    /// The public keys(sha256 hash as hex) of the peers that are allowed to access the config
+    /// store. If not given, all peers are allowed.
+    #[structopt(
+        long = "config_store_allow_peer",
+        env = "CARRIER_CONFIG_STORE_ALLOWED_PEERS",
+        use_delimiter = true
+    )]
+    config_store_allowed_peers: Vec<String>,
+    /// The maximum number of running service instances that are started by remote peers.
+    #[structopt(long = "max_service_instances", env = "CARRIER_MAX_SERVICE_INSTANCES")]
+    max_service_instances: Option<usize>,
+    /// The maximum number of running instances of one service.
+    #[structopt(
+        long = "max_service_instances_per_service",
+        env = "CARRIER_MAX_SERVICE_INSTANCES_PER_SERVICE"
+    )]
+    max_service_instances_per_service: Option<usize>,
+    /// The maximum number of running service instances that one remote peer started.
+    #[structopt(
+        long = "max_service_instances_per_peer",
+        env = "CARRIER_MAX_SERVICE_INSTANCES_PER_PEER"
+    )]
+    max_service_instances_per_peer: Option<usize>,
+    /// The number of service instances one remote peer may start per second.
+    #[structopt(long = "service_start_rate", env = "CARRIER_SERVICE_START_RATE")]
+    service_start_rate: Option<u32>,
+    /// The number of service instances one remote peer may start at once, if
+    /// `service_start_rate` is given.
+    #[structopt(
+        long = "service_start_burst",
+        env = "CARRIER_SERVICE_START_BURST",
+        default_value = "10"
+    )]
+    service_start_burst: u32,
+    /// The time in seconds the running services have to finish on `SIGTERM` or `SIGINT`.
+    #[structopt(long = "shutdown_timeout", default_value = "30")]
+    shutdown_timeout: u64,
+}
 
-    let bearer_ca_path = var("CARRIER_SERVER_CA_PATH").expect(
-        "Please give path to server certificate authorities(*.pem) via `CARRIER_SERVER_CA_PATH`",
-    );
+/// Creates the `ServiceAcl` that allows the given peers, or all peers if none are given.
+fn peers_acl(peers: &[String]) -> ServiceAcl {
+    if peers.is_empty() {
+        ServiceAcl::allow_all()
+    } else {
+        peers.iter().fold(ServiceAcl::deny_all(), |acl, peer| {
+            acl.allow_peer(
+                PubKeyHash::from_hashed_hex(peer).expect("Creates public key from hashed hex."),
+            )
+        })
+    }
+}
 
-    let client_ca_vec = carrier::util::glob_for_certificates(&peer_ca_path)
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
         .expect("Globbing for client certificate authorities(*.pem).");
 
-    let server_ca_vec = carrier::util::glob_for_certificates(&bearer_ca_path)
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
         .expect("Globbing for server certificate authorities(*.pem).");
 
-    let evt_loop = Runtime::new().unwrap();
+    let mut evt_loop = Runtime::new().unwrap();
 
     let builder = carrier::Peer::builder(evt_loop.executor())
-        .set_certificate_chain_file(certificate_path)
-        .set_private_key_file(key_path)
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
         .set_client_ca_cert_files(client_ca_vec)
         .set_server_ca_cert_files(server_ca_vec)
-        .add_remote_peer_by_url(bearer_addr.clone()).expect("Failed to add remote peer by url");
+        .add_remote_peer_by_url(options.server_addr.clone())
+        .expect("Failed to add remote peer by url");
+
+    let builder = match options.max_service_instances {
+        Some(max) => builder.set_max_service_instances(max),
+        None => builder,
+    };
+    let builder = match options.max_service_instances_per_service {
+        Some(max) => builder.set_max_service_instances_per_service(max),
+        None => builder,
+    };
+    let builder = match options.max_service_instances_per_peer {
+        Some(max) => builder.set_max_service_instances_per_peer(max),
+        None => builder,
+    };
+    let builder = match options.service_start_rate {
+        Some(rate) => builder.set_service_start_rate(rate, options.service_start_burst),
+        None => builder,
+    };
+
+    let builder = match options.lifeline_idle_timeout {
+        Some(secs) => builder.set_service_idle_timeout("lifeline", Duration::from_secs(secs)),
+        None => builder,
+    };
+    let builder = match options.lifeline_keepalive {
+        Some(secs) => builder.set_service_keepalive("lifeline", Duration::from_secs(secs)),
+        None => builder,
+    };
+
+    let lifeline = options.lifeline_allowed_targets.iter().fold(
+        Lifeline::new().set_target(options.lifeline_target),
+        |lifeline, target| lifeline.allow_target(*target),
+    );
+
+    let port_forward = options
+        .port_forward_allowed_targets
+        .iter()
+        .fold(PortForward::new(), |port_forward, target| {
+            port_forward.allow_target(*target)
+        });
+    let port_forward = options
+        .port_forward_allowed_binds
+        .iter()
+        .fold(port_forward, |port_forward, bind| {
+            port_forward.allow_bind(*bind)
+        });
+
+    let udp_forward = options
+        .udp_forward_allowed_targets
+        .iter()
+        .fold(UdpForward::new(), |udp_forward, target| {
+            udp_forward.allow_target(*target)
+        });
+
+    let telemetry = if options.telemetry_disks.is_empty() {
+        Telemetry::new()
+    } else {
+        Telemetry::new().set_disks(options.telemetry_disks.iter().map(Into::into).collect())
+    };
+    let metrics = builder.service_metrics_handle();
+    let rejected_metrics = metrics.clone();
+    let telemetry = telemetry
+        .add_gauge("service_instances", move || {
+            metrics.metrics().running_instances as f64
+        })
+        .add_gauge("rejected_service_starts", move || {
+            rejected_metrics.metrics().rejected() as f64
+        });
+
+    let config = builtin_services::Config::new()
+        .set_lifeline(lifeline)
+        .set_port_forward(port_forward)
+        .set_udp_forward(udp_forward)
+        .set_acl("lifeline", peers_acl(&options.lifeline_allowed_peers))
+        .set_acl("exec", peers_acl(&options.exec_allowed_peers))
+        .set_acl("shell", peers_acl(&options.shell_allowed_peers))
+        .set_acl(
+            "file_transfer",
+            peers_acl(&options.file_transfer_allowed_peers),
+        )
+        .set_acl("update", peers_acl(&options.update_allowed_peers))
+        .set_acl("telemetry", peers_acl(&options.telemetry_allowed_peers))
+        .set_acl("logs", peers_acl(&options.logs_allowed_peers))
+        .set_acl("config", peers_acl(&options.config_store_allowed_peers));
+
+    let exec = options
+        .exec_allowed_env
+        .iter()
+        .fold(Exec::new(), |exec, key| exec.allow_env(key.as_str()));
+    let exec = match options.exec_working_dir {
+        Some(dir) => exec.set_working_dir(dir),
+        None => exec,
+    };
+
+    let config = if options.exec_allow_all_programs {
+        config.enable_exec(exec.allow_all_programs())
+    } else if !options.exec_allowed_programs.is_empty() {
+        config.enable_exec(
+            options
+                .exec_allowed_programs
+                .iter()
+                .fold(exec, |exec, program| exec.allow_program(program.as_str())),
+        )
+    } else {
+        config
+    };
+
+    let config = if options.enable_telemetry {
+        config.enable_telemetry(telemetry)
+    } else {
+        config
+    };
+
+    let config = match (options.enable_shell, options.shell) {
+        (true, Some(shell)) => config.enable_shell(Shell::new().set_shell(shell)),
+        (true, None) => config.enable_shell(Shell::new()),
+        (false, _) => config,
+    };
+
+    let config = match options.file_transfer_root {
+        Some(root) => config.enable_file_transfer(FileTransfer::new(root)),
+        None => config,
+    };
+
+    let config = match options.update_public_key {
+        Some(public_key) => {
+            let public_key = fs::read(public_key).expect("Reads the update public key.");
+            let update = Update::from_pem(&public_key).expect("Parses the update public key.");
+            let update = match options.update_staging_dir {
+                Some(dir) => update.set_staging_dir(dir),
+                None => update,
+            };
+            let update = match options.update_apply_hook {
+                Some(hook) => update.set_apply_hook(vec![hook]),
+                None => update,
+            };
+            let update = match options.update_commit_hook {
+                Some(hook) => update.set_commit_hook(vec![hook]),
+                None => update,
+            };
+            let update = match options.update_rollback_hook {
+                Some(hook) => update.set_rollback_hook(vec![hook]),
+                None => update,
+            };
+
+            config.enable_update(update)
+        }
+        None => config,
+    };
+
+    let config = if options.logs_enable_journal || !options.logs_allowed_files.is_empty() {
+        let logs = options
+            .logs_allowed_files
+            .iter()
+            .fold(Logs::new(), |logs, file| logs.allow_file(file.as_str()));
+
+        config.enable_logs(if options.logs_enable_journal {
+            logs.enable_journal()
+        } else {
+            logs
+        })
+    } else {
+        config
+    };
+
+    let config = match options.config_store_path {
+        Some(path) => {
+            config.enable_config_store(ConfigStore::new(path).expect("Loads the config store."))
+        }
+        None => config,
+    };
 
-    let builder = carrier::builtin_services::register(builder);
+    let builder = builtin_services::register_with_config(builder, config);
 
-    info!("Peer connects to bearer({})", bearer_addr);
+    info!("Peer connects to bearer({})", options.server_addr);
     let peer = builder.build().unwrap();
 
     info!("Peer running");
-    evt_loop.block_on_all(peer).unwrap();
+    match evt_loop.block_on(peer.select2(carrier::util::termination_signal())) {
+        Ok(Either::A(_)) => {}
+        Ok(Either::B((_, peer))) => {
+            info!("Shutting down");
+            evt_loop
+                .block_on(peer.shutdown(Duration::from_secs(options.shutdown_timeout)))
+                .unwrap();
+        }
+        Err(e) => panic!("{:?}", e.split().0),
+    }
 }
@@ -0,0 +1,115 @@
+extern crate carrier;
+extern crate futures;
+extern crate pretty_env_logger;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
+extern crate tokio;
+
+use carrier::{
+    builtin_services::{UpdateClient, UpdateStatus},
+    PubKeyHash,
+};
+
+use tokio::runtime::Runtime;
+
+use futures::{sync::mpsc::unbounded, Stream};
+
+use std::fs;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Install the image at the peer.
+    #[structopt(name = "install")]
+    Install {
+        image: String,
+        /// The path to the SHA-256 signature of the image.
+        signature: String,
+    },
+    /// Commit the installed image.
+    #[structopt(name = "commit")]
+    Commit,
+    /// Roll back the installed image.
+    #[structopt(name = "rollback")]
+    Rollback,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "carrier-update")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer.
+    #[structopt(long = "peer")]
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    #[structopt(long = "server_addr")]
+    server_addr: String,
+    /// The path to the certificate.
+    #[structopt(long = "certificate")]
+    certificate: String,
+    /// The path to the private key.
+    #[structopt(long = "private_key")]
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    #[structopt(long = "client_ca_path")]
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    #[structopt(long = "server_ca_path")]
+    server_ca_path: String,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let options = Options::from_args();
+
+    let peer_key =
+        PubKeyHash::from_hashed_hex(&options.peer).expect("Creates public key from hashed hex.");
+
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
+        .expect("Globbing for client certificate authorities(*.pem).");
+
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
+        .expect("Globbing for server certificate authorities(*.pem).");
+
+    let (progress, progress_receiver) = unbounded();
+
+    let client = match options.command {
+        Command::Install { image, signature } => UpdateClient::install(
+            image,
+            fs::read(signature).expect("Reads the signature of the image."),
+        )
+        .expect("Reads the image."),
+        Command::Commit => UpdateClient::commit(),
+        Command::Rollback => UpdateClient::rollback(),
+    }
+    .report_progress(progress);
+
+    let mut evt_loop = Runtime::new().unwrap();
+
+    evt_loop.spawn(progress_receiver.for_each(|status| {
+        if let UpdateStatus::Received { received, size } = status {
+            println!("Send {}/{} bytes", received, size);
+        }
+        Ok(())
+    }));
+
+    let mut peer = carrier::Peer::builder(evt_loop.executor())
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
+        .set_client_ca_cert_files(client_ca_vec)
+        .set_server_ca_cert_files(server_ca_vec)
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
+        .build()
+        .unwrap();
+
+    let status = evt_loop
+        .block_on(peer.run_service(client, peer_key))
+        .unwrap();
+
+    println!("{:?}", status);
+}
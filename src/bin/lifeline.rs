@@ -1,55 +1,67 @@
 extern crate carrier;
 extern crate hole_punch;
+#[allow(unused)]
+#[macro_use]
+extern crate structopt;
 extern crate tokio;
 
 use carrier::builtin_services;
 
 use tokio::runtime::Runtime;
 
-use std::env::args;
+use std::net::SocketAddr;
 
-fn main() {
-    let evt_loop = Runtime::new().unwrap();
-
-    let peer_key = args().nth(1).expect(
-        "Please give the public key(sha256 hash as hex) of the peer you want to connect to.",
-    );
-
-    let peer_key = hole_punch::PubKeyHash::from_hashed_hex(&peer_key)
-        .expect("Creates public key from hashed hex.");
-
-    let bearer_addr = args()
-        .nth(2)
-        .expect("Please give carrier server address as second argument.");
+use structopt::StructOpt;
 
-    let cert = args().nth(3).expect("Please give path to certificate.");
+#[derive(StructOpt, Debug)]
+#[structopt(name = "lifeline")]
+struct Options {
+    /// The public key(sha256 hash as hex) of the peer you want to connect to.
+    peer: String,
+    /// The address of the carrier bearer(ADDR:PORT).
+    server_addr: String,
+    /// The path to the certificate.
+    certificate: String,
+    /// The path to the private key.
+    private_key: String,
+    /// The path to trusted authorities for incoming connections in PEM format(filename: *.pem).
+    client_ca_path: String,
+    /// The path to trusted authorities for outgoing connections in PEM format(filename: *.pem).
+    server_ca_path: String,
+    /// The target(IP:PORT) the peer should connect to, instead of its default target.
+    target: Option<SocketAddr>,
+}
 
-    let key = args().nth(4).expect("Please give path to key.");
+fn main() {
+    let options = Options::from_args();
 
-    let client_ca_path = args()
-        .nth(5)
-        .expect("Please give path to client certificate authorities(*.pem)");
+    let evt_loop = Runtime::new().unwrap();
 
-    let server_ca_path = args()
-        .nth(6)
-        .expect("Please give path to server certificate authorities(*.pem)");
+    let peer_key = hole_punch::PubKeyHash::from_hashed_hex(&options.peer)
+        .expect("Creates public key from hashed hex.");
 
-    let client_ca_vec = carrier::util::glob_for_certificates(&client_ca_path)
+    let client_ca_vec = carrier::util::glob_for_certificates(&options.client_ca_path)
         .expect("Globbing for client certificate authorities(*.pem).");
 
-    let server_ca_vec = carrier::util::glob_for_certificates(&server_ca_path)
+    let server_ca_vec = carrier::util::glob_for_certificates(&options.server_ca_path)
         .expect("Globbing for server certificate authorities(*.pem).");
 
+    let lifeline = match options.target {
+        Some(target) => builtin_services::Lifeline::new().request_target(target),
+        None => builtin_services::Lifeline::new(),
+    };
+
     let mut peer = carrier::Peer::builder(evt_loop.executor())
-        .set_certificate_chain_file(cert)
-        .set_private_key_file(key)
+        .set_certificate_chain_file(options.certificate)
+        .set_private_key_file(options.private_key)
         .set_client_ca_cert_files(client_ca_vec)
         .set_server_ca_cert_files(server_ca_vec)
-        .add_remote_peer_by_url(bearer_addr.clone()).expect("Failed to add remote peer")
+        .add_remote_peer_by_url(options.server_addr)
+        .expect("Failed to add remote peer")
         .build()
         .unwrap();
 
     evt_loop
-        .block_on_all(peer.run_service(builtin_services::Lifeline::new(), peer_key))
+        .block_on_all(peer.run_service(lifeline, peer_key))
         .unwrap();
 }
@@ -0,0 +1,557 @@
+use super::blocking;
+use error::*;
+use service::{Client, Server, ServiceContext, ShutdownSignal, Streams};
+use {NewStreamHandle, ProtocolStream};
+
+use tokio;
+
+use futures::{
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use serde_json;
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    result,
+    sync::{Arc, Mutex},
+};
+
+const NAME: &str = "config";
+
+/// The arguments of `ConfigStore`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigStoreArgs {
+    /// Get the entry of the key.
+    Get { key: String },
+    /// Set the value of the key.
+    /// If `version` is given, the value is only set if it matches the current version of the key
+    /// (`0` if the key does not exist).
+    Set {
+        key: String,
+        value: String,
+        version: Option<u64>,
+    },
+    /// Delete the key, `version` works like for `Set`.
+    Delete { key: String, version: Option<u64> },
+    /// List all entries with keys that start with `prefix`.
+    List { prefix: String },
+    /// Send all entries with keys that start with `prefix` and all following changes of them.
+    Watch { prefix: String },
+}
+
+/// The value of a key and its version.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigEntry {
+    pub value: String,
+    /// The revision of the store in that the value was set. Versions are never reused, not even
+    /// when a key is deleted and set again.
+    pub version: u64,
+}
+
+/// A change of a watched key.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub key: String,
+    /// The new entry of the key, `None` if the key was deleted.
+    pub entry: Option<ConfigEntry>,
+}
+
+/// The result of a `ConfigStoreClient`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigStoreResult {
+    /// The entry of the key, `None` if the key does not exist.
+    Entry(Option<ConfigEntry>),
+    Entries(BTreeMap<String, ConfigEntry>),
+    /// The key was set to the given entry.
+    Written(ConfigEntry),
+    Deleted,
+    /// The server stopped the watch.
+    WatchClosed,
+}
+
+/// The messages that are send over the `Stream` of a service instance.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+enum ConfigStoreMessage {
+    Entry(Option<ConfigEntry>),
+    Entries(BTreeMap<String, ConfigEntry>),
+    Written(ConfigEntry),
+    Deleted,
+    Changed(ConfigChange),
+    /// The requested version does not match the `current` version of the key.
+    Conflict {
+        current: u64,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+/// The content of the file the store is persisted to.
+#[derive(Deserialize, Serialize, Default)]
+struct StoreFile {
+    revision: u64,
+    entries: BTreeMap<String, ConfigEntry>,
+}
+
+/// Writes the snapshots of the store to disk.
+struct StoreWriter {
+    path: PathBuf,
+    /// The revision of the snapshot that was written last.
+    written: Mutex<u64>,
+}
+
+impl StoreWriter {
+    /// Writes the given snapshot to disk, replacing the previous file atomically.
+    /// Snapshots that are older than the last written one are skipped, so a slow write can not
+    /// replace a newer state.
+    fn write(&self, snapshot: &StoreFile) -> io::Result<()> {
+        let mut written = self.written.lock().unwrap();
+        if snapshot.revision <= *written {
+            return Ok(());
+        }
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        *written = snapshot.revision;
+        Ok(())
+    }
+}
+
+struct Store {
+    revision: u64,
+    entries: BTreeMap<String, ConfigEntry>,
+    watchers: Vec<(String, UnboundedSender<ConfigChange>)>,
+}
+
+impl Store {
+    fn load(path: PathBuf) -> Result<(Store, StoreWriter)> {
+        let file = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => StoreFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let writer = StoreWriter {
+            path,
+            written: Mutex::new(file.revision),
+        };
+        let store = Store {
+            revision: file.revision,
+            entries: file.entries,
+            watchers: Vec::new(),
+        };
+
+        Ok((store, writer))
+    }
+
+    /// The current state of the store, that is written to disk.
+    fn snapshot(&self) -> StoreFile {
+        StoreFile {
+            revision: self.revision,
+            entries: self.entries.clone(),
+        }
+    }
+
+    fn current_version(&self, key: &str) -> u64 {
+        self.entries.get(key).map(|e| e.version).unwrap_or(0)
+    }
+
+    /// Applies the operation to the entries.
+    /// Returns the answer and the snapshot that needs to be written, if the entries changed.
+    fn apply(
+        &mut self,
+        args: ConfigStoreArgs,
+    ) -> io::Result<(ConfigStoreMessage, Option<StoreFile>)> {
+        match args {
+            ConfigStoreArgs::Get { key } => Ok((
+                ConfigStoreMessage::Entry(self.entries.get(&key).cloned()),
+                None,
+            )),
+            ConfigStoreArgs::List { prefix } => Ok((
+                ConfigStoreMessage::Entries(
+                    self.entries
+                        .iter()
+                        .filter(|(key, _)| key.starts_with(&prefix))
+                        .map(|(key, entry)| (key.clone(), entry.clone()))
+                        .collect(),
+                ),
+                None,
+            )),
+            ConfigStoreArgs::Set {
+                key,
+                value,
+                version,
+            } => {
+                let current = self.current_version(&key);
+                if version.map(|v| v != current).unwrap_or(false) {
+                    return Ok((ConfigStoreMessage::Conflict { current }, None));
+                }
+
+                self.revision += 1;
+                let entry = ConfigEntry {
+                    value,
+                    version: self.revision,
+                };
+                self.entries.insert(key.clone(), entry.clone());
+                self.notify(key, Some(entry.clone()));
+                Ok((ConfigStoreMessage::Written(entry), Some(self.snapshot())))
+            }
+            ConfigStoreArgs::Delete { key, version } => {
+                let current = self.current_version(&key);
+                if version.map(|v| v != current).unwrap_or(false) {
+                    return Ok((ConfigStoreMessage::Conflict { current }, None));
+                }
+
+                if current == 0 {
+                    return Ok((ConfigStoreMessage::Deleted, None));
+                }
+
+                self.revision += 1;
+                self.entries.remove(&key);
+                self.notify(key, None);
+                Ok((ConfigStoreMessage::Deleted, Some(self.snapshot())))
+            }
+            ConfigStoreArgs::Watch { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Watch is not a single operation",
+            )),
+        }
+    }
+
+    /// Sends the current entries with the given prefix and registers the watcher for all
+    /// following changes.
+    fn watch(&mut self, prefix: String, watcher: UnboundedSender<ConfigChange>) {
+        for (key, entry) in self
+            .entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+        {
+            let _ = watcher.unbounded_send(ConfigChange {
+                key: key.clone(),
+                entry: Some(entry.clone()),
+            });
+        }
+
+        self.watchers.push((prefix, watcher));
+    }
+
+    /// Sends the change to all interested watchers and removes the watchers that are closed.
+    fn notify(&mut self, key: String, entry: Option<ConfigEntry>) {
+        let change = ConfigChange { key, entry };
+
+        self.watchers.retain(|(prefix, watcher)| {
+            !change.key.starts_with(prefix.as_str())
+                || watcher.unbounded_send(change.clone()).is_ok()
+        });
+    }
+}
+
+/// A key/value store for the configuration of the device, persisted as JSON.
+///
+/// Every write increments the revision of the store and the written entry gets the new revision
+/// as version. Clients can give the version they expect for a write, so concurrent writers do not
+/// overwrite each other's changes unnoticed. All instances of the service share the same store.
+///
+/// A change is applied in memory and afterwards written to disk, without blocking the other
+/// instances. If the write fails, the error is reported to the client and the change is written
+/// with the next successful write.
+pub struct ConfigStore {
+    store: Arc<Mutex<Store>>,
+    writer: Arc<StoreWriter>,
+}
+
+impl ConfigStore {
+    /// Creates the store, loading the entries from the given file if it exists.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<ConfigStore> {
+        let (store, writer) = Store::load(path.into())?;
+
+        Ok(ConfigStore {
+            store: Arc::new(Mutex::new(store)),
+            writer: Arc::new(writer),
+        })
+    }
+}
+
+impl Server for ConfigStore {
+    type Args = ConfigStoreArgs;
+
+    fn check_args(&self, args: &ConfigStoreArgs) -> result::Result<(), String> {
+        match *args {
+            ConfigStoreArgs::Get { ref key }
+            | ConfigStoreArgs::Set { ref key, .. }
+            | ConfigStoreArgs::Delete { ref key, .. }
+                if key.is_empty() =>
+            {
+                Err("The key is empty.".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: ConfigStoreArgs,
+    ) {
+        debug!("Config store {:?} for {}", args, context.remote_peer());
+
+        let store = self.store.clone();
+        let writer = self.writer.clone();
+        let shutdown = streams.shutdown_signal();
+
+        tokio::spawn(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(move |(stream, _)| match stream {
+                    Some(stream) => Ok(serve(args, store, writer, stream.into(), shutdown)),
+                    None => bail!("No `Stream` for config store"),
+                })
+                .flatten()
+                .map_err(|e| error!("Config store error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Runs the requested operation and sends its result over the `Stream`.
+/// Watches are also stopped, when the local peer is shut down.
+fn serve(
+    args: ConfigStoreArgs,
+    store: Arc<Mutex<Store>>,
+    writer: Arc<StoreWriter>,
+    stream: ProtocolStream<ConfigStoreMessage>,
+    shutdown: ShutdownSignal,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    match args {
+        ConfigStoreArgs::Watch { prefix } => {
+            let (sink, stream) = stream.split();
+            let (watcher, changes) = unbounded();
+            store.lock().unwrap().watch(prefix, watcher);
+
+            let watch = changes
+                .map(ConfigStoreMessage::Changed)
+                .map_err(|_| Error::from("Config store changes closed"))
+                .forward(sink.sink_map_err(Error::from))
+                .map(|_| ())
+                // The client closes the `Stream` to stop watching.
+                .select2(stream.into_future().map_err(|e| Error::from(e.0)))
+                .map_err(|e| e.split().0);
+
+            Box::new(shutdown.run_until_shutdown(watch))
+        }
+        args => Box::new(
+            blocking(move || {
+                // Only the snapshot is taken under the lock, it is written afterwards.
+                let (msg, snapshot) = store.lock().unwrap().apply(args)?;
+                if let Some(snapshot) = snapshot {
+                    writer.write(&snapshot)?;
+                }
+                Ok(msg)
+            })
+            .or_else(|e| {
+                Ok(ConfigStoreMessage::Failed {
+                    message: e.to_string(),
+                })
+            })
+            .and_then(|msg| stream.send(msg).map_err(Error::from))
+            .map(|_| ()),
+        ),
+    }
+}
+
+/// Client side of `ConfigStore`.
+/// A write with a version that does not match fails with `Error::VersionConflict`.
+pub struct ConfigStoreClient {
+    args: ConfigStoreArgs,
+    changes: Option<UnboundedSender<ConfigChange>>,
+}
+
+impl ConfigStoreClient {
+    fn new(args: ConfigStoreArgs) -> ConfigStoreClient {
+        ConfigStoreClient {
+            args,
+            changes: None,
+        }
+    }
+
+    /// Get the entry of the key.
+    pub fn get<K: Into<String>>(key: K) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::Get { key: key.into() })
+    }
+
+    /// Set the value of the key, regardless of its current version.
+    pub fn set<K: Into<String>, V: Into<String>>(key: K, value: V) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::Set {
+            key: key.into(),
+            value: value.into(),
+            version: None,
+        })
+    }
+
+    /// Set the value of the key, if its current version is `version` (`0` to only create the key).
+    pub fn compare_and_set<K: Into<String>, V: Into<String>>(
+        key: K,
+        value: V,
+        version: u64,
+    ) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::Set {
+            key: key.into(),
+            value: value.into(),
+            version: Some(version),
+        })
+    }
+
+    /// Delete the key, regardless of its current version.
+    pub fn delete<K: Into<String>>(key: K) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::Delete {
+            key: key.into(),
+            version: None,
+        })
+    }
+
+    /// Delete the key, if its current version is `version`.
+    pub fn compare_and_delete<K: Into<String>>(key: K, version: u64) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::Delete {
+            key: key.into(),
+            version: Some(version),
+        })
+    }
+
+    /// List all entries with keys that start with `prefix`.
+    pub fn list<P: Into<String>>(prefix: P) -> ConfigStoreClient {
+        ConfigStoreClient::new(ConfigStoreArgs::List {
+            prefix: prefix.into(),
+        })
+    }
+
+    /// Watch all keys that start with `prefix`.
+    /// The current entries and all following changes are send to the given sender, dropping the
+    /// receiver stops the watch.
+    pub fn watch<P: Into<String>>(
+        prefix: P,
+        changes: UnboundedSender<ConfigChange>,
+    ) -> ConfigStoreClient {
+        ConfigStoreClient {
+            args: ConfigStoreArgs::Watch {
+                prefix: prefix.into(),
+            },
+            changes: Some(changes),
+        }
+    }
+}
+
+pub struct ConfigStoreFuture {
+    future: Box<dyn Future<Item = ConfigStoreResult, Error = Error> + Send>,
+}
+
+impl Future for ConfigStoreFuture {
+    type Item = ConfigStoreResult;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for ConfigStoreClient {
+    type Error = Error;
+    type Future = ConfigStoreFuture;
+    type Args = ConfigStoreArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let changes = self.changes;
+        let key = match self.args {
+            ConfigStoreArgs::Set { key, .. } | ConfigStoreArgs::Delete { key, .. } => key,
+            _ => String::new(),
+        };
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(stream, _)| {
+                let stream: ProtocolStream<ConfigStoreMessage> = match stream {
+                    Some(stream) => stream.into(),
+                    None => bail!("No `Stream` for config store"),
+                };
+
+                Ok(receive_result(stream, changes, key))
+            })
+            .flatten();
+
+        Ok(ConfigStoreFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Receives the result of the operation at the client.
+fn receive_result(
+    stream: ProtocolStream<ConfigStoreMessage>,
+    changes: Option<UnboundedSender<ConfigChange>>,
+    key: String,
+) -> Box<dyn Future<Item = ConfigStoreResult, Error = Error> + Send> {
+    match changes {
+        Some(changes) => Box::new(
+            stream
+                .map_err(Error::from)
+                .and_then(|msg| match msg {
+                    ConfigStoreMessage::Changed(change) => Ok(change),
+                    ConfigStoreMessage::Failed { message } => {
+                        bail!("Config store failed: {}", message)
+                    }
+                    _ => bail!("Received unexpected config store message"),
+                })
+                .forward(changes.sink_map_err(|_| Error::from("Config store receiver dropped")))
+                .map(|_| ConfigStoreResult::WatchClosed),
+        ),
+        None => Box::new(stream.into_future().map_err(|e| Error::from(e.0)).and_then(
+            move |(msg, _)| match msg {
+                Some(ConfigStoreMessage::Entry(entry)) => Ok(ConfigStoreResult::Entry(entry)),
+                Some(ConfigStoreMessage::Entries(entries)) => {
+                    Ok(ConfigStoreResult::Entries(entries))
+                }
+                Some(ConfigStoreMessage::Written(entry)) => Ok(ConfigStoreResult::Written(entry)),
+                Some(ConfigStoreMessage::Deleted) => Ok(ConfigStoreResult::Deleted),
+                Some(ConfigStoreMessage::Conflict { current }) => {
+                    Err(Error::VersionConflict { key, current })
+                }
+                Some(ConfigStoreMessage::Failed { message }) => {
+                    bail!("Config store failed: {}", message)
+                }
+                Some(_) => bail!("Received unexpected config store message"),
+                None => bail!("Config store `Stream` closed"),
+            },
+        )),
+    }
+}
@@ -0,0 +1,306 @@
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream};
+
+use tokio;
+
+use futures::{
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const NAME: &str = "events";
+
+/// The default number of events that are buffered for late subscribers.
+const DEFAULT_BUFFER_SIZE: usize = 100;
+
+/// The arguments of `Events`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct EventsArgs {
+    /// The topics the client subscribes to, all topics if empty.
+    /// A topic that ends with `*` matches all topics that start with the part before the `*`.
+    pub topics: Vec<String>,
+    /// Send the buffered events with an id greater than `since`, before the new events.
+    #[serde(default)]
+    pub since: Option<u64>,
+}
+
+/// An event that was published by a peer.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    /// The id of the event, ids are increasing and start at `1`.
+    pub id: u64,
+    pub topic: String,
+    /// The time the event was published, in seconds since the unix epoch.
+    pub timestamp: u64,
+    pub payload: Value,
+}
+
+fn matches(topics: &[String], topic: &str) -> bool {
+    topics.is_empty()
+        || topics.iter().any(|filter| {
+            if filter.ends_with('*') {
+                topic.starts_with(&filter[..filter.len() - 1])
+            } else {
+                filter == topic
+            }
+        })
+}
+
+struct Bus {
+    next_id: u64,
+    buffer: VecDeque<Event>,
+    buffer_size: usize,
+    subscribers: Vec<(Vec<String>, UnboundedSender<Event>)>,
+}
+
+impl Bus {
+    fn publish(&mut self, topic: String, payload: Value) {
+        let event = Event {
+            id: self.next_id,
+            topic,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            payload,
+        };
+        self.next_id += 1;
+
+        self.subscribers.retain(|(topics, subscriber)| {
+            !matches(topics, &event.topic) || subscriber.unbounded_send(event.clone()).is_ok()
+        });
+
+        if self.buffer_size > 0 {
+            if self.buffer.len() == self.buffer_size {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(event);
+        }
+    }
+
+    fn subscribe(&mut self, args: EventsArgs, subscriber: UnboundedSender<Event>) {
+        if let Some(since) = args.since {
+            for event in self
+                .buffer
+                .iter()
+                .filter(|e| e.id > since && matches(&args.topics, &e.topic))
+            {
+                let _ = subscriber.unbounded_send(event.clone());
+            }
+        }
+
+        self.subscribers.push((args.topics, subscriber));
+    }
+}
+
+/// Publishes events to the subscribers of an `Events` service.
+#[derive(Clone)]
+pub struct EventPublisher {
+    bus: Arc<Mutex<Bus>>,
+}
+
+impl EventPublisher {
+    /// Publish an event with the given topic and payload.
+    pub fn publish<T: Into<String>, P: Serialize>(&self, topic: T, payload: &P) -> Result<()> {
+        let payload = serde_json::to_value(payload)?;
+        self.bus.lock().unwrap().publish(topic.into(), payload);
+        Ok(())
+    }
+}
+
+/// Sends the events that are published at the local peer to the subscribed remote peers.
+///
+/// Every service instance is one subscription, the events are send over its first `Stream` until
+/// the client closes it. The last events are buffered, so clients that subscribe later or
+/// reconnect can request the events they missed.
+pub struct Events {
+    bus: Arc<Mutex<Bus>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events {
+            bus: Arc::new(Mutex::new(Bus {
+                next_id: 1,
+                buffer: VecDeque::new(),
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Set the number of events that are buffered (default 100).
+    pub fn set_buffer_size(self, size: usize) -> Self {
+        {
+            let mut bus = self.bus.lock().unwrap();
+            bus.buffer_size = size;
+            while bus.buffer.len() > size {
+                bus.buffer.pop_front();
+            }
+        }
+        self
+    }
+
+    /// Returns a publisher for the events of this service.
+    pub fn publisher(&self) -> EventPublisher {
+        EventPublisher {
+            bus: self.bus.clone(),
+        }
+    }
+}
+
+impl Default for Events {
+    fn default() -> Events {
+        Events::new()
+    }
+}
+
+impl Server for Events {
+    type Args = EventsArgs;
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: EventsArgs,
+    ) {
+        info!("Events {:?} for {}", args.topics, context.remote_peer());
+
+        // Stops sending when the local peer is shut down.
+        let shutdown = streams.shutdown_signal();
+        let bus = self.bus.clone();
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(stream, _)| match stream {
+                            Some(stream) => Ok(send_events(stream.into(), bus, args)),
+                            None => bail!("No `Stream` for events"),
+                        })
+                        .flatten(),
+                )
+                .map_err(|e| error!("Events error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Sends the subscribed events, until the client closes the `Stream`.
+fn send_events(
+    stream: ProtocolStream<Event>,
+    bus: Arc<Mutex<Bus>>,
+    args: EventsArgs,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (sink, stream) = stream.split();
+    let (subscriber, events) = unbounded();
+    bus.lock().unwrap().subscribe(args, subscriber);
+
+    events
+        .map_err(|_| Error::from("Events closed"))
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ())
+        .select2(stream.into_future().map_err(|e| Error::from(e.0)))
+        .map(|_| ())
+        .map_err(|e| e.split().0)
+}
+
+/// Client side of `Events`.
+/// Sends every received `Event` to the given sender, until the server closes the `Stream`.
+/// Dropping the receiver stops the client with an error.
+pub struct EventsClient {
+    args: EventsArgs,
+    events: UnboundedSender<Event>,
+}
+
+impl EventsClient {
+    /// Subscribe to the given topics, all topics if empty.
+    pub fn new(topics: Vec<String>, events: UnboundedSender<Event>) -> EventsClient {
+        EventsClient {
+            args: EventsArgs {
+                topics,
+                since: None,
+            },
+            events,
+        }
+    }
+
+    /// Also receive the buffered events with an id greater than `id`, `0` for all buffered
+    /// events.
+    pub fn since(mut self, id: u64) -> Self {
+        self.args.since = Some(id);
+        self
+    }
+}
+
+pub struct EventsFuture {
+    future: Box<dyn Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for EventsFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for EventsClient {
+    type Error = Error;
+    type Future = EventsFuture;
+    type Args = EventsArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let events = self.events;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(stream, _)| {
+                let stream: ProtocolStream<Event> = match stream {
+                    Some(stream) => stream.into(),
+                    None => bail!("No `Stream` for events"),
+                };
+
+                Ok(stream
+                    .map_err(Error::from)
+                    .forward(events.sink_map_err(|_| Error::from("Events receiver dropped")))
+                    .map(|_| ()))
+            })
+            .flatten();
+
+        Ok(EventsFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
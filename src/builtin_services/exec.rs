@@ -0,0 +1,470 @@
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream, Stream};
+
+use tokio::{self, io};
+
+use tokio_process::CommandExt;
+
+use futures::{
+    future::{self, Either},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use std::{
+    io::Write,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    result,
+};
+
+const NAME: &str = "exec";
+
+/// The arguments of `Exec`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExecArgs {
+    /// The program, followed by its arguments.
+    pub command: Vec<String>,
+    /// The environment variables of the process, the server only accepts the variables it
+    /// allows.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// The working directory of the process, needs to be inside the working directory of the
+    /// server.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+}
+
+/// The exit status of a process that was executed by `Exec`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecStatus {
+    /// The exit code, if the process exited normally.
+    pub code: Option<i32>,
+    /// The signal that terminated the process.
+    pub signal: Option<i32>,
+}
+
+impl ExecStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<ExitStatus> for ExecStatus {
+    fn from(status: ExitStatus) -> ExecStatus {
+        ExecStatus {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+}
+
+/// The messages that are send by the server over the control `Stream`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+enum ExecMessage {
+    /// The process exited, all output was send.
+    Exited(ExecStatus),
+    /// The process could not be started.
+    Failed { message: String },
+}
+
+/// The first message of an output `Stream`, names the output that is send over the `Stream`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+enum ExecOutput {
+    Stdout,
+    Stderr,
+}
+
+/// Executes commands for remote peers.
+///
+/// The first `Stream` of a service instance is the control `Stream`, the server sends the exit
+/// status of the process over it. Stdout and stderr of the process are send over their own
+/// `Stream`s, opened by the server. If the client closes the control `Stream`, the process is
+/// killed.
+///
+/// The server only executes the programs it allows. Programs are looked up in `SEARCH_PATH` and
+/// executed by their absolute path. The process does not inherit the environment of the server,
+/// it only receives `PATH` and the variables the server allows.
+pub struct Exec {
+    allowed_programs: Vec<String>,
+    allow_all_programs: bool,
+    allowed_env: Vec<String>,
+    working_dir: Option<PathBuf>,
+}
+
+/// The directories that programs are looked up in, also the `PATH` of every process.
+pub const SEARCH_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+impl Exec {
+    pub fn new() -> Exec {
+        Exec {
+            allowed_programs: Vec::new(),
+            allow_all_programs: false,
+            allowed_env: Vec::new(),
+            working_dir: None,
+        }
+    }
+
+    /// Allow clients to execute the given program.
+    pub fn allow_program<P: Into<String>>(mut self, program: P) -> Self {
+        self.allowed_programs.push(program.into());
+        self
+    }
+
+    /// Allow clients to execute any program.
+    pub fn allow_all_programs(mut self) -> Self {
+        self.allow_all_programs = true;
+        self
+    }
+
+    /// Allow clients to set the given environment variable.
+    pub fn allow_env<K: Into<String>>(mut self, key: K) -> Self {
+        self.allowed_env.push(key.into());
+        self
+    }
+
+    /// Set the working directory of the processes. Clients can only request working directories
+    /// inside of it. If not set, the processes run in the working directory of the server and
+    /// clients can not request a working directory.
+    pub fn set_working_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns the working directory of the process.
+    fn resolve_cwd(&self, cwd: Option<&Path>) -> result::Result<Option<PathBuf>, String> {
+        let root = match (&self.working_dir, cwd) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) => return Err("Working directories are not allowed.".into()),
+            (Some(root), _) => root
+                .canonicalize()
+                .map_err(|e| format!("Working directory of the server is not usable: {}", e))?,
+        };
+
+        let cwd = match cwd {
+            Some(cwd) => root
+                .join(cwd)
+                .canonicalize()
+                .map_err(|e| format!("Working directory `{}`: {}", cwd.display(), e))?,
+            None => return Ok(Some(root)),
+        };
+
+        if cwd.starts_with(&root) {
+            Ok(Some(cwd))
+        } else {
+            Err(format!(
+                "Working directory `{}` is not allowed.",
+                cwd.display()
+            ))
+        }
+    }
+
+    /// Builds the `Command` for the given arguments.
+    fn command(&self, args: &ExecArgs) -> result::Result<Command, String> {
+        let program = match args.command.first() {
+            None => return Err("No command given.".into()),
+            Some(program)
+                if !self.allow_all_programs && !self.allowed_programs.contains(program) =>
+            {
+                return Err(format!("Program `{}` is not allowed.", program));
+            }
+            Some(program) => resolve_program(program)
+                .ok_or_else(|| format!("Program `{}` was not found.", program))?,
+        };
+
+        if let Some((key, _)) = args
+            .env
+            .iter()
+            .find(|(key, _)| !self.allowed_env.contains(key))
+        {
+            return Err(format!("Environment variable `{}` is not allowed.", key));
+        }
+
+        let mut command = Command::new(program);
+        command
+            .args(&args.command[1..])
+            .env_clear()
+            .env("PATH", SEARCH_PATH)
+            .envs(args.env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = self.resolve_cwd(args.cwd.as_ref().map(|c| c.as_path()))? {
+            command.current_dir(cwd);
+        }
+
+        Ok(command)
+    }
+}
+
+/// Returns the absolute path of the given program.
+/// Programs without a `/` are looked up in `SEARCH_PATH`, all others need to be absolute.
+fn resolve_program(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.contains('/') {
+        let path = Path::new(program);
+        if path.is_absolute() && is_executable(path) {
+            Some(path.to_path_buf())
+        } else {
+            None
+        }
+    } else {
+        SEARCH_PATH
+            .split(':')
+            .map(|dir| Path::new(dir).join(program))
+            .find(|path| is_executable(path))
+    }
+}
+
+impl Default for Exec {
+    fn default() -> Exec {
+        Exec::new()
+    }
+}
+
+impl Server for Exec {
+    type Args = ExecArgs;
+
+    fn check_args(&self, args: &ExecArgs) -> result::Result<(), String> {
+        self.command(args).map(|_| ())
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: ExecArgs,
+    ) {
+        info!("Exec {:?} for {}", args.command, context.remote_peer());
+
+        let command = self.command(&args);
+
+        tokio::spawn(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(move |(control, _)| match control {
+                    Some(control) => Ok(run_command(control.into(), command, new_stream_handle)),
+                    None => bail!("No control `Stream` for exec"),
+                })
+                .flatten()
+                .map_err(|e| error!("Exec error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Runs the command and sends its exit status, after all output was forwarded.
+/// The command is killed, if the control `Stream` is closed before.
+fn run_command(
+    control: ProtocolStream<ExecMessage>,
+    command: result::Result<Command, String>,
+    mut new_stream_handle: NewStreamHandle,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (sink, control) = control.split();
+
+    let mut child = match command.and_then(|mut c| c.spawn_async().map_err(|e| e.to_string())) {
+        Ok(child) => child,
+        Err(message) => {
+            return Either::A(
+                sink.send(ExecMessage::Failed { message })
+                    .map(|_| ())
+                    .map_err(Error::from),
+            );
+        }
+    };
+
+    let stdout = forward_output(
+        &mut new_stream_handle,
+        ExecOutput::Stdout,
+        child.stdout().take(),
+    );
+    let stderr = forward_output(
+        &mut new_stream_handle,
+        ExecOutput::Stderr,
+        child.stderr().take(),
+    );
+
+    let exited = stdout
+        .join(stderr)
+        .and_then(move |_| child.map_err(Error::from))
+        .and_then(move |status| {
+            sink.send(ExecMessage::Exited(status.into()))
+                .map_err(Error::from)
+        })
+        .map(|_| ());
+
+    Either::B(
+        exited
+            .select(control.map_err(Error::from).for_each(|_| Ok(())))
+            .map(|_| ())
+            .map_err(|e| e.0),
+    )
+}
+
+/// Opens a new `Stream` and copies the given output to it.
+fn forward_output<R>(
+    new_stream_handle: &mut NewStreamHandle,
+    kind: ExecOutput,
+    output: Option<R>,
+) -> impl Future<Item = (), Error = Error> + Send
+where
+    R: io::AsyncRead + Send + 'static,
+{
+    let output = match output {
+        Some(output) => output,
+        None => return Either::A(future::err("Output of the process is not piped".into())),
+    };
+
+    Either::B(
+        new_stream_handle
+            .new_stream()
+            .and_then(move |stream| {
+                let stream: ProtocolStream<ExecOutput> = stream.into();
+                stream.send(kind).map_err(Error::from)
+            })
+            .and_then(move |stream| io::copy(output, Stream::from(stream)).map_err(Error::from))
+            .map(|_| ()),
+    )
+}
+
+/// Receives the output of an output `Stream` and writes it to the matching local output.
+fn receive_output(stream: Stream) -> impl Future<Item = (), Error = Error> + Send {
+    let stream: ProtocolStream<ExecOutput> = stream.into();
+
+    stream
+        .into_future()
+        .map_err(|e| Error::from(e.0))
+        .and_then(|(kind, stream)| match kind {
+            Some(kind) => Ok((kind, Stream::from(stream))),
+            None => bail!("Exec output `Stream` closed"),
+        })
+        .and_then(|(kind, stream)| {
+            stream.for_each(move |buf| match kind {
+                ExecOutput::Stdout => {
+                    std::io::stdout().write_all(&buf)?;
+                    std::io::stdout().flush().map_err(Error::from)
+                }
+                ExecOutput::Stderr => {
+                    std::io::stderr().write_all(&buf)?;
+                    std::io::stderr().flush().map_err(Error::from)
+                }
+            })
+        })
+}
+
+/// Client side of `Exec`.
+/// Writes the output of the remote process to the local stdout and stderr and resolves to the
+/// `ExecStatus` of the process.
+pub struct ExecClient {
+    args: ExecArgs,
+}
+
+impl ExecClient {
+    /// Execute the given command, the first item is the program.
+    pub fn new<I, S>(command: I) -> ExecClient
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ExecClient {
+            args: ExecArgs {
+                command: command.into_iter().map(Into::into).collect(),
+                env: Vec::new(),
+                cwd: None,
+            },
+        }
+    }
+
+    /// Set an environment variable of the process, the server needs to allow the variable.
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.args.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the working directory of the process, relative to the working directory of the server.
+    pub fn current_dir<P: Into<PathBuf>>(mut self, cwd: P) -> Self {
+        self.args.cwd = Some(cwd.into());
+        self
+    }
+}
+
+pub struct ExecFuture {
+    future: Box<dyn Future<Item = ExecStatus, Error = Error> + Send>,
+}
+
+impl Future for ExecFuture {
+    type Item = ExecStatus;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for ExecClient {
+    type Error = Error;
+    type Future = ExecFuture;
+    type Args = ExecArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(|(control, streams)| {
+                let control: ProtocolStream<ExecMessage> = match control {
+                    Some(control) => control.into(),
+                    None => bail!("No control `Stream` for exec"),
+                };
+
+                let status = control
+                    .into_future()
+                    .map_err(|e| Error::from(e.0))
+                    .and_then(|(msg, _)| match msg {
+                        Some(ExecMessage::Exited(status)) => Ok(status),
+                        Some(ExecMessage::Failed { message }) => {
+                            bail!("Exec failed to start the command: {}", message)
+                        }
+                        None => bail!("Exec control `Stream` closed"),
+                    });
+
+                // Stdout and stderr are received concurrently, so that none of them is blocked
+                // by the other.
+                let output = streams
+                    .take(2)
+                    .map(receive_output)
+                    .buffer_unordered(2)
+                    .for_each(|_| Ok(()));
+
+                Ok(status.join(output).map(|(status, _)| status))
+            })
+            .flatten();
+
+        Ok(ExecFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
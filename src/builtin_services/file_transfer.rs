@@ -0,0 +1,792 @@
+use super::{blocking, poll_blocking};
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream, Stream};
+
+use tokio;
+
+use futures::{
+    stream::SplitStream, sync::mpsc::unbounded, try_ready, Async, AsyncSink, Future, Poll, Sink,
+    Stream as FStream,
+};
+
+use openssl::sha::Sha256;
+
+use libc;
+
+use bytes::{Bytes, BytesMut};
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Component, Path, PathBuf},
+    result,
+    time::UNIX_EPOCH,
+};
+
+const NAME: &str = "file_transfer";
+
+/// The size of the chunks that are read from a file.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The operation that is requested from the server.
+/// All paths are relative to the root directory of the server.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum FileTransferArgs {
+    /// Download the file, starting at `offset`.
+    Get { path: PathBuf, offset: u64 },
+    /// Upload the file, the server continues a previous upload that was interrupted.
+    Put { path: PathBuf },
+    /// List the entries of the directory.
+    List { path: PathBuf },
+    /// Get the information about the file.
+    Stat { path: PathBuf },
+}
+
+impl FileTransferArgs {
+    fn path(&self) -> &Path {
+        match *self {
+            FileTransferArgs::Get { ref path, .. }
+            | FileTransferArgs::Put { ref path }
+            | FileTransferArgs::List { ref path }
+            | FileTransferArgs::Stat { ref path } => path,
+        }
+    }
+}
+
+/// Information about a file.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+    /// The time of the last modification, in seconds since the unix epoch.
+    pub modified: Option<u64>,
+}
+
+impl FileInfo {
+    fn new(name: OsString, metadata: &Metadata) -> FileInfo {
+        FileInfo {
+            name: name.to_string_lossy().into_owned(),
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        }
+    }
+}
+
+/// The result of a file transfer operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileTransferResult {
+    /// The file was transferred and verified, `size` is the size of the complete file.
+    Transferred { size: u64 },
+    /// The entries of a directory.
+    Entries(Vec<FileInfo>),
+    /// The information about a file.
+    Info(FileInfo),
+}
+
+/// The messages that are send over the control `Stream`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+enum FileTransferMessage {
+    /// The receiver is ready, the sender starts sending the file at `offset`.
+    Ready {
+        offset: u64,
+    },
+    /// The sender send the complete file, with the given SHA-256 digest (hex).
+    Done {
+        sha256: String,
+    },
+    /// The server verified and stored an uploaded file.
+    Verified,
+    Entries(Vec<FileInfo>),
+    Info(FileInfo),
+    Failed {
+        message: String,
+    },
+}
+
+type Control = SplitStream<ProtocolStream<FileTransferMessage>>;
+
+/// Transfers files between two peers.
+///
+/// The first `Stream` of a service instance is the control `Stream`, the content of a file is
+/// send over a second `Stream`, opened by the sender. The receiver writes the content into a
+/// `.part` file and only moves it to its destination after the SHA-256 digest was verified. If a
+/// transfer is interrupted, the next transfer of the same file continues at the end of the
+/// `.part` file, a download starts again, if the `.part` file is longer than the file. The `.part`
+/// file is locked during a transfer, concurrent transfers of the same file fail.
+///
+/// The server only gives access to the files below its root directory, symlinks that point out of
+/// it are rejected. Files are opened without following symlinks.
+pub struct FileTransfer {
+    root: PathBuf,
+}
+
+impl FileTransfer {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileTransfer {
+        FileTransfer { root: root.into() }
+    }
+
+    /// Resolves the given path relative to the root directory, following all symlinks.
+    /// Paths that would leave the root directory are rejected. The last component of the path
+    /// does not need to exist.
+    fn resolve(&self, path: &Path) -> result::Result<PathBuf, String> {
+        let leaves_root = || format!("Path `{}` leaves the root directory.", path.display());
+        let root = self
+            .root
+            .canonicalize()
+            .map_err(|e| format!("Root directory is not usable: {}", e))?;
+        let mut resolved = root.clone();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(component) => resolved.push(component),
+                Component::RootDir | Component::CurDir => {}
+                _ => return Err(leaves_root()),
+            }
+        }
+
+        let resolved = match resolved.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => match (resolved.parent(), resolved.file_name()) {
+                (Some(parent), Some(name)) => parent
+                    .canonicalize()
+                    .map_err(|e| format!("Path `{}`: {}", path.display(), e))?
+                    .join(name),
+                _ => return Err(leaves_root()),
+            },
+        };
+
+        if resolved.starts_with(&root) {
+            Ok(resolved)
+        } else {
+            Err(leaves_root())
+        }
+    }
+}
+
+impl Server for FileTransfer {
+    type Args = FileTransferArgs;
+
+    fn check_args(&self, args: &FileTransferArgs) -> result::Result<(), String> {
+        self.resolve(args.path()).map(|_| ())
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: FileTransferArgs,
+    ) {
+        info!("File transfer {:?} for {}", args, context.remote_peer());
+
+        let path = match self.resolve(args.path()) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("File transfer error: {}", e);
+                return;
+            }
+        };
+
+        tokio::spawn(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(move |(control, streams)| match control {
+                    Some(control) => Ok(serve(
+                        args,
+                        path,
+                        control.into(),
+                        streams,
+                        new_stream_handle,
+                    )),
+                    None => bail!("No control `Stream` for file transfer"),
+                })
+                .flatten()
+                .map_err(|e| error!("File transfer error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Runs the requested operation at the server and sends its result over the control `Stream`.
+fn serve(
+    args: FileTransferArgs,
+    path: PathBuf,
+    control: ProtocolStream<FileTransferMessage>,
+    streams: Streams,
+    mut new_stream_handle: NewStreamHandle,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (sink, control) = control.split();
+    let (messages, receiver) = unbounded();
+
+    let send_messages = receiver
+        .map_err(|_| Error::from("File transfer messages closed"))
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let operation: Box<dyn Future<Item = FileTransferMessage, Error = Error> + Send> = match args {
+        FileTransferArgs::Get { offset, .. } => {
+            let messages = messages.clone();
+
+            Box::new(
+                blocking(move || {
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .custom_flags(libc::O_NOFOLLOW)
+                        .open(&path)?;
+                    let size = file.metadata()?.len();
+                    Ok((file, size))
+                })
+                .map(move |(file, size)| {
+                    // The `.part` file of the client belongs to another version of the file.
+                    let offset = if offset > size {
+                        debug!(
+                            "Offset {} is beyond the end of the file, restarting",
+                            offset
+                        );
+                        0
+                    } else {
+                        offset
+                    };
+
+                    let _ = messages.unbounded_send(FileTransferMessage::Ready { offset });
+                    new_stream_handle
+                        .new_stream()
+                        .and_then(move |stream| SendFile::new(file, offset, stream))
+                })
+                .flatten()
+                .map(|(sha256, _)| FileTransferMessage::Done { sha256 }),
+            )
+        }
+        FileTransferArgs::Put { .. } => {
+            let messages = messages.clone();
+
+            Box::new(
+                open_part(part_path(&path))
+                    .and_then(move |(file, offset, part)| {
+                        let _ = messages.unbounded_send(FileTransferMessage::Ready { offset });
+                        streams
+                            .into_future()
+                            .map_err(|e| e.0)
+                            .and_then(|(data, _)| match data {
+                                Some(data) => Ok(ReceiveFile::new(file, data)),
+                                None => bail!("No data `Stream` for file transfer"),
+                            })
+                            .flatten()
+                            .map(move |received| (received, part))
+                    })
+                    .and_then(move |((received, _), part)| {
+                        next_message(control).and_then(move |(msg, _)| match msg {
+                            FileTransferMessage::Done { sha256 } => {
+                                Ok(verify(sha256, received, part, path))
+                            }
+                            _ => bail!("Received unexpected file transfer message"),
+                        })
+                    })
+                    .flatten()
+                    .map(|_| FileTransferMessage::Verified),
+            )
+        }
+        FileTransferArgs::List { .. } => Box::new(
+            blocking(move || {
+                let mut entries = Vec::new();
+                for entry in fs::read_dir(&path)? {
+                    let entry = entry?;
+                    entries.push(FileInfo::new(entry.file_name(), &entry.metadata()?));
+                }
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(entries)
+            })
+            .map(FileTransferMessage::Entries),
+        ),
+        FileTransferArgs::Stat { .. } => Box::new(
+            blocking(move || {
+                let metadata = fs::metadata(&path)?;
+                let name = path.file_name().map(Into::into).unwrap_or_default();
+                Ok(FileInfo::new(name, &metadata))
+            })
+            .map(FileTransferMessage::Info),
+        ),
+    };
+
+    let operation = operation.then(move |res| {
+        let msg = res.unwrap_or_else(|e| FileTransferMessage::Failed {
+            message: e.to_string(),
+        });
+        let _ = messages.unbounded_send(msg);
+        Ok(())
+    });
+
+    operation.join(send_messages).map(|_| ())
+}
+
+/// The path of the `.part` file for the given path.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    part.into()
+}
+
+/// A locked `.part` file, the lock is released when it is dropped.
+struct PartLock {
+    path: PathBuf,
+    _file: File,
+}
+
+/// Opens and locks the `.part` file and returns it with its current size.
+fn open_part(part: PathBuf) -> impl Future<Item = (File, u64, PartLock), Error = Error> + Send {
+    blocking(move || {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&part)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let e = io::Error::last_os_error();
+            return Err(if e.kind() == io::ErrorKind::WouldBlock {
+                io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("`{}` is already being transferred", part.display()),
+                )
+            } else {
+                e
+            });
+        }
+
+        let size = file.metadata()?.len();
+        let lock = PartLock {
+            path: part,
+            _file: file.try_clone()?,
+        };
+        Ok((file, size, lock))
+    })
+}
+
+/// Truncates the `.part` file to the offset the sender starts at.
+/// The sender starts at the beginning, if the `.part` file is longer than the file.
+fn truncate(
+    (file, size, part): (File, u64, PartLock),
+    offset: u64,
+) -> impl Future<Item = (File, u64, PartLock), Error = Error> + Send {
+    blocking(move || {
+        if size > offset {
+            file.set_len(offset)?;
+        }
+        Ok((file, offset, part))
+    })
+}
+
+/// Moves the `.part` file to its destination, if the digests match. Otherwise the `.part` file
+/// is removed, a broken `.part` file would prevent all further transfers.
+fn verify(
+    expected: String,
+    received: String,
+    part: PartLock,
+    path: PathBuf,
+) -> impl Future<Item = (), Error = Error> + Send {
+    blocking(move || {
+        // The lock is held, until the `.part` file is moved or removed.
+        if expected == received {
+            fs::rename(&part.path, &path).map(|_| true)
+        } else {
+            fs::remove_file(&part.path).map(|_| false)
+        }
+    })
+    .and_then(|verified| {
+        if verified {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch)
+        }
+    })
+}
+
+/// Receives the next message from the control `Stream`.
+fn next_message(
+    control: Control,
+) -> impl Future<Item = (FileTransferMessage, Control), Error = Error> + Send {
+    control
+        .into_future()
+        .map_err(|e| Error::from(e.0))
+        .and_then(|(msg, control)| match msg {
+            Some(FileTransferMessage::Failed { message }) => {
+                bail!("File transfer failed: {}", message)
+            }
+            Some(msg) => Ok((msg, control)),
+            None => bail!("File transfer control `Stream` closed"),
+        })
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sends a file over a `Stream`, starting at the given offset.
+/// Resolves to the SHA-256 digest and the size of the complete file.
+pub(super) struct SendFile {
+    file: File,
+    offset: u64,
+    position: u64,
+    hasher: Option<Sha256>,
+    stream: Stream,
+    buf: Vec<u8>,
+    chunk: Option<Bytes>,
+}
+
+impl SendFile {
+    pub(super) fn new(file: File, offset: u64, stream: Stream) -> SendFile {
+        SendFile {
+            file,
+            offset,
+            position: 0,
+            hasher: Some(Sha256::new()),
+            stream,
+            buf: vec![0; CHUNK_SIZE],
+            chunk: None,
+        }
+    }
+}
+
+impl Future for SendFile {
+    type Item = (String, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(chunk) = self.chunk.take() {
+                if let AsyncSink::NotReady(chunk) = self.stream.start_send(chunk)? {
+                    self.chunk = Some(chunk);
+                    try_ready!(self.stream.poll_complete());
+                    continue;
+                }
+            }
+
+            let len = {
+                let file = &mut self.file;
+                let buf = &mut self.buf;
+                try_ready!(poll_blocking(|| file.read(buf)))
+            };
+
+            if len == 0 {
+                try_ready!(self.stream.poll_complete());
+                let digest = self
+                    .hasher
+                    .take()
+                    .expect("Polled after completion")
+                    .finish();
+                return Ok(Async::Ready((to_hex(&digest), self.position)));
+            }
+
+            let data = &self.buf[..len];
+            self.hasher
+                .as_mut()
+                .expect("Polled after completion")
+                .update(data);
+
+            // The receiver already has the data before the offset.
+            let end = self.position + len as u64;
+            if end > self.offset {
+                let skip = self.offset.saturating_sub(self.position) as usize;
+                self.chunk = Some(Bytes::from(&data[skip..]));
+            }
+            self.position = end;
+        }
+    }
+}
+
+/// Receives a file from a `Stream` and appends it to the given file.
+/// Resolves to the SHA-256 digest and the size of the complete file, including the data that
+/// was in the file before.
+struct ReceiveFile {
+    file: File,
+    size: u64,
+    hasher: Option<Sha256>,
+    stream: Stream,
+    existing_hashed: bool,
+    chunk: Option<BytesMut>,
+}
+
+impl ReceiveFile {
+    fn new(file: File, stream: Stream) -> ReceiveFile {
+        ReceiveFile {
+            file,
+            size: 0,
+            hasher: Some(Sha256::new()),
+            stream,
+            existing_hashed: false,
+            chunk: None,
+        }
+    }
+}
+
+impl Future for ReceiveFile {
+    type Item = (String, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.existing_hashed {
+            let file = &mut self.file;
+            let hasher = self.hasher.as_mut().expect("Polled after completion");
+            self.size = try_ready!(poll_blocking(|| {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let mut size = 0;
+                loop {
+                    match file.read(&mut buf)? {
+                        0 => return Ok(size),
+                        len => {
+                            hasher.update(&buf[..len]);
+                            size += len as u64;
+                        }
+                    }
+                }
+            }));
+            self.existing_hashed = true;
+        }
+
+        loop {
+            if let Some(chunk) = self.chunk.take() {
+                let file = &mut self.file;
+                let res = poll_blocking(|| file.write_all(&chunk))?;
+                if res.is_not_ready() {
+                    self.chunk = Some(chunk);
+                    return Ok(Async::NotReady);
+                }
+
+                self.hasher
+                    .as_mut()
+                    .expect("Polled after completion")
+                    .update(&chunk);
+                self.size += chunk.len() as u64;
+            }
+
+            match try_ready!(self.stream.poll()) {
+                Some(chunk) => self.chunk = Some(chunk),
+                None => {
+                    let file = &mut self.file;
+                    try_ready!(poll_blocking(|| file.sync_all()));
+                    let digest = self
+                        .hasher
+                        .take()
+                        .expect("Polled after completion")
+                        .finish();
+                    return Ok(Async::Ready((to_hex(&digest), self.size)));
+                }
+            }
+        }
+    }
+}
+
+enum Operation {
+    Get { local: PathBuf },
+    Put { local: PathBuf },
+    List,
+    Stat,
+}
+
+/// Client side of `FileTransfer`.
+pub struct FileTransferClient {
+    args: FileTransferArgs,
+    operation: Operation,
+}
+
+impl FileTransferClient {
+    /// Download the `remote` file to `local`.
+    /// An interrupted download is continued, if the `.part` file of `local` exists.
+    pub fn get<R: Into<PathBuf>, L: Into<PathBuf>>(remote: R, local: L) -> FileTransferClient {
+        let local = local.into();
+        let offset = fs::metadata(part_path(&local))
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        FileTransferClient {
+            args: FileTransferArgs::Get {
+                path: remote.into(),
+                offset,
+            },
+            operation: Operation::Get { local },
+        }
+    }
+
+    /// Upload the `local` file to `remote`.
+    pub fn put<L: Into<PathBuf>, R: Into<PathBuf>>(local: L, remote: R) -> FileTransferClient {
+        FileTransferClient {
+            args: FileTransferArgs::Put {
+                path: remote.into(),
+            },
+            operation: Operation::Put {
+                local: local.into(),
+            },
+        }
+    }
+
+    /// List the entries of the `remote` directory.
+    pub fn list<R: Into<PathBuf>>(remote: R) -> FileTransferClient {
+        FileTransferClient {
+            args: FileTransferArgs::List {
+                path: remote.into(),
+            },
+            operation: Operation::List,
+        }
+    }
+
+    /// Get the information about the `remote` file.
+    pub fn stat<R: Into<PathBuf>>(remote: R) -> FileTransferClient {
+        FileTransferClient {
+            args: FileTransferArgs::Stat {
+                path: remote.into(),
+            },
+            operation: Operation::Stat,
+        }
+    }
+}
+
+pub struct FileTransferFuture {
+    future: Box<dyn Future<Item = FileTransferResult, Error = Error> + Send>,
+}
+
+impl Future for FileTransferFuture {
+    type Item = FileTransferResult;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for FileTransferClient {
+    type Error = Error;
+    type Future = FileTransferFuture;
+    type Args = FileTransferArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let operation = self.operation;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(control, streams)| {
+                let control: ProtocolStream<FileTransferMessage> = match control {
+                    Some(control) => control.into(),
+                    None => bail!("No control `Stream` for file transfer"),
+                };
+
+                Ok(run_operation(
+                    operation,
+                    control,
+                    streams,
+                    new_stream_handle,
+                ))
+            })
+            .flatten();
+
+        Ok(FileTransferFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Runs the operation at the client.
+fn run_operation(
+    operation: Operation,
+    control: ProtocolStream<FileTransferMessage>,
+    streams: Streams,
+    mut new_stream_handle: NewStreamHandle,
+) -> Box<dyn Future<Item = FileTransferResult, Error = Error> + Send> {
+    let (sink, control) = control.split();
+
+    match operation {
+        Operation::Get { local } => {
+            let part = part_path(&local);
+
+            Box::new(
+                next_message(control)
+                    .and_then(move |(msg, control)| match msg {
+                        FileTransferMessage::Ready { offset } => Ok((
+                            open_part(part).and_then(move |p| truncate(p, offset)),
+                            control,
+                        )),
+                        _ => bail!("Received unexpected file transfer message"),
+                    })
+                    .and_then(|(file, control)| {
+                        file.join(streams.into_future().map_err(|e| e.0))
+                            .and_then(|((file, _, part), (data, _))| match data {
+                                Some(data) => {
+                                    Ok(ReceiveFile::new(file, data).map(move |r| (r, part)))
+                                }
+                                None => bail!("No data `Stream` for file transfer"),
+                            })
+                            .flatten()
+                            .map(move |received| (received, control))
+                    })
+                    .and_then(move |(((received, size), part), control)| {
+                        next_message(control).and_then(move |(msg, _)| match msg {
+                            FileTransferMessage::Done { sha256 } => {
+                                Ok(verify(sha256, received, part, local)
+                                    .map(move |_| FileTransferResult::Transferred { size }))
+                            }
+                            _ => bail!("Received unexpected file transfer message"),
+                        })
+                    })
+                    .flatten(),
+            )
+        }
+        Operation::Put { local } => Box::new(
+            blocking(move || File::open(&local))
+                .join(next_message(control))
+                .and_then(move |(file, (msg, control))| match msg {
+                    FileTransferMessage::Ready { offset } => Ok(new_stream_handle
+                        .new_stream()
+                        .and_then(move |stream| SendFile::new(file, offset, stream))
+                        .map(move |sent| (sent, control))),
+                    _ => bail!("Received unexpected file transfer message"),
+                })
+                .flatten()
+                .and_then(|((sha256, size), control)| {
+                    sink.send(FileTransferMessage::Done { sha256 })
+                        .map_err(Error::from)
+                        .and_then(|_| next_message(control))
+                        .and_then(move |(msg, _)| match msg {
+                            FileTransferMessage::Verified => {
+                                Ok(FileTransferResult::Transferred { size })
+                            }
+                            _ => bail!("Received unexpected file transfer message"),
+                        })
+                }),
+        ),
+        Operation::List => Box::new(next_message(control).and_then(|(msg, _)| match msg {
+            FileTransferMessage::Entries(entries) => Ok(FileTransferResult::Entries(entries)),
+            _ => bail!("Received unexpected file transfer message"),
+        })),
+        Operation::Stat => Box::new(next_message(control).and_then(|(msg, _)| match msg {
+            FileTransferMessage::Info(info) => Ok(FileTransferResult::Info(info)),
+            _ => bail!("Received unexpected file transfer message"),
+        })),
+    }
+}
@@ -1,52 +1,108 @@
+use super::pipe;
 use error::*;
-use service::{Client, Server, Streams};
+use service::{Client, Server, ServiceContext, Streams};
 use {NewStreamHandle, Stream};
 
 use tokio::net::TcpStream;
 
-use tokio::{
-    self,
-    io::{self, AsyncRead},
-};
+use tokio::{self, io::AsyncRead};
 
 use tokio_file_unix;
 
 use futures::{stream, Future, Poll, Sink, Stream as FStream};
 
-use std::io::Write;
+use std::{io::Write, net::SocketAddr, result};
 
 use bytes::Bytes;
 
-pub struct Lifeline {}
+/// Forwards a `Stream` to a local TCP server, by default the ssh server at `127.0.0.1:22`.
+pub struct Lifeline {
+    /// The target the server connects to, if the client does not request a target.
+    target: SocketAddr,
+    /// The other targets a client is allowed to request.
+    allowed_targets: Vec<SocketAddr>,
+    /// The target the client requests from the server.
+    requested_target: Option<SocketAddr>,
+}
 
 impl Lifeline {
     pub fn new() -> Lifeline {
-        Lifeline {}
+        Lifeline {
+            target: ([127, 0, 0, 1], 22).into(),
+            allowed_targets: Vec::new(),
+            requested_target: None,
+        }
+    }
+
+    /// Set the target the server connects to, if the client does not request a target.
+    pub fn set_target(mut self, target: SocketAddr) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Allow clients to request the given target from the server.
+    pub fn allow_target(mut self, target: SocketAddr) -> Self {
+        self.allowed_targets.push(target);
+        self
+    }
+
+    /// Request the given target from the server.
+    /// The server only accepts its default target and the targets it allows.
+    pub fn request_target(mut self, target: SocketAddr) -> Self {
+        self.requested_target = Some(target);
+        self
+    }
+}
+
+impl Default for Lifeline {
+    fn default() -> Lifeline {
+        Lifeline::new()
     }
 }
 
 impl Server for Lifeline {
-    fn start(&mut self, streams: Streams, _: NewStreamHandle) {
+    type Args = Option<SocketAddr>;
+
+    fn check_args(&self, target: &Option<SocketAddr>) -> result::Result<(), String> {
+        match *target {
+            Some(target) if target != self.target && !self.allowed_targets.contains(&target) => {
+                Err(format!("Target {} is not allowed.", target))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        target: Option<SocketAddr>,
+    ) {
+        let target = target.unwrap_or(self.target);
+        info!(
+            "Lifeline session for {} ({}) to {}",
+            context.remote_peer(),
+            context.remote_addr(),
+            target
+        );
+
+        let shutdown = streams.shutdown_signal();
+
         tokio::spawn(
-            streams
-                .into_future()
-                .map_err(|e| e.0)
-                .and_then(move |(stream, _)| match stream {
-                    Some(stream) => Ok(TcpStream::connect(&([127, 0, 0, 1], 22).into())
-                        .map_err(|e| e.into())
-                        .and_then(move |tcp| {
-                            let (read, write) = AsyncRead::split(stream);
-                            let (read2, write2) = tcp.split();
-
-                            io::copy(read, write2)
-                                .map(|_| ())
-                                .select(io::copy(read2, write).map(|_| ()))
-                                .map(|_| ())
-                                .map_err(|e| Error::from(e.0))
-                        })),
-                    None => bail!("No `Stream` for Lifeline"),
-                })
-                .flatten()
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(stream, _)| match stream {
+                            Some(stream) => Ok(TcpStream::connect(&target)
+                                .map_err(|e| e.into())
+                                .and_then(move |tcp| pipe(stream, tcp))),
+                            None => bail!("No `Stream` for Lifeline"),
+                        })
+                        .flatten(),
+                )
                 .map_err(|e| error!("Lifeline error: {:?}", e)),
         );
     }
@@ -56,14 +112,15 @@ impl Server for Lifeline {
     }
 }
 
-struct StdinReader<R: AsyncRead> {
+/// Sends everything that is read from stdin to the given sink.
+pub(super) struct StdinReader<R: AsyncRead> {
     stdin: R,
     sink: stream::SplitSink<Stream>,
     buf: Vec<u8>,
 }
 
 impl<R: AsyncRead> StdinReader<R> {
-    fn new(stdin: R, sink: stream::SplitSink<Stream>) -> StdinReader<R> {
+    pub(super) fn new(stdin: R, sink: stream::SplitSink<Stream>) -> StdinReader<R> {
         StdinReader {
             stdin,
             sink,
@@ -137,8 +194,18 @@ impl Future for LifelineClientFuture {
 impl Client for Lifeline {
     type Error = Error;
     type Future = LifelineClientFuture;
+    type Args = Option<SocketAddr>;
+
+    fn args(&self) -> Self::Args {
+        self.requested_target
+    }
 
-    fn start(self, streams: Streams, _: NewStreamHandle) -> Result<Self::Future> {
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
         LifelineClientFuture::new(streams)
     }
 
@@ -0,0 +1,456 @@
+use super::blocking;
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream};
+
+use tokio::{
+    self,
+    io::{lines, Lines},
+    timer::Interval,
+};
+
+use tokio_process::{Child, ChildStdout, CommandExt};
+
+use futures::{stream, sync::mpsc::UnboundedSender, Future, Poll, Sink, Stream as FStream};
+
+use regex::Regex;
+
+use serde_json::{self, Value};
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    result,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const NAME: &str = "logs";
+
+/// The interval in that followed files are checked for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The maximum length of an entry of a followed file, longer lines are split into multiple
+/// entries.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// The maximum number of bytes that are read from a followed file per interval.
+const MAX_READ_LENGTH: u64 = 1024 * 1024;
+
+/// A source of log entries.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum LogSource {
+    /// A plain log file, one entry per line.
+    File(PathBuf),
+    /// The systemd journal.
+    Journal,
+}
+
+/// The arguments of `Logs`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogsArgs {
+    /// The sources that are followed.
+    pub sources: Vec<LogSource>,
+    /// Only send journal entries of the given systemd unit.
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// Only send journal entries with the given priority or a more important one (`0`-`7`).
+    #[serde(default)]
+    pub priority: Option<u8>,
+    /// Only send entries with a message that matches the given regular expression.
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+/// A log entry.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub source: LogSource,
+    /// The systemd unit of a journal entry.
+    pub unit: Option<String>,
+    /// The priority of a journal entry.
+    pub priority: Option<u8>,
+    /// The time of a journal entry, in microseconds since the unix epoch.
+    pub timestamp: Option<u64>,
+    pub message: String,
+}
+
+/// Streams new log entries to remote peers.
+///
+/// The entries are send over the first `Stream` of a service instance, until the client closes
+/// it. The server only follows the files it allows and the journal, if it is enabled.
+pub struct Logs {
+    allowed_files: Vec<PathBuf>,
+    journal: bool,
+}
+
+impl Logs {
+    pub fn new() -> Logs {
+        Logs {
+            allowed_files: Vec::new(),
+            journal: false,
+        }
+    }
+
+    /// Allow clients to follow the given file.
+    pub fn allow_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.allowed_files.push(path.into());
+        self
+    }
+
+    /// Allow clients to follow the systemd journal, using `journalctl`.
+    pub fn enable_journal(mut self) -> Self {
+        self.journal = true;
+        self
+    }
+}
+
+impl Default for Logs {
+    fn default() -> Logs {
+        Logs::new()
+    }
+}
+
+impl Server for Logs {
+    type Args = LogsArgs;
+
+    fn check_args(&self, args: &LogsArgs) -> result::Result<(), String> {
+        for source in &args.sources {
+            match *source {
+                LogSource::File(ref path) if !self.allowed_files.contains(path) => {
+                    return Err(format!("File `{}` is not allowed.", path.display()))
+                }
+                LogSource::Journal if !self.journal => {
+                    return Err("The journal is not enabled.".into())
+                }
+                _ => {}
+            }
+        }
+
+        match args.pattern {
+            Some(ref pattern) => Regex::new(pattern).map(|_| ()).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: LogsArgs,
+    ) {
+        info!("Logs {:?} for {}", args.sources, context.remote_peer());
+
+        // Stops sending when the local peer is shut down.
+        let shutdown = streams.shutdown_signal();
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(stream, _)| match stream {
+                            Some(stream) => Ok(send_entries(stream.into(), args)),
+                            None => bail!("No `Stream` for logs"),
+                        })
+                        .flatten()
+                        .flatten(),
+                )
+                .map_err(|e| error!("Logs error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+type EntryStream = Box<dyn FStream<Item = LogEntry, Error = Error> + Send>;
+
+/// Sends the entries of all sources that match the filter, until the `Stream` is closed.
+fn send_entries(
+    stream: ProtocolStream<LogEntry>,
+    args: LogsArgs,
+) -> Result<impl Future<Item = (), Error = Error> + Send> {
+    let pattern = match args.pattern {
+        Some(ref pattern) => Some(Regex::new(pattern).map_err(|e| format_err!("{}", e))?),
+        None => None,
+    };
+
+    let mut entries: EntryStream = Box::new(stream::empty());
+    for source in args.sources.iter().cloned() {
+        let source: EntryStream = match source {
+            LogSource::File(path) => Box::new(follow_file(path)),
+            LogSource::Journal => Box::new(follow_journal(args.unit.clone(), args.priority)?),
+        };
+        entries = Box::new(entries.select(source));
+    }
+
+    Ok(entries
+        .filter(move |entry| match pattern {
+            Some(ref pattern) => pattern.is_match(&entry.message),
+            None => true,
+        })
+        .forward(stream.sink_map_err(Error::from))
+        .map(|_| ()))
+}
+
+/// Follows a file like `tail -F`.
+/// The file is opened again, if it was replaced or truncated.
+struct FileFollower {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    position: u64,
+    /// The start of a line that is not terminated yet, at most `MAX_LINE_LENGTH` bytes.
+    partial: Vec<u8>,
+}
+
+impl FileFollower {
+    fn new(path: PathBuf) -> FileFollower {
+        FileFollower {
+            path,
+            file: None,
+            inode: 0,
+            position: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Reads the lines that were appended since the last call.
+    /// Lines that are longer than `MAX_LINE_LENGTH` are split.
+    fn read_lines(&mut self) -> io::Result<Vec<String>> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // The file may be in the middle of being rotated.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        if self.file.is_none() || metadata.ino() != self.inode || metadata.len() < self.position {
+            let mut file = File::open(&self.path)?;
+            // Only new lines are followed, the existing content of the file is skipped.
+            self.position = if self.file.is_none() {
+                file.seek(SeekFrom::End(0))?
+            } else {
+                0
+            };
+            self.inode = metadata.ino();
+            self.partial.clear();
+            self.file = Some(file);
+        }
+
+        let file = self.file.as_mut().expect("File is opened above");
+        let mut buf = Vec::new();
+        self.position += file.take(MAX_READ_LENGTH).read_to_end(&mut buf)? as u64;
+        self.partial.extend_from_slice(&buf);
+
+        let mut lines = Vec::new();
+        loop {
+            let line = match self.partial.iter().position(|b| *b == b'\n') {
+                Some(end) if end <= MAX_LINE_LENGTH => {
+                    let line = self.partial.drain(..=end).collect::<Vec<_>>();
+                    line[..end].to_vec()
+                }
+                _ if self.partial.len() >= MAX_LINE_LENGTH => {
+                    self.partial.drain(..MAX_LINE_LENGTH).collect()
+                }
+                _ => break,
+            };
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+
+        Ok(lines)
+    }
+}
+
+fn follow_file(path: PathBuf) -> impl FStream<Item = LogEntry, Error = Error> + Send {
+    let source = LogSource::File(path.clone());
+    let follower = Arc::new(Mutex::new(FileFollower::new(path)));
+
+    Interval::new_interval(FOLLOW_INTERVAL)
+        .map_err(|_| Error::from("Logs timer failed"))
+        .and_then(move |_| {
+            let follower = follower.clone();
+            blocking(move || follower.lock().unwrap().read_lines())
+        })
+        .map(stream::iter_ok)
+        .flatten()
+        .map(move |message| LogEntry {
+            source: source.clone(),
+            unit: None,
+            priority: None,
+            timestamp: None,
+            message,
+        })
+}
+
+/// The lines of `journalctl`, the process is killed when the stream is dropped.
+struct JournalLines {
+    _child: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+}
+
+impl FStream for JournalLines {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.lines.poll().map_err(Error::from)
+    }
+}
+
+fn follow_journal(
+    unit: Option<String>,
+    priority: Option<u8>,
+) -> Result<impl FStream<Item = LogEntry, Error = Error> + Send> {
+    let mut command = Command::new("journalctl");
+    command
+        .args(&["--output=json", "--follow", "--lines=0"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    if let Some(unit) = unit {
+        command.arg(format!("--unit={}", unit));
+    }
+    if let Some(priority) = priority {
+        command.arg(format!("--priority={}", priority));
+    }
+
+    let mut child = command.spawn_async()?;
+    let stdout = match child.stdout().take() {
+        Some(stdout) => stdout,
+        None => bail!("Output of journalctl is not piped"),
+    };
+
+    Ok(JournalLines {
+        _child: child,
+        lines: lines(BufReader::new(stdout)),
+    }
+    .filter_map(|line| parse_journal_entry(&line)))
+}
+
+/// Parses an entry of `journalctl --output=json`.
+fn parse_journal_entry(line: &str) -> Option<LogEntry> {
+    let entry: Value = serde_json::from_str(line).ok()?;
+    let field = |name: &str| entry.get(name).and_then(Value::as_str);
+
+    Some(LogEntry {
+        source: LogSource::Journal,
+        unit: field("_SYSTEMD_UNIT").map(Into::into),
+        priority: field("PRIORITY").and_then(|p| p.parse().ok()),
+        timestamp: field("__REALTIME_TIMESTAMP").and_then(|t| t.parse().ok()),
+        // Messages that are not valid UTF-8 are encoded as byte arrays.
+        message: match entry.get("MESSAGE") {
+            Some(Value::String(message)) => message.clone(),
+            Some(Value::Array(bytes)) => String::from_utf8_lossy(
+                &bytes
+                    .iter()
+                    .filter_map(|b| b.as_u64().map(|b| b as u8))
+                    .collect::<Vec<_>>(),
+            )
+            .into_owned(),
+            _ => return None,
+        },
+    })
+}
+
+/// Client side of `Logs`.
+/// Sends every received `LogEntry` to the given sender, until the server closes the `Stream`.
+/// Dropping the receiver stops the client with an error.
+pub struct LogsClient {
+    args: LogsArgs,
+    entries: UnboundedSender<LogEntry>,
+}
+
+impl LogsClient {
+    pub fn new(sources: Vec<LogSource>, entries: UnboundedSender<LogEntry>) -> LogsClient {
+        LogsClient {
+            args: LogsArgs {
+                sources,
+                unit: None,
+                priority: None,
+                pattern: None,
+            },
+            entries,
+        }
+    }
+
+    /// Only receive journal entries of the given systemd unit.
+    pub fn unit<U: Into<String>>(mut self, unit: U) -> Self {
+        self.args.unit = Some(unit.into());
+        self
+    }
+
+    /// Only receive journal entries with the given priority or a more important one.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.args.priority = Some(priority);
+        self
+    }
+
+    /// Only receive entries with a message that matches the given regular expression.
+    pub fn pattern<P: Into<String>>(mut self, pattern: P) -> Self {
+        self.args.pattern = Some(pattern.into());
+        self
+    }
+}
+
+pub struct LogsFuture {
+    future: Box<dyn Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for LogsFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for LogsClient {
+    type Error = Error;
+    type Future = LogsFuture;
+    type Args = LogsArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let entries = self.entries;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(stream, _)| {
+                let stream: ProtocolStream<LogEntry> = match stream {
+                    Some(stream) => stream.into(),
+                    None => bail!("No `Stream` for logs"),
+                };
+
+                Ok(stream
+                    .map_err(Error::from)
+                    .forward(entries.sink_map_err(|_| Error::from("Logs receiver dropped")))
+                    .map(|_| ()))
+            })
+            .flatten();
+
+        Ok(LogsFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
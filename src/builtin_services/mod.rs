@@ -1,9 +1,249 @@
+use error::*;
 use peer_builder::PeerBuilder;
+use service::{Server, ServiceAcl};
 
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+use futures::{future, Async, Future, Poll};
+
+use tokio_threadpool;
+
+use std::collections::HashMap;
+
+mod config_store;
+mod events;
+mod exec;
+mod file_transfer;
 mod lifeline;
+mod logs;
+mod port_forward;
+mod shell;
+mod telemetry;
+mod udp_forward;
+mod update;
+pub use self::config_store::{
+    ConfigChange, ConfigEntry, ConfigStore, ConfigStoreArgs, ConfigStoreClient, ConfigStoreResult,
+};
+pub use self::events::{Event, EventPublisher, Events, EventsArgs, EventsClient};
+pub use self::exec::{Exec, ExecArgs, ExecClient, ExecStatus};
+pub use self::file_transfer::{
+    FileInfo, FileTransfer, FileTransferArgs, FileTransferClient, FileTransferResult,
+};
 pub use self::lifeline::Lifeline;
+pub use self::logs::{LogEntry, LogSource, Logs, LogsArgs, LogsClient};
+pub use self::port_forward::{PortForward, PortForwardArgs, PortForwardClient};
+pub use self::shell::{Shell, ShellArgs, ShellClient, WindowSize};
+pub use self::telemetry::{
+    DiskUsage, InterfaceUsage, MemoryUsage, Sample, Telemetry, TelemetryArgs, TelemetryClient,
+};
+pub use self::udp_forward::{UdpForward, UdpForwardArgs, UdpForwardClient};
+pub use self::update::{Update, UpdateArgs, UpdateClient, UpdateStatus};
+
+/// The configuration of the builtin services.
+///
+/// `ConfigStore`, `Events`, `Exec`, `FileTransfer`, `Logs`, `Shell`, `Telemetry` and `Update`
+/// are only registered, if they are enabled explicitly.
+pub struct Config {
+    config_store: Option<ConfigStore>,
+    events: Option<Events>,
+    exec: Option<Exec>,
+    file_transfer: Option<FileTransfer>,
+    logs: Option<Logs>,
+    shell: Option<Shell>,
+    telemetry: Option<Telemetry>,
+    update: Option<Update>,
+    lifeline: Lifeline,
+    port_forward: PortForward,
+    udp_forward: UdpForward,
+    acls: HashMap<&'static str, ServiceAcl>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            config_store: None,
+            events: None,
+            exec: None,
+            file_transfer: None,
+            logs: None,
+            shell: None,
+            telemetry: None,
+            update: None,
+            lifeline: Lifeline::new(),
+            port_forward: PortForward::new(),
+            udp_forward: UdpForward::new(),
+            acls: HashMap::new(),
+        }
+    }
+
+    /// Enable the given `ConfigStore` instance.
+    pub fn enable_config_store(mut self, config_store: ConfigStore) -> Self {
+        self.config_store = Some(config_store);
+        self
+    }
+
+    /// Enable the given `Events` instance.
+    pub fn enable_events(mut self, events: Events) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Enable the given `Exec` instance.
+    pub fn enable_exec(mut self, exec: Exec) -> Self {
+        self.exec = Some(exec);
+        self
+    }
+
+    /// Enable the given `FileTransfer` instance.
+    pub fn enable_file_transfer(mut self, file_transfer: FileTransfer) -> Self {
+        self.file_transfer = Some(file_transfer);
+        self
+    }
+
+    /// Enable the given `Logs` instance.
+    pub fn enable_logs(mut self, logs: Logs) -> Self {
+        self.logs = Some(logs);
+        self
+    }
+
+    /// Enable the given `Shell` instance.
+    pub fn enable_shell(mut self, shell: Shell) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    /// Enable the given `Telemetry` instance.
+    pub fn enable_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    /// Enable the given `Update` instance.
+    pub fn enable_update(mut self, update: Update) -> Self {
+        self.update = Some(update);
+        self
+    }
+
+    /// Set the `Lifeline` instance that is registered.
+    pub fn set_lifeline(mut self, lifeline: Lifeline) -> Self {
+        self.lifeline = lifeline;
+        self
+    }
+
+    /// Set the `PortForward` instance that is registered.
+    pub fn set_port_forward(mut self, port_forward: PortForward) -> Self {
+        self.port_forward = port_forward;
+        self
+    }
+
+    /// Set the `UdpForward` instance that is registered.
+    pub fn set_udp_forward(mut self, udp_forward: UdpForward) -> Self {
+        self.udp_forward = udp_forward;
+        self
+    }
+
+    /// Set the `ServiceAcl` of the builtin service with the given name.
+    /// Services without a `ServiceAcl` can be started by every remote peer.
+    pub fn set_acl(mut self, service: &'static str, acl: ServiceAcl) -> Self {
+        self.acls.insert(service, acl);
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
 
 /// Registers the builtin services at the given `PeerBuilder`.
 pub fn register(builder: PeerBuilder) -> PeerBuilder {
-    builder.register_service(lifeline::Lifeline {})
+    register_with_config(builder, Config::new())
+}
+
+/// Registers the builtin services at the given `PeerBuilder`, using the given `Config`.
+pub fn register_with_config(builder: PeerBuilder, mut config: Config) -> PeerBuilder {
+    let builder = match config.config_store {
+        Some(config_store) => register_service(builder, config_store, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.events {
+        Some(events) => register_service(builder, events, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.exec {
+        Some(exec) => register_service(builder, exec, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.file_transfer {
+        Some(file_transfer) => register_service(builder, file_transfer, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.logs {
+        Some(logs) => register_service(builder, logs, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.shell {
+        Some(shell) => register_service(builder, shell, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.telemetry {
+        Some(telemetry) => register_service(builder, telemetry, &mut config.acls),
+        None => builder,
+    };
+    let builder = match config.update {
+        Some(update) => register_service(builder, update, &mut config.acls),
+        None => builder,
+    };
+    let builder = register_service(builder, config.lifeline, &mut config.acls);
+    let builder = register_service(builder, config.port_forward, &mut config.acls);
+    register_service(builder, config.udp_forward, &mut config.acls)
+}
+
+fn register_service<S: Server + 'static>(
+    builder: PeerBuilder,
+    service: S,
+    acls: &mut HashMap<&'static str, ServiceAcl>,
+) -> PeerBuilder {
+    let acl = acls.remove(service.name()).unwrap_or_default();
+    builder.register_service_with_acl(service, acl)
+}
+
+/// Copies the data between both ends, until one of them is closed.
+fn pipe<A, B>(a: A, b: B) -> impl Future<Item = (), Error = Error> + Send
+where
+    A: AsyncRead + AsyncWrite + Send + 'static,
+    B: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_a, write_a) = AsyncRead::split(a);
+    let (read_b, write_b) = AsyncRead::split(b);
+
+    io::copy(read_a, write_b)
+        .map(|_| ())
+        .select(io::copy(read_b, write_a).map(|_| ()))
+        .map(|_| ())
+        .map_err(|e| Error::from(e.0))
+}
+
+/// Runs the given blocking file system operation on the thread pool.
+fn blocking<F, T>(f: F) -> impl Future<Item = T, Error = Error> + Send
+where
+    F: FnOnce() -> io::Result<T> + Send,
+    T: Send,
+{
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        poll_blocking(|| (f.take().expect("Blocking operation is only run once"))())
+    })
+}
+
+fn poll_blocking<F, T>(f: F) -> Poll<T, Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    match tokio_threadpool::blocking(f) {
+        Ok(Async::Ready(res)) => Ok(Async::Ready(res?)),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(_) => bail!("Blocking operations require the tokio thread pool"),
+    }
 }
@@ -0,0 +1,268 @@
+use super::pipe;
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, Stream};
+
+use tokio::{
+    self,
+    net::{TcpListener, TcpStream},
+};
+
+use futures::{
+    future::{self, Either},
+    Future, Poll, Stream as FStream,
+};
+
+use std::{collections::HashMap, net::SocketAddr, result};
+
+const NAME: &str = "port_forward";
+
+/// The port forward that is requested from the server.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortForwardArgs {
+    /// The client listens and the server connects every forwarded connection to `target`.
+    Local { target: SocketAddr },
+    /// The server listens on `bind` and the client connects every forwarded connection.
+    Remote { bind: SocketAddr },
+}
+
+/// Forwards TCP connections between two peers, like `ssh -L` and `ssh -R`.
+/// Every forwarded connection uses its own `Stream`, the first `Stream` of a service instance
+/// only controls the lifetime of the port forward.
+///
+/// The server only connects to the targets and only listens on the addresses it allows.
+/// It listens before the service instance is started, so the client is informed when the address
+/// can not be used.
+pub struct PortForward {
+    allowed_targets: Vec<SocketAddr>,
+    allowed_binds: Vec<SocketAddr>,
+    /// The listeners that were bound by `prepare`, until the instance is started.
+    listeners: HashMap<SocketAddr, TcpListener>,
+}
+
+impl PortForward {
+    pub fn new() -> PortForward {
+        PortForward {
+            allowed_targets: Vec::new(),
+            allowed_binds: Vec::new(),
+            listeners: HashMap::new(),
+        }
+    }
+
+    /// Allow clients to forward connections to the given target.
+    pub fn allow_target(mut self, target: SocketAddr) -> Self {
+        self.allowed_targets.push(target);
+        self
+    }
+
+    /// Allow clients to request a listener on the given address.
+    pub fn allow_bind(mut self, bind: SocketAddr) -> Self {
+        self.allowed_binds.push(bind);
+        self
+    }
+}
+
+impl Default for PortForward {
+    fn default() -> PortForward {
+        PortForward::new()
+    }
+}
+
+impl Server for PortForward {
+    type Args = PortForwardArgs;
+
+    fn check_args(&self, args: &PortForwardArgs) -> result::Result<(), String> {
+        match *args {
+            PortForwardArgs::Local { target } if !self.allowed_targets.contains(&target) => {
+                Err(format!("Target {} is not allowed.", target))
+            }
+            PortForwardArgs::Remote { bind } if !self.allowed_binds.contains(&bind) => {
+                Err(format!("Listening on {} is not allowed.", bind))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn prepare(&mut self, args: &PortForwardArgs) -> Result<()> {
+        if let PortForwardArgs::Remote { bind } = *args {
+            let listener = TcpListener::bind(&bind)
+                .map_err(|e| Error::BadArguments(format!("Could not listen on {}: {}", bind, e)))?;
+            self.listeners.insert(bind, listener);
+        }
+
+        Ok(())
+    }
+
+    fn abort(&mut self, args: PortForwardArgs) {
+        if let PortForwardArgs::Remote { bind } = args {
+            self.listeners.remove(&bind);
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: PortForwardArgs,
+    ) {
+        info!("Port forward {:?} for {}", args, context.remote_peer());
+
+        let shutdown = streams.shutdown_signal();
+        let future = match args {
+            PortForwardArgs::Local { target } => Either::A(forward_to_target(streams, target)),
+            PortForwardArgs::Remote { bind } => match self.listeners.remove(&bind) {
+                Some(listener) => Either::B(forward_listener(streams, listener, new_stream_handle)),
+                None => {
+                    error!("Port forward has no listener for {}", bind);
+                    return;
+                }
+            },
+        };
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(future)
+                .map_err(|e| error!("Port forward error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Runs the given forward, until the control `Stream` is closed.
+fn until_closed<F>(control: Option<Stream>, forward: F) -> impl Future<Item = (), Error = Error>
+where
+    F: Future<Item = (), Error = Error>,
+{
+    match control {
+        Some(control) => Either::A(
+            forward
+                .select(control.for_each(|_| Ok(())))
+                .map(|_| ())
+                .map_err(|e| e.0),
+        ),
+        None => Either::B(future::err("No control `Stream` for port forward".into())),
+    }
+}
+
+/// Connects every incoming `Stream` to the given target.
+fn forward_to_target(
+    streams: Streams,
+    target: SocketAddr,
+) -> impl Future<Item = (), Error = Error> + Send {
+    streams
+        .into_future()
+        .map_err(|e| e.0)
+        .and_then(move |(control, streams)| {
+            let forward = streams.for_each(move |stream| {
+                tokio::spawn(
+                    TcpStream::connect(&target)
+                        .map_err(Error::from)
+                        .and_then(|tcp| pipe(stream, tcp))
+                        .map_err(|e| error!("Port forward connection error: {:?}", e)),
+                );
+                Ok(())
+            });
+
+            until_closed(control, forward)
+        })
+}
+
+/// Opens a new `Stream` for every connection that is accepted by the given listener.
+fn forward_listener(
+    streams: Streams,
+    listener: TcpListener,
+    mut new_stream_handle: NewStreamHandle,
+) -> impl Future<Item = (), Error = Error> + Send {
+    streams
+        .into_future()
+        .map_err(|e| e.0)
+        .and_then(move |(control, _)| {
+            let forward = listener
+                .incoming()
+                .map_err(Error::from)
+                .for_each(move |tcp| {
+                    tokio::spawn(
+                        new_stream_handle
+                            .new_stream()
+                            .and_then(|stream| pipe(stream, tcp))
+                            .map_err(|e| error!("Port forward connection error: {:?}", e)),
+                    );
+                    Ok(())
+                });
+
+            until_closed(control, forward)
+        })
+}
+
+/// Client side of `PortForward`.
+pub struct PortForwardClient {
+    args: PortForwardArgs,
+    local: SocketAddr,
+}
+
+impl PortForwardClient {
+    /// Forward the connections to `listen` to the `target` of the server (`ssh -L`).
+    pub fn local(listen: SocketAddr, target: SocketAddr) -> PortForwardClient {
+        PortForwardClient {
+            args: PortForwardArgs::Local { target },
+            local: listen,
+        }
+    }
+
+    /// Forward the connections to `bind` at the server to the local `target` (`ssh -R`).
+    pub fn remote(bind: SocketAddr, target: SocketAddr) -> PortForwardClient {
+        PortForwardClient {
+            args: PortForwardArgs::Remote { bind },
+            local: target,
+        }
+    }
+}
+
+pub struct PortForwardFuture {
+    future: Box<dyn Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for PortForwardFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for PortForwardClient {
+    type Error = Error;
+    type Future = PortForwardFuture;
+    type Args = PortForwardArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let future: Box<dyn Future<Item = (), Error = Error> + Send> = match self.args {
+            PortForwardArgs::Local { .. } => Box::new(forward_listener(
+                streams,
+                TcpListener::bind(&self.local)?,
+                new_stream_handle,
+            )),
+            PortForwardArgs::Remote { .. } => Box::new(forward_to_target(streams, self.local)),
+        };
+
+        Ok(PortForwardFuture { future })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
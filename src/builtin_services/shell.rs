@@ -0,0 +1,450 @@
+use super::{lifeline::StdinReader, pipe, ExecStatus};
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream, Stream};
+
+use tokio::{self, reactor::Handle};
+
+use tokio_file_unix;
+
+use tokio_process::CommandExt;
+
+use tokio_signal::unix::Signal;
+
+use futures::{
+    future::{self, Either},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use libc;
+
+use std::{
+    env,
+    ffi::CStr,
+    fs::File,
+    io::{self, Write},
+    mem,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::CommandExt as StdCommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    ptr,
+};
+
+const NAME: &str = "shell";
+
+/// The size of a terminal window.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> WindowSize {
+        WindowSize { rows: 24, cols: 80 }
+    }
+}
+
+impl From<WindowSize> for libc::winsize {
+    fn from(size: WindowSize) -> libc::winsize {
+        libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+/// The arguments of `Shell`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ShellArgs {
+    /// The `TERM` variable of the client terminal.
+    #[serde(default)]
+    pub term: Option<String>,
+    /// The initial size of the client terminal.
+    #[serde(default)]
+    pub size: WindowSize,
+}
+
+/// The messages that are send over the control `Stream`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+enum ShellMessage {
+    /// The client terminal was resized.
+    Resize(WindowSize),
+    /// The shell exited.
+    Exited(ExecStatus),
+}
+
+/// Runs an interactive shell in a pseudo-terminal for remote peers.
+///
+/// The first `Stream` of a service instance is the control `Stream`, the client sends the
+/// window size changes of its terminal over it and the server sends the exit status of the
+/// shell. The second `Stream`, opened by the client, carries the terminal data.
+pub struct Shell {
+    shell: Option<PathBuf>,
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        Shell { shell: None }
+    }
+
+    /// Set the shell that is started, by default the login shell of the user that runs the
+    /// peer.
+    pub fn set_shell<P: Into<PathBuf>>(mut self, shell: P) -> Self {
+        self.shell = Some(shell.into());
+        self
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell::new()
+    }
+}
+
+impl Server for Shell {
+    type Args = ShellArgs;
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: ShellArgs,
+    ) {
+        let shell = self.shell.clone().unwrap_or_else(login_shell);
+        info!(
+            "Shell session for {} ({}) with {}",
+            context.remote_peer(),
+            context.remote_addr(),
+            shell.display()
+        );
+
+        let shutdown = streams.shutdown_signal();
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(control, streams)| {
+                            let control = match control {
+                                Some(control) => control,
+                                None => bail!("No control `Stream` for shell"),
+                            };
+
+                            Ok(streams
+                                .into_future()
+                                .map_err(|e| e.0)
+                                .and_then(move |(data, _)| match data {
+                                    Some(data) => run_shell(&shell, args, control.into(), data),
+                                    None => bail!("No data `Stream` for shell"),
+                                })
+                                .flatten())
+                        })
+                        .flatten(),
+                )
+                .map_err(|e| error!("Shell error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// The login shell of the current user, `/bin/sh` if it is unknown.
+fn login_shell() -> PathBuf {
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if !passwd.is_null() && !(*passwd).pw_shell.is_null() {
+            if let Ok(shell) = CStr::from_ptr((*passwd).pw_shell).to_str() {
+                if !shell.is_empty() {
+                    return shell.into();
+                }
+            }
+        }
+    }
+
+    "/bin/sh".into()
+}
+
+/// Opens a new pseudo-terminal with the given size, returns the master and the slave.
+fn open_pty(size: WindowSize) -> io::Result<(File, File)> {
+    let mut master = 0;
+    let mut slave = 0;
+    let size = libc::winsize::from(size);
+
+    unsafe {
+        if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The shell should only inherit the slave.
+        libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC);
+
+        Ok((File::from_raw_fd(master), File::from_raw_fd(slave)))
+    }
+}
+
+fn set_window_size(pty: RawFd, size: WindowSize) -> io::Result<()> {
+    let size = libc::winsize::from(size);
+
+    if unsafe { libc::ioctl(pty, libc::TIOCSWINSZ, &size) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn window_size(terminal: RawFd) -> io::Result<WindowSize> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+
+    if unsafe { libc::ioctl(terminal, libc::TIOCGWINSZ, &mut size) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(WindowSize {
+            rows: size.ws_row,
+            cols: size.ws_col,
+        })
+    }
+}
+
+/// Starts the shell as session leader with the given pseudo-terminal slave as controlling
+/// terminal.
+fn spawn_shell(
+    shell: &Path,
+    term: Option<String>,
+    slave: File,
+) -> io::Result<tokio_process::Child> {
+    let mut command = Command::new(shell);
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    if let Some(term) = term {
+        command.env("TERM", term);
+    }
+
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+
+    command.spawn_async()
+}
+
+/// Runs the shell and forwards the terminal data, until the shell exits or the control `Stream`
+/// is closed.
+fn run_shell(
+    shell: &Path,
+    args: ShellArgs,
+    control: ProtocolStream<ShellMessage>,
+    data: Stream,
+) -> Result<impl Future<Item = (), Error = Error> + Send> {
+    let (master, slave) = open_pty(args.size)?;
+    let resize = master.try_clone()?;
+    let child = spawn_shell(shell, args.term, slave)?;
+    let pty = tokio_file_unix::File::new_nb(master)?.into_io(&Handle::default())?;
+    let (sink, control) = control.split();
+
+    let session = pipe(data, pty)
+        // Reading from the pseudo-terminal fails, after the shell exited.
+        .then(|_| Ok::<_, Error>(()))
+        .and_then(move |_| child.map_err(Error::from))
+        .and_then(move |status| {
+            sink.send(ShellMessage::Exited(status.into()))
+                .map_err(Error::from)
+        })
+        .map(|_| ());
+
+    let resizes = control.map_err(Error::from).for_each(move |msg| match msg {
+        ShellMessage::Resize(size) => {
+            set_window_size(resize.as_raw_fd(), size).map_err(Error::from)
+        }
+        ShellMessage::Exited(_) => bail!("Unexpected message on shell control `Stream`"),
+    });
+
+    Ok(session.select(resizes).map(|_| ()).map_err(|e| e.0))
+}
+
+/// Puts the local terminal into raw mode and restores the previous mode on drop.
+struct RawTerminal {
+    previous: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn enable() -> io::Result<RawTerminal> {
+        let fd = libc::STDIN_FILENO;
+
+        if unsafe { libc::isatty(fd) } != 1 {
+            return Ok(RawTerminal { previous: None });
+        }
+
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let previous = termios;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RawTerminal {
+            previous: Some(previous),
+        })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(ref previous) = self.previous {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, previous) };
+        }
+    }
+}
+
+/// Client side of `Shell`.
+/// Connects the local terminal to the remote shell and resolves to the `ExecStatus` of the
+/// shell.
+pub struct ShellClient {
+    args: ShellArgs,
+}
+
+impl ShellClient {
+    /// Uses the `TERM` variable and the window size of the local terminal.
+    pub fn new() -> ShellClient {
+        ShellClient {
+            args: ShellArgs {
+                term: env::var("TERM").ok(),
+                size: window_size(libc::STDOUT_FILENO).unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl Default for ShellClient {
+    fn default() -> ShellClient {
+        ShellClient::new()
+    }
+}
+
+pub struct ShellFuture {
+    future: Box<dyn Future<Item = ExecStatus, Error = Error> + Send>,
+}
+
+impl Future for ShellFuture {
+    type Item = ExecStatus;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for ShellClient {
+    type Error = Error;
+    type Future = ShellFuture;
+    type Args = ShellArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        mut new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let stdin = tokio_file_unix::raw_stdin()?;
+        let stdin = tokio_file_unix::File::new_nb(stdin)?;
+        let stdin = stdin.into_reader(&Handle::default())?;
+        let resizes = Signal::new(libc::SIGWINCH)
+            .flatten_stream()
+            .map_err(Error::from);
+        let raw_terminal = RawTerminal::enable()?;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(control, _)| {
+                let control: ProtocolStream<ShellMessage> = match control {
+                    Some(control) => control.into(),
+                    None => bail!("No control `Stream` for shell"),
+                };
+                let (sink, control) = control.split();
+
+                let status = control
+                    .map_err(Error::from)
+                    .filter_map(|msg| match msg {
+                        ShellMessage::Exited(status) => Some(status),
+                        ShellMessage::Resize(_) => None,
+                    })
+                    .into_future()
+                    .map_err(|e| e.0)
+                    .and_then(|(status, _)| match status {
+                        Some(status) => Ok(status),
+                        None => bail!("Shell control `Stream` closed"),
+                    });
+
+                let resize = resizes
+                    .fold(sink, |sink, _| {
+                        future::result(window_size(libc::STDOUT_FILENO))
+                            .and_then(move |size| sink.send(ShellMessage::Resize(size)))
+                            .map_err(Error::from)
+                    })
+                    .map(|_| ());
+
+                let data = new_stream_handle.new_stream().and_then(move |stream| {
+                    let (sink, stream) = FStream::split(stream);
+
+                    stream
+                        .for_each(|buf| {
+                            io::stdout().write_all(&buf)?;
+                            io::stdout().flush()?;
+                            Ok(())
+                        })
+                        .select(StdinReader::new(stdin, sink))
+                        .map(|_| ())
+                        .map_err(|e| e.0)
+                });
+
+                Ok(data
+                    .join(status)
+                    .map(|(_, status)| status)
+                    .select2(resize)
+                    .then(|res| match res {
+                        Ok(Either::A((status, _))) => Ok(status),
+                        Ok(Either::B(_)) => bail!("Window size changes ended"),
+                        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+                    }))
+            })
+            .flatten()
+            .then(move |res| {
+                drop(raw_terminal);
+                res
+            });
+
+        Ok(ShellFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
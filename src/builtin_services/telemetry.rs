@@ -0,0 +1,431 @@
+use super::blocking;
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream};
+
+use tokio::{self, timer::Interval};
+
+use futures::{sync::mpsc::UnboundedSender, Future, Poll, Sink, Stream as FStream};
+
+use libc;
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    result,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const NAME: &str = "telemetry";
+
+/// The arguments of `Telemetry`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TelemetryArgs {
+    /// The interval between two samples.
+    pub interval: Duration,
+}
+
+/// The memory of the device, in bytes.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub total: u64,
+    pub available: u64,
+}
+
+/// The usage of a file system, in bytes.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub path: PathBuf,
+    pub total: u64,
+    pub available: u64,
+}
+
+/// The traffic of a network interface since it was brought up, in bytes.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct InterfaceUsage {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// A sample of the device readings.
+/// Readings that are not available on the device are `None` or empty.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The time of the sample, in seconds since the unix epoch.
+    pub timestamp: u64,
+    /// The uptime of the device, in seconds.
+    pub uptime: Option<f64>,
+    /// The CPU usage since the previous sample, between `0` and `1`.
+    pub cpu: Option<f64>,
+    /// The load average over 1, 5 and 15 minutes.
+    pub load: Option<(f64, f64, f64)>,
+    pub memory: Option<MemoryUsage>,
+    pub disks: Vec<DiskUsage>,
+    pub interfaces: Vec<InterfaceUsage>,
+    /// The values of the custom gauges.
+    pub gauges: BTreeMap<String, f64>,
+}
+
+type Gauge = Arc<dyn Fn() -> f64 + Send + Sync>;
+
+/// Sends periodic samples of the device readings to remote peers.
+///
+/// The samples are send over the first `Stream` of a service instance, until the client closes
+/// it. The samples describe the device, so the service is only registered if it is enabled in the
+/// `Config`.
+pub struct Telemetry {
+    disks: Vec<PathBuf>,
+    gauges: BTreeMap<String, Gauge>,
+    min_interval: Duration,
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        Telemetry {
+            disks: vec!["/".into()],
+            gauges: BTreeMap::new(),
+            min_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Report the usage of the file system at the given path, by default only `/` is reported.
+    pub fn add_disk<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.disks.push(path.into());
+        self
+    }
+
+    /// Set the file systems that are reported.
+    pub fn set_disks(mut self, paths: Vec<PathBuf>) -> Self {
+        self.disks = paths;
+        self
+    }
+
+    /// Add a custom gauge, the given function is called for every sample.
+    pub fn add_gauge<N, F>(mut self, name: N, gauge: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.gauges.insert(name.into(), Arc::new(gauge));
+        self
+    }
+
+    /// Set the minimum interval clients can request (default 1s).
+    pub fn set_min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = interval;
+        self
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Telemetry {
+        Telemetry::new()
+    }
+}
+
+impl Server for Telemetry {
+    type Args = TelemetryArgs;
+
+    fn check_args(&self, args: &TelemetryArgs) -> result::Result<(), String> {
+        if args.interval < self.min_interval {
+            Err(format!(
+                "Interval {:?} is smaller than the minimum {:?}.",
+                args.interval, self.min_interval
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: TelemetryArgs,
+    ) {
+        info!(
+            "Telemetry every {:?} for {}",
+            args.interval,
+            context.remote_peer()
+        );
+
+        // Stops sending when the local peer is shut down.
+        let shutdown = streams.shutdown_signal();
+        let sampler = Sampler {
+            disks: self.disks.clone(),
+            gauges: self.gauges.clone(),
+            cpu: None,
+        };
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(stream, _)| match stream {
+                            Some(stream) => Ok(send_samples(stream.into(), sampler, args.interval)),
+                            None => bail!("No `Stream` for telemetry"),
+                        })
+                        .flatten(),
+                )
+                .map_err(|e| error!("Telemetry error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Sends a sample every interval, until the `Stream` is closed.
+fn send_samples(
+    stream: ProtocolStream<Sample>,
+    sampler: Sampler,
+    interval: Duration,
+) -> impl Future<Item = (), Error = Error> + Send {
+    Interval::new_interval(interval)
+        .map_err(|_| Error::from("Telemetry timer failed"))
+        .fold((sampler, stream), |(sampler, stream), _| {
+            blocking(move || {
+                let mut sampler = sampler;
+                let sample = sampler.sample();
+                Ok((sampler, sample))
+            })
+            .and_then(|(sampler, sample)| {
+                stream
+                    .send(sample)
+                    .map(|stream| (sampler, stream))
+                    .map_err(Error::from)
+            })
+        })
+        .map(|_| ())
+}
+
+/// The total and idle time of all CPUs, in ticks.
+#[derive(Clone, Copy)]
+struct CpuTimes {
+    total: u64,
+    idle: u64,
+}
+
+/// Takes the samples of the device readings.
+struct Sampler {
+    disks: Vec<PathBuf>,
+    gauges: BTreeMap<String, Gauge>,
+    /// The CPU times of the previous sample.
+    cpu: Option<CpuTimes>,
+}
+
+impl Sampler {
+    fn sample(&mut self) -> Sample {
+        let cpu_times = read_cpu_times();
+        let cpu = match (self.cpu, cpu_times) {
+            (Some(prev), Some(now)) if now.total > prev.total => {
+                let idle = now.idle.saturating_sub(prev.idle) as f64;
+                Some(1.0 - idle / (now.total - prev.total) as f64)
+            }
+            _ => None,
+        };
+        self.cpu = cpu_times;
+
+        Sample {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            uptime: read_uptime(),
+            cpu,
+            load: read_load(),
+            memory: read_memory(),
+            disks: self.disks.iter().filter_map(|p| disk_usage(p)).collect(),
+            interfaces: read_interfaces(),
+            gauges: self
+                .gauges
+                .iter()
+                .map(|(name, gauge)| (name.clone(), gauge()))
+                .collect(),
+        }
+    }
+}
+
+/// Parses the first whitespace separated values of the given file.
+fn read_values(path: &str) -> Option<Vec<f64>> {
+    fs::read_to_string(path).ok().map(|s| {
+        s.split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .collect()
+    })
+}
+
+fn read_uptime() -> Option<f64> {
+    read_values("/proc/uptime").and_then(|v| v.first().cloned())
+}
+
+fn read_load() -> Option<(f64, f64, f64)> {
+    read_values("/proc/loadavg").and_then(|v| {
+        if v.len() >= 3 {
+            Some((v[0], v[1], v[2]))
+        } else {
+            None
+        }
+    })
+}
+
+fn read_cpu_times() -> Option<CpuTimes> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let times = stat
+        .lines()
+        .find(|l| l.starts_with("cpu "))?
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|v| v.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+
+    // user, nice, system, idle, iowait, ...
+    if times.len() < 4 {
+        return None;
+    }
+
+    Some(CpuTimes {
+        total: times.iter().sum(),
+        idle: times[3] + times.get(4).cloned().unwrap_or(0),
+    })
+}
+
+fn read_memory() -> Option<MemoryUsage> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let value = |name: &str| {
+        meminfo
+            .lines()
+            .find(|l| l.starts_with(name))
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+
+    Some(MemoryUsage {
+        total: value("MemTotal:")?,
+        available: value("MemAvailable:").or_else(|| value("MemFree:"))?,
+    })
+}
+
+fn read_interfaces() -> Vec<InterfaceUsage> {
+    let dev = match fs::read_to_string("/proc/net/dev") {
+        Ok(dev) => dev,
+        Err(_) => return Vec::new(),
+    };
+
+    // The first two lines are the header.
+    dev.lines()
+        .skip(2)
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            let name = parts.next()?.trim().to_string();
+            let values = parts
+                .next()?
+                .split_whitespace()
+                .filter_map(|v| v.parse::<u64>().ok())
+                .collect::<Vec<_>>();
+
+            Some(InterfaceUsage {
+                name,
+                rx_bytes: *values.get(0)?,
+                tx_bytes: *values.get(8)?,
+            })
+        })
+        .collect()
+}
+
+fn disk_usage(path: &Path) -> Option<DiskUsage> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(DiskUsage {
+        path: path.to_path_buf(),
+        total: stat.f_blocks as u64 * stat.f_frsize as u64,
+        available: stat.f_bavail as u64 * stat.f_frsize as u64,
+    })
+}
+
+/// Client side of `Telemetry`.
+/// Sends every received `Sample` to the given sender, until the server closes the `Stream`.
+/// Dropping the receiver stops the client with an error.
+pub struct TelemetryClient {
+    interval: Duration,
+    samples: UnboundedSender<Sample>,
+}
+
+impl TelemetryClient {
+    pub fn new(interval: Duration, samples: UnboundedSender<Sample>) -> TelemetryClient {
+        TelemetryClient { interval, samples }
+    }
+}
+
+pub struct TelemetryFuture {
+    future: Box<dyn Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for TelemetryFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for TelemetryClient {
+    type Error = Error;
+    type Future = TelemetryFuture;
+    type Args = TelemetryArgs;
+
+    fn args(&self) -> Self::Args {
+        TelemetryArgs {
+            interval: self.interval,
+        }
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let samples = self.samples;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(stream, _)| {
+                let stream: ProtocolStream<Sample> = match stream {
+                    Some(stream) => stream.into(),
+                    None => bail!("No `Stream` for telemetry"),
+                };
+
+                Ok(stream
+                    .map_err(Error::from)
+                    .forward(samples.sink_map_err(|_| Error::from("Telemetry receiver dropped")))
+                    .map(|_| ()))
+            })
+            .flatten();
+
+        Ok(TelemetryFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
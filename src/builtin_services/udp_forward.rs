@@ -0,0 +1,397 @@
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, Stream};
+
+use tokio::{
+    self,
+    codec::{BytesCodec, Framed, LengthDelimitedCodec},
+    net::{UdpFramed, UdpSocket},
+    util::StreamExt,
+};
+
+use futures::{
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::SocketAddr,
+    result,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const NAME: &str = "udp_forward";
+
+/// The arguments of `UdpForward`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UdpForwardArgs {
+    /// The target the server forwards the datagrams to.
+    pub target: SocketAddr,
+}
+
+/// Forwards UDP datagrams between two peers.
+///
+/// The datagrams are send over the first `Stream` of the service instance, each datagram
+/// prefixed with the association of its source. The server opens one UDP socket per association,
+/// so that the answers of the target can be send back to the right source at the client.
+/// Associations that do not send datagrams for the idle timeout are closed.
+pub struct UdpForward {
+    allowed_targets: Vec<SocketAddr>,
+    max_associations: usize,
+    idle_timeout: Duration,
+}
+
+impl UdpForward {
+    pub fn new() -> UdpForward {
+        UdpForward {
+            allowed_targets: Vec::new(),
+            max_associations: 256,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Allow clients to forward datagrams to the given target.
+    pub fn allow_target(mut self, target: SocketAddr) -> Self {
+        self.allowed_targets.push(target);
+        self
+    }
+
+    /// Set the maximum number of associations per service instance (default 256).
+    /// Datagrams of new associations are dropped, while the maximum is reached.
+    pub fn set_max_associations(mut self, max: usize) -> Self {
+        self.max_associations = max;
+        self
+    }
+
+    /// Set the time after that an association without datagrams is closed (default 60s).
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+impl Default for UdpForward {
+    fn default() -> UdpForward {
+        UdpForward::new()
+    }
+}
+
+impl Server for UdpForward {
+    type Args = UdpForwardArgs;
+
+    fn check_args(&self, args: &UdpForwardArgs) -> result::Result<(), String> {
+        if self.allowed_targets.contains(&args.target) {
+            Ok(())
+        } else {
+            Err(format!("Target {} is not allowed.", args.target))
+        }
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: UdpForwardArgs,
+    ) {
+        info!(
+            "UDP forward to {} for {}",
+            args.target,
+            context.remote_peer()
+        );
+
+        let max_associations = self.max_associations;
+        let idle_timeout = self.idle_timeout;
+        let shutdown = streams.shutdown_signal();
+
+        tokio::spawn(
+            shutdown
+                .run_until_shutdown(
+                    streams
+                        .into_future()
+                        .map_err(|e| e.0)
+                        .and_then(move |(stream, _)| match stream {
+                            Some(stream) => Ok(forward_associations(
+                                stream,
+                                args.target,
+                                max_associations,
+                                idle_timeout,
+                            )),
+                            None => bail!("No `Stream` for UDP forward"),
+                        })
+                        .flatten(),
+                )
+                .map_err(|e| error!("UDP forward error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+fn encode_frame(association: u32, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(4 + payload.len());
+    frame.put_u32_be(association);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+fn decode_frame(mut frame: BytesMut) -> Result<(u32, Bytes)> {
+    if frame.len() < 4 {
+        bail!("UDP forward frame is too short");
+    }
+
+    let payload = frame.split_off(4);
+    Ok((Cursor::new(frame).get_u32_be(), payload.freeze()))
+}
+
+/// Forwards the datagrams of all associations between the `Stream` and the target.
+fn forward_associations(
+    stream: Stream,
+    target: SocketAddr,
+    max_associations: usize,
+    idle_timeout: Duration,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (sink, stream) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+    let (replies_sender, replies) = unbounded();
+    let mut associations = HashMap::<u32, UnboundedSender<Bytes>>::new();
+
+    let to_peer = replies
+        .map_err(|_| Error::from("UDP forward replies closed"))
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let from_peer = stream.map_err(Error::from).for_each(move |frame| {
+        let (id, payload) = decode_frame(frame)?;
+
+        let payload = match associations.get(&id) {
+            Some(association) => match association.unbounded_send(payload) {
+                Ok(()) => return Ok(()),
+                // The association was closed, open it again.
+                Err(e) => e.into_inner(),
+            },
+            None => payload,
+        };
+
+        associations.retain(|_, association| !association.is_closed());
+        if associations.len() >= max_associations {
+            warn!("UDP forward reached maximum associations, dropping datagram");
+            return Ok(());
+        }
+
+        let association = open_association(id, target, replies_sender.clone(), idle_timeout)?;
+        let _ = association.unbounded_send(payload);
+        associations.insert(id, association);
+        Ok(())
+    });
+
+    to_peer.select(from_peer).map(|_| ()).map_err(|e| e.0)
+}
+
+/// Opens the UDP socket for the given association.
+/// Returns the sender for the datagrams that should be send to the target.
+fn open_association(
+    id: u32,
+    target: SocketAddr,
+    replies: UnboundedSender<Bytes>,
+    idle_timeout: Duration,
+) -> Result<UnboundedSender<Bytes>> {
+    let bind: SocketAddr = if target.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let (udp_sink, udp_stream) = UdpFramed::new(UdpSocket::bind(&bind)?, BytesCodec::new()).split();
+    let (sender, datagrams) = unbounded();
+
+    let to_target = datagrams
+        .timeout(idle_timeout)
+        .map_err(|_| Error::from("UDP forward association idle"))
+        .map(move |payload| (payload, target))
+        .forward(udp_sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let from_target = udp_stream
+        .map_err(Error::from)
+        .filter(move |&(_, addr)| addr == target)
+        .map(move |(payload, _)| encode_frame(id, &payload))
+        .forward(replies.sink_map_err(|_| Error::from("UDP forward closed")))
+        .map(|_| ());
+
+    tokio::spawn(
+        to_target
+            .select(from_target)
+            .map(|_| ())
+            .map_err(move |e| debug!("UDP forward association {} closed: {:?}", id, e.0)),
+    );
+
+    Ok(sender)
+}
+
+/// The associations of the client, one per source address.
+/// Sources that do not send datagrams for the idle timeout are removed, like the associations
+/// at the server.
+struct Sources {
+    ids: HashMap<SocketAddr, u32>,
+    addrs: HashMap<u32, (SocketAddr, Instant)>,
+    next_id: u32,
+    idle_timeout: Duration,
+}
+
+impl Sources {
+    fn new(idle_timeout: Duration) -> Sources {
+        Sources {
+            ids: HashMap::new(),
+            addrs: HashMap::new(),
+            next_id: 0,
+            idle_timeout,
+        }
+    }
+
+    fn id(&mut self, addr: SocketAddr) -> u32 {
+        let now = Instant::now();
+
+        if let Some(id) = self.ids.get(&addr).cloned() {
+            self.addrs.insert(id, (addr, now));
+            return id;
+        }
+
+        self.remove_idle(now);
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if let Some((old, _)) = self.addrs.insert(id, (addr, now)) {
+            self.ids.remove(&old);
+        }
+        self.ids.insert(addr, id);
+        id
+    }
+
+    fn addr(&self, id: u32) -> Option<SocketAddr> {
+        self.addrs.get(&id).map(|&(addr, _)| addr)
+    }
+
+    fn remove_idle(&mut self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        let ids = &mut self.ids;
+
+        self.addrs.retain(|_, &mut (addr, last_datagram)| {
+            let idle = now.duration_since(last_datagram) >= idle_timeout;
+            if idle {
+                ids.remove(&addr);
+            }
+            !idle
+        });
+    }
+}
+
+/// Client side of `UdpForward`.
+/// Listens on a local UDP socket and forwards all received datagrams to the target of the
+/// server.
+pub struct UdpForwardClient {
+    listen: SocketAddr,
+    target: SocketAddr,
+    idle_timeout: Duration,
+}
+
+impl UdpForwardClient {
+    pub fn new(listen: SocketAddr, target: SocketAddr) -> UdpForwardClient {
+        UdpForwardClient {
+            listen,
+            target,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// Set the time after that a source without datagrams is forgotten (default 60s).
+    /// Should match the idle timeout of the server.
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+pub struct UdpForwardFuture {
+    future: Box<dyn Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for UdpForwardFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for UdpForwardClient {
+    type Error = Error;
+    type Future = UdpForwardFuture;
+    type Args = UdpForwardArgs;
+
+    fn args(&self) -> Self::Args {
+        UdpForwardArgs {
+            target: self.target,
+        }
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let socket = UdpSocket::bind(&self.listen)?;
+        let idle_timeout = self.idle_timeout;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(stream, _)| match stream {
+                Some(stream) => Ok(forward_sources(stream, socket, idle_timeout)),
+                None => bail!("No `Stream` for UDP forward"),
+            })
+            .flatten();
+
+        Ok(UdpForwardFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Forwards the datagrams between the local socket and the `Stream`.
+fn forward_sources(
+    stream: Stream,
+    socket: UdpSocket,
+    idle_timeout: Duration,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (sink, stream) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+    let (udp_sink, udp_stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+    let sources = Arc::new(Mutex::new(Sources::new(idle_timeout)));
+    let sources2 = sources.clone();
+
+    let to_peer = udp_stream
+        .map_err(Error::from)
+        .map(move |(payload, addr)| encode_frame(sources.lock().unwrap().id(addr), &payload))
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let from_peer = stream
+        .map_err(Error::from)
+        .and_then(decode_frame)
+        .filter_map(move |(id, payload)| sources2.lock().unwrap().addr(id).map(|a| (payload, a)))
+        .forward(udp_sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    to_peer.select(from_peer).map(|_| ()).map_err(|e| e.0)
+}
@@ -0,0 +1,629 @@
+use super::{blocking, file_transfer::SendFile, ExecStatus};
+use error::*;
+use service::{Client, Server, ServiceContext, Streams};
+use {NewStreamHandle, ProtocolStream, Stream};
+
+use tokio;
+
+use tokio_process::CommandExt;
+
+use futures::{
+    future::{self, Either},
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future, Poll, Sink, Stream as FStream,
+};
+
+use libc;
+
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    sign::Verifier,
+};
+
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const NAME: &str = "update";
+
+/// The name of the staged image in the staging directory.
+const IMAGE_NAME: &str = "image";
+
+/// The received bytes between two progress reports.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// The operation that is requested from the server.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateArgs {
+    /// Receive, verify and apply a new image.
+    Install { size: u64, signature: Vec<u8> },
+    /// Commit the applied image.
+    Commit,
+    /// Roll back the applied image.
+    Rollback,
+}
+
+/// The status of an update, send by the server over the control `Stream`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The server received `received` bytes of the image.
+    Received { received: u64, size: u64 },
+    /// The signature of the image was verified.
+    Verified,
+    /// The apply hook succeeded.
+    Applied,
+    /// The commit hook succeeded.
+    Committed,
+    /// The rollback hook succeeded.
+    RolledBack,
+    /// The update failed, `UpdateClient` reports this as error.
+    Failed { message: String },
+}
+
+impl UpdateStatus {
+    /// Is this the last status of an operation?
+    fn is_final(&self) -> bool {
+        match *self {
+            UpdateStatus::Received { .. } | UpdateStatus::Verified => false,
+            _ => true,
+        }
+    }
+}
+
+/// Installs signed images on the device.
+///
+/// The first `Stream` of a service instance is the control `Stream`, the server reports the
+/// status of the update over it. The image is send over a second `Stream`, opened by the client.
+/// The server writes the image to the staging directory and verifies its signature with the
+/// configured public key, before it runs the apply hook. The commit and rollback hooks are run
+/// by separate service instances, for example after the device rebooted into the new image.
+/// If the apply hook fails, the rollback hook is run.
+///
+/// The hooks are commands that get the path of the staged image in `CARRIER_UPDATE_IMAGE`. The
+/// staging directory is only accessible by the user of the peer and the staged image is read-only,
+/// the apply hook also gets the verified image as stdin.
+/// Only one operation runs at a time, further starts are rejected as busy.
+pub struct Update {
+    public_key: PKey<Public>,
+    staging_dir: PathBuf,
+    apply_hook: Option<Vec<String>>,
+    commit_hook: Option<Vec<String>>,
+    rollback_hook: Option<Vec<String>>,
+    running: Arc<AtomicBool>,
+    /// The operation that was acquired by `prepare`, until the instance is started.
+    prepared: Option<Running>,
+}
+
+impl Update {
+    /// Create a new instance that verifies the images with the given public key.
+    pub fn new(public_key: PKey<Public>) -> Update {
+        Update {
+            public_key,
+            staging_dir: "/tmp/carrier-update".into(),
+            apply_hook: None,
+            commit_hook: None,
+            rollback_hook: None,
+            running: Arc::new(AtomicBool::new(false)),
+            prepared: None,
+        }
+    }
+
+    /// Create a new instance that verifies the images with the given public key in PEM format.
+    pub fn from_pem(public_key: &[u8]) -> Result<Update> {
+        Ok(Update::new(PKey::public_key_from_pem(public_key)?))
+    }
+
+    /// Set the directory the image is written to (default `/tmp/carrier-update`).
+    /// The directory is created, if it does not exist. An existing directory needs to be owned by
+    /// the user of the peer and may not be accessible by other users.
+    pub fn set_staging_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.staging_dir = dir.into();
+        self
+    }
+
+    /// Set the command that applies a verified image.
+    pub fn set_apply_hook(mut self, command: Vec<String>) -> Self {
+        self.apply_hook = Some(command);
+        self
+    }
+
+    /// Set the command that commits an applied image.
+    pub fn set_commit_hook(mut self, command: Vec<String>) -> Self {
+        self.commit_hook = Some(command);
+        self
+    }
+
+    /// Set the command that rolls back an applied image.
+    pub fn set_rollback_hook(mut self, command: Vec<String>) -> Self {
+        self.rollback_hook = Some(command);
+        self
+    }
+}
+
+/// Marks an operation as running, until it is dropped.
+struct Running(Arc<AtomicBool>);
+
+impl Running {
+    fn acquire(running: &Arc<AtomicBool>) -> Option<Running> {
+        running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Running(running.clone()))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Server for Update {
+    type Args = UpdateArgs;
+
+    fn prepare(&mut self, _: &UpdateArgs) -> Result<()> {
+        match Running::acquire(&self.running) {
+            Some(running) => {
+                self.prepared = Some(running);
+                Ok(())
+            }
+            None => Err(Error::Busy("An update is already running".into())),
+        }
+    }
+
+    fn abort(&mut self, _: UpdateArgs) {
+        self.prepared = None;
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+        args: UpdateArgs,
+    ) {
+        match args {
+            UpdateArgs::Install { size, .. } => info!(
+                "Update with image of {} bytes for {}",
+                size,
+                context.remote_peer()
+            ),
+            ref args => info!("Update {:?} for {}", args, context.remote_peer()),
+        }
+
+        let running = self.prepared.take();
+        let hooks = Hooks {
+            image: self.staging_dir.join(IMAGE_NAME),
+            apply: self.apply_hook.clone(),
+            commit: self.commit_hook.clone(),
+            rollback: self.rollback_hook.clone(),
+        };
+        let public_key = self.public_key.clone();
+
+        tokio::spawn(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(move |(control, streams)| {
+                    let control: ProtocolStream<UpdateStatus> = match control {
+                        Some(control) => control.into(),
+                        None => bail!("No control `Stream` for update"),
+                    };
+
+                    Ok(serve(args, running, hooks, public_key, control, streams))
+                })
+                .flatten()
+                .map_err(|e| error!("Update error: {:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// The staged image and the hooks of an `Update`.
+struct Hooks {
+    image: PathBuf,
+    apply: Option<Vec<String>>,
+    commit: Option<Vec<String>>,
+    rollback: Option<Vec<String>>,
+}
+
+impl Hooks {
+    /// Runs the given hook, if it is set.
+    /// `image` is the verified image, that is given to the hook as stdin.
+    fn run(
+        &self,
+        name: &'static str,
+        hook: &Option<Vec<String>>,
+        image: Option<File>,
+    ) -> impl Future<Item = (), Error = Error> + Send {
+        let hook = match *hook {
+            Some(ref hook) if !hook.is_empty() => hook,
+            _ => return Either::A(future::ok(())),
+        };
+
+        let mut command = Command::new(&hook[0]);
+        command
+            .args(&hook[1..])
+            .env("CARRIER_UPDATE_IMAGE", &self.image);
+        if let Some(image) = image {
+            command.stdin(Stdio::from(image));
+        }
+        let status = command.status_async();
+
+        Either::B(
+            future::result(status)
+                .flatten()
+                .map_err(Error::from)
+                .and_then(move |status| {
+                    let status = ExecStatus::from(status);
+                    if status.success() {
+                        Ok(())
+                    } else {
+                        bail!("The {} hook failed with {:?}", name, status)
+                    }
+                }),
+        )
+    }
+}
+
+/// Runs the requested operation at the server and reports the status over the control `Stream`.
+fn serve(
+    args: UpdateArgs,
+    running: Option<Running>,
+    hooks: Hooks,
+    public_key: PKey<Public>,
+    control: ProtocolStream<UpdateStatus>,
+    streams: Streams,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (status, receiver) = unbounded();
+
+    let send_status = receiver
+        .map_err(|_| Error::from("Update status closed"))
+        .forward(control.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let operation: Box<dyn Future<Item = UpdateStatus, Error = Error> + Send> = match args {
+        _ if running.is_none() => Box::new(future::err("Update was not prepared".into())),
+        UpdateArgs::Install { size, signature } => {
+            let status = status.clone();
+            let image = hooks.image.clone();
+
+            Box::new(
+                streams
+                    .into_future()
+                    .map_err(|e| e.0)
+                    .and_then(|(data, _)| match data {
+                        Some(data) => Ok(data),
+                        None => bail!("No data `Stream` for update"),
+                    })
+                    .and_then(move |data| {
+                        receive_image(image.clone(), size, data, status.clone())
+                            .and_then(move |file| verify_image(file, image, public_key, signature))
+                            .map(move |file| {
+                                let _ = status.unbounded_send(UpdateStatus::Verified);
+                                file
+                            })
+                    })
+                    .and_then(move |file| {
+                        hooks
+                            .run("apply", &hooks.apply, Some(file))
+                            .or_else(move |e| {
+                                hooks
+                                    .run("rollback", &hooks.rollback, None)
+                                    .then(move |_| Err(e))
+                            })
+                    })
+                    .map(|_| UpdateStatus::Applied),
+            )
+        }
+        UpdateArgs::Commit => Box::new(
+            hooks
+                .run("commit", &hooks.commit, None)
+                .map(|_| UpdateStatus::Committed),
+        ),
+        UpdateArgs::Rollback => Box::new(
+            hooks
+                .run("rollback", &hooks.rollback, None)
+                .map(|_| UpdateStatus::RolledBack),
+        ),
+    };
+
+    let operation = operation.then(move |res| {
+        drop(running);
+        let res = res.unwrap_or_else(|e| UpdateStatus::Failed {
+            message: e.to_string(),
+        });
+        let _ = status.unbounded_send(res);
+        Ok(())
+    });
+
+    operation.join(send_status).map(|_| ())
+}
+
+/// Creates the file for a new image in the staging directory.
+/// The file is created exclusively, a symlink or a file that was placed in the staging directory
+/// is not used.
+fn create_image(image: &Path) -> io::Result<File> {
+    let dir = image.parent().unwrap_or_else(|| Path::new("/"));
+
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::geteuid() }
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Staging directory `{}` may only be accessible by the peer",
+                dir.display()
+            ),
+        ));
+    }
+
+    match fs::remove_file(image) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(image)
+}
+
+/// Receives the image and writes it to the staging directory.
+fn receive_image(
+    image: PathBuf,
+    size: u64,
+    data: Stream,
+    status: UnboundedSender<UpdateStatus>,
+) -> impl Future<Item = File, Error = Error> + Send {
+    blocking(move || create_image(&image))
+        .and_then(move |file| {
+            data.fold((file, 0u64), move |(file, received), chunk| {
+                let received = received + chunk.len() as u64;
+                if received > size {
+                    return Either::A(future::err("Image is bigger than announced".into()));
+                }
+
+                if received / PROGRESS_INTERVAL
+                    != (received - chunk.len() as u64) / PROGRESS_INTERVAL
+                    || received == size
+                {
+                    let _ = status.unbounded_send(UpdateStatus::Received { received, size });
+                }
+
+                Either::B(blocking(move || {
+                    let mut file = file;
+                    file.write_all(&chunk)?;
+                    Ok((file, received))
+                }))
+            })
+        })
+        .and_then(move |(file, received)| {
+            if received != size {
+                bail!(
+                    "Image is incomplete, received {} of {} bytes",
+                    received,
+                    size
+                );
+            }
+
+            Ok(blocking(move || file.sync_all().map(|_| file)))
+        })
+        .flatten()
+}
+
+/// Verifies the signature of the staged image, the image is removed if the verification fails.
+/// The image is read from the file that it was written to and the verified image is made
+/// read-only. Resolves to the verified file, positioned at its start.
+fn verify_image(
+    mut file: File,
+    image: PathBuf,
+    public_key: PKey<Public>,
+    signature: Vec<u8>,
+) -> impl Future<Item = File, Error = Error> + Send {
+    blocking(move || {
+        let verified = (|| -> Result<bool> {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+            file.seek(SeekFrom::Start(0))?;
+            let mut buf = vec![0; 64 * 1024];
+
+            loop {
+                match file.read(&mut buf)? {
+                    0 => break,
+                    len => verifier.update(&buf[..len])?,
+                }
+            }
+
+            if !verifier.verify(&signature)? {
+                return Ok(false);
+            }
+
+            file.set_permissions(Permissions::from_mode(0o400))?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok(true)
+        })();
+
+        match verified {
+            Ok(true) => Ok(Ok(file)),
+            Ok(false) => {
+                let _ = fs::remove_file(&image);
+                Ok(Err(Error::from("The signature of the image is invalid")))
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&image);
+                Ok(Err(e))
+            }
+        }
+    })
+    .and_then(|res| res)
+}
+
+enum Operation {
+    Install { image: PathBuf },
+    Commit,
+    Rollback,
+}
+
+/// Client side of `Update`.
+/// Resolves to the final `UpdateStatus` of the operation.
+pub struct UpdateClient {
+    args: UpdateArgs,
+    operation: Operation,
+    progress: Option<UnboundedSender<UpdateStatus>>,
+}
+
+impl UpdateClient {
+    /// Install the given image, signed with SHA-256 by the private key that matches the
+    /// public key of the server.
+    pub fn install<P: Into<PathBuf>>(image: P, signature: Vec<u8>) -> Result<UpdateClient> {
+        let image = image.into();
+        let size = fs::metadata(&image)?.len();
+
+        Ok(UpdateClient {
+            args: UpdateArgs::Install { size, signature },
+            operation: Operation::Install { image },
+            progress: None,
+        })
+    }
+
+    /// Commit the applied image.
+    pub fn commit() -> UpdateClient {
+        UpdateClient {
+            args: UpdateArgs::Commit,
+            operation: Operation::Commit,
+            progress: None,
+        }
+    }
+
+    /// Roll back the applied image.
+    pub fn rollback() -> UpdateClient {
+        UpdateClient {
+            args: UpdateArgs::Rollback,
+            operation: Operation::Rollback,
+            progress: None,
+        }
+    }
+
+    /// Send every `UpdateStatus` that is reported by the server to the given sender.
+    pub fn report_progress(mut self, progress: UnboundedSender<UpdateStatus>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+pub struct UpdateFuture {
+    future: Box<dyn Future<Item = UpdateStatus, Error = Error> + Send>,
+}
+
+impl Future for UpdateFuture {
+    type Item = UpdateStatus;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+
+impl Client for UpdateClient {
+    type Error = Error;
+    type Future = UpdateFuture;
+    type Args = UpdateArgs;
+
+    fn args(&self) -> Self::Args {
+        self.args.clone()
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        mut new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let operation = self.operation;
+        let progress = self.progress;
+
+        let future = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(move |(control, _)| {
+                let control: ProtocolStream<UpdateStatus> = match control {
+                    Some(control) => control.into(),
+                    None => bail!("No control `Stream` for update"),
+                };
+
+                let status = receive_status(control, progress);
+
+                Ok(match operation {
+                    Operation::Install { image } => Either::A(
+                        blocking(move || File::open(&image))
+                            .and_then(move |file| {
+                                new_stream_handle
+                                    .new_stream()
+                                    .and_then(move |stream| SendFile::new(file, 0, stream))
+                            })
+                            .join(status)
+                            .map(|(_, status)| status),
+                    ),
+                    Operation::Commit | Operation::Rollback => Either::B(status),
+                })
+            })
+            .flatten();
+
+        Ok(UpdateFuture {
+            future: Box::new(future),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+}
+
+/// Receives the status messages, until the final status.
+fn receive_status(
+    control: ProtocolStream<UpdateStatus>,
+    progress: Option<UnboundedSender<UpdateStatus>>,
+) -> impl Future<Item = UpdateStatus, Error = Error> + Send {
+    control
+        .map_err(Error::from)
+        .and_then(|status| match status {
+            UpdateStatus::Failed { message } => bail!("Update failed: {}", message),
+            status => Ok(status),
+        })
+        .filter(move |status| {
+            if let Some(ref progress) = progress {
+                let _ = progress.unbounded_send(status.clone());
+            }
+            status.is_final()
+        })
+        .into_future()
+        .map_err(|e| e.0)
+        .and_then(|(status, _)| match status {
+            Some(status) => Ok(status),
+            None => bail!("Update control `Stream` closed"),
+        })
+}
@@ -0,0 +1,119 @@
+use error::*;
+
+use hole_punch::{FileFormat, PubKeyHash};
+
+use openssl::{
+    stack::Stack,
+    x509::{
+        store::{X509Store, X509StoreBuilder},
+        X509StoreContext, X509,
+    },
+};
+
+use std::{fs::File, io::Read, path::Path};
+
+/// The certificate chain of the local `Peer` and the CAs that are trusted to sign the certificate
+/// chains of remote `Peer`s.
+///
+/// The chains are exchanged when a service instance is started, hole_punch only exposes the public
+/// key of the remote `Peer`.
+pub struct Certificates {
+    /// The certificate chain of the local `Peer` in `PEM` format.
+    chain: Vec<String>,
+    trusted: X509Store,
+}
+
+impl Certificates {
+    pub fn new<P: AsRef<Path>>(chain: Vec<X509>, ca_files: &[P]) -> Result<Certificates> {
+        let chain = chain
+            .iter()
+            .map(|cert| Ok(String::from_utf8_lossy(&cert.to_pem()?).into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut trusted = X509StoreBuilder::new()?;
+        for file in ca_files {
+            for cert in load_certificates_from_file(file.as_ref())? {
+                trusted.add_cert(cert)?;
+            }
+        }
+
+        Ok(Certificates {
+            chain,
+            trusted: trusted.build(),
+        })
+    }
+
+    /// The certificate chain of the local `Peer` in `PEM` format.
+    pub fn local_chain(&self) -> Vec<String> {
+        self.chain.clone()
+    }
+
+    /// Verifies the certificate chain that was send by the given remote `Peer`.
+    /// Returns the chain, if its first certificate contains the public key of the remote `Peer`
+    /// and the chain is signed by one of the trusted CAs.
+    pub fn verify(&self, chain: &[String], remote_peer: &PubKeyHash) -> Option<Vec<X509>> {
+        match self.verify_chain(chain, remote_peer) {
+            Ok(chain) => Some(chain),
+            Err(e) => {
+                debug!(
+                    "Could not verify certificate chain of peer {}: {:?}",
+                    remote_peer, e
+                );
+                None
+            }
+        }
+    }
+
+    fn verify_chain(&self, chain: &[String], remote_peer: &PubKeyHash) -> Result<Vec<X509>> {
+        let leaf = match chain.first() {
+            Some(leaf) => leaf,
+            None => bail!("Empty certificate chain."),
+        };
+
+        if PubKeyHash::from_x509_pem(leaf.as_bytes(), false)? != *remote_peer {
+            bail!("Certificate does not belong to the peer.");
+        }
+
+        let chain = chain
+            .iter()
+            .map(|cert| Ok(X509::from_pem(cert.as_bytes())?))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut intermediates = Stack::new()?;
+        for cert in &chain[1..] {
+            intermediates.push(cert.clone())?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        if !context.init(&self.trusted, &chain[0], &intermediates, |c| {
+            c.verify_cert()
+        })? {
+            bail!("Certificate chain is not signed by a trusted CA.");
+        }
+
+        Ok(chain)
+    }
+}
+
+/// Loads the certificate chain from memory.
+pub fn load_certificates(chain: &[Vec<u8>], format: FileFormat) -> Result<Vec<X509>> {
+    let mut res = Vec::new();
+
+    for data in chain {
+        match format {
+            FileFormat::PEM => res.extend(X509::stack_from_pem(data)?),
+            FileFormat::DER => res.push(X509::from_der(data)?),
+        }
+    }
+
+    Ok(res)
+}
+
+/// Loads the certificates from the given file.
+/// The file needs to be in `PEM` format.
+pub fn load_certificates_from_file(path: &Path) -> Result<Vec<X509>> {
+    let mut file = File::open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(X509::stack_from_pem(&data)?)
+}
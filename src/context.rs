@@ -1,134 +1,632 @@
+use certificates::Certificates;
+use error::*;
 use hole_punch::ProtocolStream;
-use protocol::Protocol;
-use service::{Client, Server, ServiceId, Streams};
-use stream::{NewStreamHandle, Stream};
+use limits::{Limiter, ServiceLimits, ServiceMetrics};
+use protocol::{ErrorCode, Protocol};
+use service::{
+    Client, CloseReason, DynServer, Instance, InstanceGuard, Server, ServiceAcl, ServiceContext,
+    ServiceDescriptor, ServiceId, ServiceTimeouts, ShutdownSender, ShutdownSignal, Streams,
+    Watchdog,
+};
+use stream::{self, NewStreamHandle, Stream, StreamCounter};
+use PubKeyHash;
 
 use std::{
     collections::HashMap,
     result,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, Weak,
+    },
 };
 
-use tokio::runtime::TaskExecutor;
+use tokio::{self, runtime::TaskExecutor};
+
+use tokio_threadpool;
 
 use futures::{
-    sync::mpsc::{channel, Receiver, Sender, UnboundedSender},
-    Sink, Stream as FStream,
+    future,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    Async, AsyncSink, Sink, Stream as FStream,
 };
 
+use serde_json::Value;
+
+use openssl::rand;
+
+/// A registered service, the server is locked while it is started.
+struct RegisteredService {
+    server: Mutex<Box<dyn DynServer>>,
+    acl: Mutex<ServiceAcl>,
+}
+
+/// The default maximum number of open `Stream`s of one service instance.
+const DEFAULT_MAX_STREAMS_PER_SERVICE_INSTANCE: usize = 32;
+
+/// The number of shards the service instances are split into.
+const SERVICE_INSTANCE_SHARDS: usize = 32;
+
+struct ServiceInstance {
+    streams: Sender<Stream>,
+    /// The number of open `Stream`s, including the ones that wait to be polled from `Streams`.
+    open_streams: Arc<AtomicUsize>,
+    /// The name of the service, if the instance was started by the remote peer.
+    started_service: Option<String>,
+    /// The remote peer of the instance, only this peer may access the instance.
+    remote_peer: PubKeyHash,
+    instance: Instance,
+    /// Held by the `Streams` and the `Stream`s of the instance, the instance is removed when the
+    /// last of them is dropped.
+    guard: Weak<InstanceGuard>,
+    /// Closes the instance, `None` after it was closed.
+    close: Option<oneshot::Sender<CloseReason>>,
+}
+
+/// The service instances, split into shards by their id.
+/// Every shard has its own lock, so `Stream`s of different instances can be processed
+/// concurrently.
+struct ServiceInstances {
+    shards: Vec<Mutex<HashMap<ServiceId, ServiceInstance>>>,
+    /// The number of service instances in all shards.
+    len: AtomicUsize,
+}
+
+impl ServiceInstances {
+    fn new() -> ServiceInstances {
+        ServiceInstances {
+            shards: (0..SERVICE_INSTANCE_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, service_id: ServiceId) -> MutexGuard<'_, HashMap<ServiceId, ServiceInstance>> {
+        self.shards[(service_id % SERVICE_INSTANCE_SHARDS as u64) as usize]
+            .lock()
+            .unwrap()
+    }
+
+    fn insert(&self, service_id: ServiceId, instance: ServiceInstance) {
+        if self
+            .shard(service_id)
+            .insert(service_id, instance)
+            .is_none()
+        {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn remove(&self, service_id: ServiceId) -> Option<ServiceInstance> {
+        let instance = self.shard(service_id).remove(&service_id);
+
+        if instance.is_some() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        instance
+    }
+
+    fn contains(&self, service_id: ServiceId) -> bool {
+        self.shard(service_id).contains_key(&service_id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// The state of the `PeerContext`.
+/// The parts are locked independently and only for short operations. `Server::start` is called
+/// while holding only the lock of the started service.
 struct Inner {
-    services: HashMap<String, Box<dyn Server + Send>>,
-    service_instances: HashMap<ServiceId, UnboundedSender<Stream>>,
-    next_service_id: ServiceId,
+    services: RwLock<HashMap<String, Arc<RegisteredService>>>,
+    service_instances: ServiceInstances,
+    max_streams_per_service_instance: AtomicUsize,
+    limiter: Mutex<Limiter>,
+    timeouts: RwLock<HashMap<String, ServiceTimeouts>>,
     service_instance_dropped_sender: Sender<ServiceId>,
+    /// Signals the shutdown, `None` after the shutdown started.
+    shutdown_sender: Mutex<Option<ShutdownSender>>,
+    shutdown_signal: ShutdownSignal,
+    /// Notified, when all service instances are dropped after the shutdown started.
+    drained_senders: Mutex<Vec<oneshot::Sender<()>>>,
+    /// Set when the `Peer` is build.
+    certificates: RwLock<Option<Arc<Certificates>>>,
+}
+
+/// The request of a remote peer to start a service instance.
+pub struct ServiceStartRequest {
+    pub name: String,
+    pub remote_service_id: ServiceId,
+    pub args: Value,
+    /// The certificate chain that was send by the remote peer.
+    pub certificate_chain: Vec<String>,
 }
 
 impl Inner {
     fn new() -> (Inner, Receiver<ServiceId>) {
         let (service_instance_dropped_sender, receiver) = channel(0);
+        let (shutdown_sender, shutdown_signal) = ShutdownSignal::new();
 
         (
             Inner {
-                services: HashMap::new(),
-                service_instances: HashMap::new(),
-                next_service_id: 0,
+                services: RwLock::new(HashMap::new()),
+                service_instances: ServiceInstances::new(),
+                max_streams_per_service_instance: AtomicUsize::new(
+                    DEFAULT_MAX_STREAMS_PER_SERVICE_INSTANCE,
+                ),
+                limiter: Mutex::new(Limiter::default()),
+                timeouts: RwLock::new(HashMap::new()),
                 service_instance_dropped_sender,
+                shutdown_sender: Mutex::new(Some(shutdown_sender)),
+                shutdown_signal,
+                drained_senders: Mutex::new(Vec::new()),
+                certificates: RwLock::new(None),
             },
             receiver,
         )
     }
 
-    fn register_service<S: Server + 'static>(&mut self, service: S) {
-        self.services
-            .insert(service.name().into(), Box::new(service));
+    fn register_service<S: Server + 'static>(&self, service: S, acl: ServiceAcl) {
+        self.services.write().unwrap().insert(
+            service.name().into(),
+            Arc::new(RegisteredService {
+                server: Mutex::new(Box::new(service)),
+                acl: Mutex::new(acl),
+            }),
+        );
     }
 
-    fn service_instance_dropped(&mut self, service_id: ServiceId) {
-        self.service_instances.remove(&service_id);
+    fn service(&self, name: &str) -> Option<Arc<RegisteredService>> {
+        self.services.read().unwrap().get(name).cloned()
     }
 
-    fn next_service_id(&mut self) -> ServiceId {
-        let res = self.next_service_id;
-        self.next_service_id += 1;
-        res
+    fn service_instance_dropped(&self, service_id: ServiceId) {
+        if let Some(instance) = self.service_instances.remove(service_id) {
+            if let Some(name) = instance.started_service {
+                self.limiter
+                    .lock()
+                    .unwrap()
+                    .instance_dropped(&name, &instance.remote_peer);
+            }
+        }
+
+        // Checked while holding the lock, so a concurrent `shutdown` can not miss the drain.
+        let mut drained_senders = self.drained_senders.lock().unwrap();
+        if self.service_instances.is_empty() {
+            for sender in drained_senders.drain(..) {
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    /// Starts the shutdown.
+    /// Returns a receiver that is notified when all service instances are dropped, `None` if
+    /// there are no service instances.
+    fn shutdown(&self) -> Option<oneshot::Receiver<()>> {
+        if let Some(sender) = self.shutdown_sender.lock().unwrap().take() {
+            sender.send();
+        }
+
+        let mut drained_senders = self.drained_senders.lock().unwrap();
+        if self.service_instances.is_empty() {
+            None
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            drained_senders.push(sender);
+            Some(receiver)
+        }
+    }
+
+    fn service_metrics(&self) -> ServiceMetrics {
+        self.limiter.lock().unwrap().metrics()
+    }
+
+    fn list_services(&self, remote_peer: &PubKeyHash) -> Vec<ServiceDescriptor> {
+        let mut services = self
+            .services
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| service.acl.lock().unwrap().is_allowed(remote_peer))
+            .map(|(name, _)| ServiceDescriptor { name: name.clone() })
+            .collect::<Vec<_>>();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        services
+    }
+
+    /// Returns a new random `ServiceId`, that is not used by another service instance.
+    /// The ids are random, so remote peers can not guess the ids of other service instances.
+    fn next_service_id(&self) -> Result<ServiceId> {
+        loop {
+            let mut bytes = [0u8; 8];
+            rand::rand_bytes(&mut bytes)?;
+            let id = bytes
+                .iter()
+                .fold(0, |id, b| (id << 8) | ServiceId::from(*b));
+
+            if !self.service_instances.contains(id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Calls the given function with the service instance with the given id, if it belongs to the
+    /// given remote peer. The shard of the instance is locked while the function is called.
+    fn with_service_instance<F, R>(
+        &self,
+        service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        f: F,
+    ) -> R
+    where
+        F: FnOnce(Option<&mut ServiceInstance>) -> R,
+    {
+        let mut shard = self.service_instances.shard(service_id);
+
+        match shard.get_mut(&service_id) {
+            Some(ref instance) if instance.remote_peer != *remote_peer => {
+                warn!(
+                    "Peer {} tried to access service instance {} of peer {}",
+                    remote_peer, service_id, instance.remote_peer
+                );
+                f(None)
+            }
+            instance => f(instance),
+        }
+    }
+
+    fn local_certificate_chain(&self) -> Vec<String> {
+        match *self.certificates.read().unwrap() {
+            Some(ref certificates) => certificates.local_chain(),
+            None => Vec::new(),
+        }
     }
 
     fn create_new_stream_handle_and_streams(
-        &mut self,
-        stream: Stream,
+        &self,
+        mut stream: Stream,
         local_service_id: ServiceId,
         remote_service_id: ServiceId,
-    ) -> (NewStreamHandle, Streams) {
-        let new_stream_handle = NewStreamHandle::new(remote_service_id, &stream);
+        remote_certificate_chain: Vec<String>,
+        started_service: Option<String>,
+        version: u32,
+    ) -> (ServiceContext, NewStreamHandle, Streams, Instance) {
+        let (close, instance) = Instance::new();
+        let stream_peer = stream.peer_identifier().clone();
+        let certificate_chain = self
+            .certificates
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|c| c.verify(&remote_certificate_chain, &stream_peer));
+        let context = ServiceContext::new(&stream, certificate_chain);
+        let guard = InstanceGuard::new(
+            local_service_id,
+            self.service_instance_dropped_sender.clone(),
+        );
+        let new_stream_handle = NewStreamHandle::new(
+            remote_service_id,
+            version,
+            &stream,
+            instance.clone(),
+            Arc::downgrade(&guard),
+        );
+
+        let open_streams = Arc::new(AtomicUsize::new(0));
+        stream.set_counter(StreamCounter::new(open_streams.clone()));
+        stream.set_instance(instance.clone(), guard.clone());
+
         let (streams, streams_sender) = Streams::new(
             stream,
-            self.service_instance_dropped_sender.clone(),
+            guard.clone(),
+            self.shutdown_signal.clone(),
+            instance.clone(),
+            self.max_streams_per_service_instance.load(Ordering::SeqCst),
+        );
+        self.service_instances.insert(
             local_service_id,
+            ServiceInstance {
+                streams: streams_sender,
+                open_streams,
+                started_service,
+                remote_peer: stream_peer,
+                instance: instance.clone(),
+                guard: Arc::downgrade(&guard),
+                close: Some(close),
+            },
         );
-        self.service_instances
-            .insert(local_service_id, streams_sender);
 
-        (new_stream_handle, streams)
+        (context, new_stream_handle, streams, instance)
     }
 
     fn start_server_service_instance(
-        &mut self,
-        name: &str,
-        remote_service_id: ServiceId,
+        &self,
+        request: ServiceStartRequest,
+        remote_peer: &PubKeyHash,
         mut stream: ProtocolStream<Protocol>,
-    ) {
-        if self.services.contains_key(name) {
-            let id = self.next_service_id();
-            send_protocol_message(&mut stream, Protocol::ServiceStarted { id });
+        version: u32,
+    ) -> Option<Watchdog> {
+        let ServiceStartRequest {
+            name,
+            remote_service_id,
+            args,
+            certificate_chain,
+        } = request;
+        let name = &name[..];
 
-            let (new_stream_handle, streams) =
-                self.create_new_stream_handle_and_streams(stream.into(), id, remote_service_id);
+        if self.shutdown_signal.is_shutting_down() {
+            send_protocol_message(
+                &mut stream,
+                version,
+                Protocol::error(ErrorCode::Busy, "Peer is shutting down."),
+            );
+            return None;
+        }
 
-            self.services
-                .get_mut(name)
-                .unwrap()
-                .start(streams, new_stream_handle);
-        } else {
-            send_protocol_message(&mut stream, Protocol::ServiceNotFound);
+        let service = match self.service(name) {
+            Some(service) => service,
+            None => {
+                send_protocol_message(
+                    &mut stream,
+                    version,
+                    Protocol::error(
+                        ErrorCode::NotFound,
+                        format!("Service `{}` not found.", name),
+                    ),
+                );
+                return None;
+            }
+        };
+
+        if !service.acl.lock().unwrap().is_allowed(remote_peer) {
+            send_protocol_message(
+                &mut stream,
+                version,
+                Protocol::error(
+                    ErrorCode::Unauthorized,
+                    format!("Not authorized to start service `{}`.", name),
+                ),
+            );
+            return None;
+        }
+
+        let id = match self.next_service_id() {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Could not create service id: {:?}", e);
+                send_protocol_message(
+                    &mut stream,
+                    version,
+                    Protocol::error(ErrorCode::Busy, "Could not create service id."),
+                );
+                return None;
+            }
+        };
+
+        // The limits are checked before the arguments, a `Server` only reserves resources in
+        // `prepare`, when the start is allowed.
+        let check = self.limiter.lock().unwrap().check(name, remote_peer);
+        if let Err(rejection) = check {
+            send_protocol_message(
+                &mut stream,
+                version,
+                Protocol::error(ErrorCode::Busy, rejection.message(name)),
+            );
+            return None;
+        }
+
+        // Only this service is locked, while it is started.
+        let mut server = service.server.lock().unwrap();
+        let args = server
+            .parse_args(args)
+            .map_err(|reason| (ErrorCode::BadArguments, reason))
+            .and_then(|args| match server.prepare(&*args) {
+                Ok(()) => Ok(args),
+                Err(Error::Busy(reason)) => Err((ErrorCode::Busy, reason)),
+                Err(Error::BadArguments(reason)) => Err((ErrorCode::BadArguments, reason)),
+                Err(e) => Err((ErrorCode::BadArguments, e.to_string())),
+            });
+
+        match args {
+            Ok(args) => {
+                let stream: Stream = stream.into();
+                let remote_new_stream_handle = stream.get_ref().new_stream_handle().clone();
+
+                let (context, new_stream_handle, mut streams, instance) = self
+                    .create_new_stream_handle_and_streams(
+                        stream,
+                        id,
+                        remote_service_id,
+                        certificate_chain,
+                        Some(name.to_owned()),
+                        version,
+                    );
+
+                // The instance is registered first, the remote peer may connect to it, as soon
+                // as it knows the id.
+                let local_certificate_chain = self.local_certificate_chain();
+                let mut informed = false;
+                streams.map_first_stream(|first| {
+                    let mut stream: stream::ProtocolStream<Protocol> = first.into();
+                    informed = send_protocol_message(
+                        &mut stream,
+                        version,
+                        Protocol::ServiceStarted {
+                            id,
+                            certificate_chain: local_certificate_chain,
+                        },
+                    );
+                    stream.into()
+                });
+
+                // Dropping the `Streams` also removes the instance again.
+                if !informed {
+                    debug!("Client went away, aborting start of service `{}`", name);
+                    server.abort(args);
+                    return None;
+                }
+
+                server.start(context, streams, new_stream_handle, args);
+
+                match self.timeouts.read().unwrap().get(name) {
+                    Some(timeouts) if timeouts.is_enabled() => Some(Watchdog {
+                        id,
+                        remote_peer: remote_peer.clone(),
+                        instance,
+                        timeouts: *timeouts,
+                        new_stream_handle: remote_new_stream_handle,
+                        version,
+                        remote_service_id,
+                    }),
+                    _ => None,
+                }
+            }
+            Err((code, reason)) => {
+                self.limiter
+                    .lock()
+                    .unwrap()
+                    .start_cancelled(name, remote_peer);
+                send_protocol_message(&mut stream, version, Protocol::error(code, reason));
+                None
+            }
         }
     }
 
     fn start_client_service_instance<C>(
-        &mut self,
+        &self,
         service: C,
         local_service_id: ServiceId,
         remote_service_id: ServiceId,
+        remote_certificate_chain: Vec<String>,
         stream: Stream,
+        version: u32,
     ) -> result::Result<C::Future, C::Error>
     where
         C: Client,
     {
-        let (new_stream_handle, streams) =
-            self.create_new_stream_handle_and_streams(stream, local_service_id, remote_service_id);
+        let (context, new_stream_handle, streams, _) = self.create_new_stream_handle_and_streams(
+            stream,
+            local_service_id,
+            remote_service_id,
+            remote_certificate_chain,
+            None,
+            version,
+        );
 
-        service.start(streams, new_stream_handle)
+        service.start(context, streams, new_stream_handle)
     }
 
     fn connect_stream_to_service_instance(
-        &mut self,
+        &self,
         mut stream: ProtocolStream<Protocol>,
         service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        version: u32,
     ) {
-        match self.service_instances.get_mut(&service_id) {
-            Some(instance) => {
-                send_protocol_message(&mut stream, Protocol::ServiceConnected);
-                let _ = instance.unbounded_send(stream.into());
-            }
-            None => {
-                send_protocol_message(&mut stream, Protocol::ServiceNotFound);
+        let max_streams = self.max_streams_per_service_instance.load(Ordering::SeqCst);
+
+        self.with_service_instance(service_id, remote_peer, |instance| {
+            // The instance does not accept new `Stream`s, after it dropped its `Streams`.
+            let instance = instance
+                .filter(|instance| !instance.streams.is_closed())
+                .and_then(|instance| instance.guard.upgrade().map(|guard| (instance, guard)));
+
+            match instance {
+                Some((ref instance, _))
+                    if instance.open_streams.load(Ordering::SeqCst) >= max_streams =>
+                {
+                    send_protocol_message(
+                        &mut stream,
+                        version,
+                        Protocol::error(
+                            ErrorCode::Busy,
+                            format!(
+                                "Service instance `{}` has too many open streams.",
+                                service_id
+                            ),
+                        ),
+                    );
+                }
+                Some((instance, guard)) => {
+                    send_protocol_message(&mut stream, version, Protocol::ServiceConnected);
+
+                    let mut stream: Stream = stream.into();
+                    stream.set_counter(StreamCounter::new(instance.open_streams.clone()));
+                    stream.set_instance(instance.instance.clone(), guard);
+                    // The queue can not be full, it is as large as the maximum number of open
+                    // `Stream`s.
+                    if let Err(e) = instance.streams.try_send(stream) {
+                        warn!(
+                            "Could not pass `Stream` to service instance {}: {}",
+                            service_id, e
+                        );
+                    }
+                }
+                None => {
+                    send_protocol_message(
+                        &mut stream,
+                        version,
+                        Protocol::error(
+                            ErrorCode::NotFound,
+                            format!("Service instance `{}` not found.", service_id),
+                        ),
+                    );
+                }
             }
+        })
+    }
+
+    /// Closes the given service instance of the given remote peer for the given reason.
+    /// Returns `false`, if the instance does not exist or was already closed.
+    fn close_service_instance(
+        &self,
+        service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        reason: CloseReason,
+    ) -> bool {
+        match self
+            .with_service_instance(service_id, remote_peer, |i| i.and_then(|i| i.close.take()))
+        {
+            Some(close) => close.send(reason).is_ok(),
+            None => false,
         }
     }
+
+    fn answer_keepalive(
+        &self,
+        mut stream: ProtocolStream<Protocol>,
+        service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        version: u32,
+    ) {
+        let msg = if self.with_service_instance(service_id, remote_peer, |i| i.is_some()) {
+            Protocol::KeepaliveAck
+        } else {
+            Protocol::error(
+                ErrorCode::NotFound,
+                format!("Service instance `{}` not found.", service_id),
+            )
+        };
+
+        send_protocol_message(&mut stream, version, msg);
+    }
 }
 
-fn send_protocol_message(stream: &mut ProtocolStream<Protocol>, msg: Protocol) {
-    let _ = stream.start_send(msg);
-    let _ = stream.poll_complete();
+/// Sends the message in the given protocol version.
+/// Returns if the message could be send, it fails if the remote side closed the stream.
+fn send_protocol_message<S>(stream: &mut S, version: u32, msg: Protocol) -> bool
+where
+    S: Sink<SinkItem = Protocol>,
+{
+    match stream.start_send(msg.for_version(version)) {
+        Ok(AsyncSink::Ready) => stream.poll_complete().is_ok(),
+        _ => false,
+    }
 }
 
 /// Spawn the service dropped receiver that informs the `PeerContext` about dropped service
@@ -146,9 +644,13 @@ fn spawn_service_dropped(
 
 /// The context of the peer.
 /// It stores all registered services and service instances.
+///
+/// The context is not locked as a whole. `Stream`s of different service instances and starts of
+/// different services are processed concurrently, a slow `Server::start` only delays other starts
+/// of the same service.
 #[derive(Clone)]
 pub struct PeerContext {
-    inner: Arc<Mutex<Inner>>,
+    inner: Arc<Inner>,
 }
 
 impl PeerContext {
@@ -156,7 +658,7 @@ impl PeerContext {
         let (inner, service_dropped) = Inner::new();
 
         let context = PeerContext {
-            inner: Arc::new(Mutex::new(inner)),
+            inner: Arc::new(inner),
         };
 
         spawn_service_dropped(context.clone(), service_dropped, handle);
@@ -164,27 +666,51 @@ impl PeerContext {
         context
     }
 
-    pub fn register_service<S: Server + 'static>(&mut self, service: S) {
-        self.inner.lock().unwrap().register_service(service);
+    pub fn register_service<S: Server + 'static>(&mut self, service: S, acl: ServiceAcl) {
+        self.inner.register_service(service, acl);
     }
 
     fn service_instance_dropped(&mut self, service_id: ServiceId) {
-        self.inner
-            .lock()
-            .unwrap()
-            .service_instance_dropped(service_id);
+        self.inner.service_instance_dropped(service_id);
     }
 
+    /// Starts the requested service instance.
+    /// `Server::parse_args` and `Server::start` may block and the service is locked while they
+    /// run, so the start runs as blocking operation on the thread pool. Waiting for the lock or
+    /// for a slow `Server::start` does not stall the other `Stream`s of the worker thread.
     pub fn start_server_service_instance(
         &mut self,
-        name: &str,
-        remote_service_id: ServiceId,
+        request: ServiceStartRequest,
+        remote_peer: &PubKeyHash,
         stream: ProtocolStream<Protocol>,
+        version: u32,
     ) {
-        self.inner
-            .lock()
-            .unwrap()
-            .start_server_service_instance(name, remote_service_id, stream);
+        let context = self.clone();
+        let remote_peer = remote_peer.clone();
+        let mut start = Some((request, stream));
+
+        tokio::spawn(future::poll_fn(move || {
+            let mut start_instance = || {
+                let (request, stream) =
+                    start.take().expect("Service instance is only started once");
+                context
+                    .inner
+                    .start_server_service_instance(request, &remote_peer, stream, version)
+            };
+
+            let watchdog = match tokio_threadpool::blocking(&mut start_instance) {
+                Ok(Async::Ready(watchdog)) => watchdog,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // Not running on the thread pool, start the instance directly.
+                Err(_) => start_instance(),
+            };
+
+            if let Some(watchdog) = watchdog {
+                watchdog.spawn(context.clone());
+            }
+
+            Ok(Async::Ready(()))
+        }));
     }
 
     pub fn start_client_service_instance<C>(
@@ -192,31 +718,97 @@ impl PeerContext {
         service: C,
         local_service_id: ServiceId,
         remote_service_id: ServiceId,
+        remote_certificate_chain: Vec<String>,
         stream: Stream,
+        version: u32,
     ) -> result::Result<C::Future, C::Error>
     where
         C: Client,
     {
-        self.inner.lock().unwrap().start_client_service_instance(
+        self.inner.start_client_service_instance(
             service,
             local_service_id,
             remote_service_id,
+            remote_certificate_chain,
             stream,
+            version,
         )
     }
 
-    pub fn next_service_id(&mut self) -> ServiceId {
-        self.inner.lock().unwrap().next_service_id()
+    pub fn set_certificates(&mut self, certificates: Certificates) {
+        *self.inner.certificates.write().unwrap() = Some(Arc::new(certificates));
+    }
+
+    /// The certificate chain of the local peer, that is send to remote peers.
+    pub fn local_certificate_chain(&self) -> Vec<String> {
+        self.inner.local_certificate_chain()
+    }
+
+    pub fn list_services(&self, remote_peer: &PubKeyHash) -> Vec<ServiceDescriptor> {
+        self.inner.list_services(remote_peer)
+    }
+
+    pub fn shutdown(&mut self) -> Option<oneshot::Receiver<()>> {
+        self.inner.shutdown()
+    }
+
+    pub fn set_service_limits<F: FnOnce(&mut ServiceLimits)>(&mut self, set: F) {
+        set(&mut self.inner.limiter.lock().unwrap().limits);
+    }
+
+    pub fn service_metrics(&self) -> ServiceMetrics {
+        self.inner.service_metrics()
+    }
+
+    pub fn set_service_timeouts<F: FnOnce(&mut ServiceTimeouts)>(&mut self, name: String, set: F) {
+        set(self
+            .inner
+            .timeouts
+            .write()
+            .unwrap()
+            .entry(name)
+            .or_insert_with(ServiceTimeouts::default));
+    }
+
+    pub fn close_service_instance(
+        &mut self,
+        service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        reason: CloseReason,
+    ) -> bool {
+        self.inner
+            .close_service_instance(service_id, remote_peer, reason)
+    }
+
+    pub fn answer_keepalive(
+        &mut self,
+        stream: ProtocolStream<Protocol>,
+        service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        version: u32,
+    ) {
+        self.inner
+            .answer_keepalive(stream, service_id, remote_peer, version);
+    }
+
+    pub fn set_max_streams_per_service_instance(&mut self, max: usize) {
+        self.inner
+            .max_streams_per_service_instance
+            .store(max, Ordering::SeqCst);
+    }
+
+    pub fn next_service_id(&mut self) -> Result<ServiceId> {
+        self.inner.next_service_id()
     }
 
     pub fn connect_stream_to_service_instance(
         &mut self,
         stream: ProtocolStream<Protocol>,
         service_id: ServiceId,
+        remote_peer: &PubKeyHash,
+        version: u32,
     ) {
         self.inner
-            .lock()
-            .unwrap()
-            .connect_stream_to_service_instance(stream, service_id);
+            .connect_stream_to_service_instance(stream, service_id, remote_peer, version);
     }
 }
@@ -1,3 +1,5 @@
+use protocol::VersionRange;
+use service::CloseReason;
 use PubKeyHash;
 
 use failure;
@@ -7,6 +9,8 @@ use hole_punch;
 
 use std::{io, result};
 
+use serde_json;
+
 use openssl;
 
 pub type Result<T> = result::Result<T, Error>;
@@ -19,10 +23,44 @@ pub enum Error {
     IoError(#[cause] io::Error),
     #[fail(display = "Openssl Error {}", _0)]
     OpenSslError(#[cause] openssl::error::ErrorStack),
+    #[fail(display = "Json Error {}", _0)]
+    JsonError(#[cause] serde_json::Error),
     #[fail(display = "Error {}", _0)]
     Custom(failure::Error),
     #[fail(display = "Peer {} not found.", _0)]
     PeerNotFound(PubKeyHash),
+    #[fail(
+        display = "Incompatible protocol versions (local: {}, remote: {}).",
+        local, remote
+    )]
+    IncompatibleProtocol {
+        local: VersionRange,
+        remote: VersionRange,
+    },
+    #[fail(
+        display = "Remote peer does not support the protocol feature `{}`.",
+        _0
+    )]
+    FeatureNotSupported(&'static str),
+    #[fail(display = "Not found: {}", _0)]
+    NotFound(String),
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    #[fail(display = "Busy: {}", _0)]
+    Busy(String),
+    #[fail(display = "Timeout: {}", _0)]
+    Timeout(String),
+    #[fail(display = "Service instance closed: {}", _0)]
+    ServiceClosed(CloseReason),
+    #[fail(display = "Bad arguments: {}", _0)]
+    BadArguments(String),
+    #[fail(display = "Checksum of the transferred file does not match.")]
+    ChecksumMismatch,
+    #[fail(
+        display = "Version conflict for key `{}` (current version: {}).",
+        key, current
+    )]
+    VersionConflict { key: String, current: u64 },
 }
 
 impl From<hole_punch::Error> for Error {
@@ -52,6 +90,12 @@ impl From<openssl::error::ErrorStack> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
 impl From<&'static str> for Error {
     fn from(err: &'static str) -> Error {
         Error::Custom(::failure::err_msg::<&'static str>(err))
@@ -3,15 +3,21 @@ extern crate bytes;
 extern crate failure;
 extern crate futures;
 extern crate glob;
+extern crate libc;
 extern crate hole_punch;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate tokio;
 #[macro_use]
 extern crate tokio_io;
 extern crate openssl;
+extern crate regex;
 extern crate tokio_file_unix;
+extern crate tokio_process;
+extern crate tokio_signal;
+extern crate tokio_threadpool;
 extern crate tokio_serde_json;
 #[macro_use]
 extern crate log;
@@ -19,15 +25,22 @@ extern crate log;
 #[macro_use]
 mod error;
 pub mod builtin_services;
+mod certificates;
 mod context;
+mod limits;
 mod peer;
 mod peer_builder;
 mod protocol;
+mod remote_peer;
 pub mod service;
 mod stream;
 pub mod util;
 
 pub use error::Error;
 pub use hole_punch::{FileFormat, PubKeyHash, SendFuture, Resolve};
+pub use limits::{ServiceMetrics, ServiceMetricsHandle};
 pub use peer::Peer;
+pub use peer_builder::PeerBuilder;
+pub use protocol::VersionRange;
+pub use remote_peer::RemotePeer;
 pub use stream::{NewStreamHandle, Stream, ProtocolStream};
@@ -0,0 +1,264 @@
+use context::PeerContext;
+use PubKeyHash;
+
+use std::{borrow::Borrow, collections::HashMap, hash::Hash, time::Instant};
+
+/// The limits for service instances that are started by remote peers.
+/// All limits are disabled by default.
+#[derive(Default)]
+pub(crate) struct ServiceLimits {
+    pub max_instances: Option<usize>,
+    pub max_instances_per_service: Option<usize>,
+    pub max_instances_per_peer: Option<usize>,
+    pub start_rate: Option<StartRate>,
+}
+
+/// The rate at which a remote peer may start service instances.
+#[derive(Clone, Copy)]
+pub(crate) struct StartRate {
+    pub per_second: u32,
+    /// The number of starts that may be done at once.
+    pub burst: u32,
+}
+
+/// A token bucket that limits the service starts of one remote peer.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: StartRate) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(rate.burst),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: StartRate) {
+        let elapsed = self.last_refill.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+
+        self.tokens =
+            (self.tokens + elapsed * f64::from(rate.per_second)).min(f64::from(rate.burst));
+        self.last_refill = Instant::now();
+    }
+
+    fn is_full(&self, rate: StartRate) -> bool {
+        self.tokens >= f64::from(rate.burst)
+    }
+
+    fn take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The reason why a service start was rejected.
+pub(crate) enum Rejection {
+    GlobalLimit,
+    ServiceLimit,
+    PeerLimit,
+    RateLimit,
+}
+
+impl Rejection {
+    pub fn message(&self, name: &str) -> String {
+        match self {
+            Rejection::GlobalLimit => "Too many running service instances.".into(),
+            Rejection::ServiceLimit => format!("Too many running instances of service `{}`.", name),
+            Rejection::PeerLimit => "Too many running service instances for this peer.".into(),
+            Rejection::RateLimit => "Too many service starts, try again later.".into(),
+        }
+    }
+}
+
+/// The running service instances that were started by remote peers.
+#[derive(Default)]
+struct RunningInstances {
+    total: usize,
+    per_service: HashMap<String, usize>,
+    per_peer: HashMap<PubKeyHash, usize>,
+}
+
+impl RunningInstances {
+    fn add(&mut self, name: &str, remote_peer: &PubKeyHash) {
+        self.total += 1;
+        *self.per_service.entry(name.to_owned()).or_insert(0) += 1;
+        *self.per_peer.entry(remote_peer.clone()).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, name: &str, remote_peer: &PubKeyHash) {
+        self.total = self.total.saturating_sub(1);
+        decrement(&mut self.per_service, name);
+        decrement(&mut self.per_peer, remote_peer);
+    }
+}
+
+/// Decrements the counter of the given key, the key is removed when it reaches zero.
+fn decrement<K, Q>(counters: &mut HashMap<K, usize>, key: &Q)
+where
+    K: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized,
+{
+    let remove = match counters.get_mut(key) {
+        Some(count) => {
+            *count -= 1;
+            *count == 0
+        }
+        None => false,
+    };
+
+    if remove {
+        counters.remove(key);
+    }
+}
+
+/// Enforces the `ServiceLimits` and counts the service starts.
+/// It tracks the running service instances that were started by remote peers, so a check does
+/// not need to look at all service instances.
+#[derive(Default)]
+pub(crate) struct Limiter {
+    pub limits: ServiceLimits,
+    buckets: HashMap<PubKeyHash, TokenBucket>,
+    running: RunningInstances,
+    metrics: ServiceMetrics,
+}
+
+impl Limiter {
+    /// Checks if the remote peer may start another instance of the given service.
+    /// If the start is allowed, the instance is counted as running until `instance_dropped` is
+    /// called.
+    pub fn check(&mut self, name: &str, remote_peer: &PubKeyHash) -> Result<(), Rejection> {
+        let res = self.check_limits(name, remote_peer);
+
+        match res {
+            Ok(()) => {
+                self.running.add(name, remote_peer);
+                self.metrics.started += 1;
+            }
+            Err(Rejection::GlobalLimit) => self.metrics.rejected_global_limit += 1,
+            Err(Rejection::ServiceLimit) => self.metrics.rejected_service_limit += 1,
+            Err(Rejection::PeerLimit) => self.metrics.rejected_peer_limit += 1,
+            Err(Rejection::RateLimit) => self.metrics.rejected_rate_limit += 1,
+        }
+
+        res
+    }
+
+    /// An instance of the given service that was started by the given remote peer was dropped.
+    pub fn instance_dropped(&mut self, name: &str, remote_peer: &PubKeyHash) {
+        self.running.remove(name, remote_peer);
+    }
+
+    /// The start of an instance of the given service that passed `check` was cancelled.
+    pub fn start_cancelled(&mut self, name: &str, remote_peer: &PubKeyHash) {
+        self.running.remove(name, remote_peer);
+        self.metrics.started -= 1;
+    }
+
+    fn check_limits(&mut self, name: &str, remote_peer: &PubKeyHash) -> Result<(), Rejection> {
+        let running = &self.running;
+
+        if self
+            .limits
+            .max_instances
+            .map_or(false, |max| running.total >= max)
+        {
+            return Err(Rejection::GlobalLimit);
+        }
+
+        if self.limits.max_instances_per_service.map_or(false, |max| {
+            running.per_service.get(name).map_or(false, |n| *n >= max)
+        }) {
+            return Err(Rejection::ServiceLimit);
+        }
+
+        if self.limits.max_instances_per_peer.map_or(false, |max| {
+            running
+                .per_peer
+                .get(remote_peer)
+                .map_or(false, |n| *n >= max)
+        }) {
+            return Err(Rejection::PeerLimit);
+        }
+
+        if let Some(rate) = self.limits.start_rate {
+            // Full buckets behave like new ones, so they are removed.
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(rate);
+                !bucket.is_full(rate)
+            });
+
+            let allowed = self
+                .buckets
+                .entry(remote_peer.clone())
+                .or_insert_with(|| TokenBucket::new(rate))
+                .take();
+
+            if !allowed {
+                return Err(Rejection::RateLimit);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> ServiceMetrics {
+        ServiceMetrics {
+            running_instances: self.running.total,
+            ..self.metrics.clone()
+        }
+    }
+}
+
+/// The service instances that were started by remote peers and the rejected starts.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServiceMetrics {
+    /// The number of running service instances.
+    pub running_instances: usize,
+    /// The number of started service instances.
+    pub started: u64,
+    /// The number of starts that were rejected, because of the maximum number of instances.
+    pub rejected_global_limit: u64,
+    /// The number of starts that were rejected, because of the maximum number of instances of
+    /// the requested service.
+    pub rejected_service_limit: u64,
+    /// The number of starts that were rejected, because of the maximum number of instances of
+    /// the remote peer.
+    pub rejected_peer_limit: u64,
+    /// The number of starts that were rejected, because of the start rate of the remote peer.
+    pub rejected_rate_limit: u64,
+}
+
+impl ServiceMetrics {
+    /// The number of all rejected starts.
+    pub fn rejected(&self) -> u64 {
+        self.rejected_global_limit
+            + self.rejected_service_limit
+            + self.rejected_peer_limit
+            + self.rejected_rate_limit
+    }
+}
+
+/// Returns the current `ServiceMetrics` of a `Peer`.
+/// The handle can be created before the `Peer` is build, for example to add the metrics as
+/// telemetry gauges.
+#[derive(Clone)]
+pub struct ServiceMetricsHandle {
+    peer_context: PeerContext,
+}
+
+impl ServiceMetricsHandle {
+    pub(crate) fn new(peer_context: PeerContext) -> ServiceMetricsHandle {
+        ServiceMetricsHandle { peer_context }
+    }
+
+    pub fn metrics(&self) -> ServiceMetrics {
+        self.peer_context.service_metrics()
+    }
+}
@@ -1,21 +1,28 @@
-use context::PeerContext;
+use context::{PeerContext, ServiceStartRequest};
 use error::*;
+use limits::ServiceMetrics;
 use peer_builder::PeerBuilder;
-use protocol::Protocol;
-use service::Client;
+use protocol::{self, Protocol};
+use remote_peer::RemotePeer;
+use service::{Client, ServiceDescriptor};
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-use hole_punch::{Context, CreateConnectionToPeerHandle, ProtocolStream, PubKeyHash, SendFuture};
+use hole_punch::{
+    self, Context, CreateConnectionToPeerHandle, ProtocolStream, PubKeyHash, SendFuture,
+};
+
+use serde_json;
 
 use futures::{
+    future::{self, Either},
     sync::oneshot,
     try_ready,
     Async::{NotReady, Ready},
     Future, Poll, Sink, Stream as FStream,
 };
 
-use tokio::runtime::TaskExecutor;
+use tokio::{runtime::TaskExecutor, timer::Timeout};
 
 struct HolePunchContextRunner {
     context: Context,
@@ -56,8 +63,10 @@ impl Future for HolePunchContextRunner {
                 }
             };
 
+            let remote_peer = stream.peer_identifier().clone();
+
             tokio::spawn(
-                build_incoming_stream_future(stream.into(), self.peer_context.clone())
+                build_incoming_stream_future(stream.into(), remote_peer, self.peer_context.clone())
                     .map_err(|e| error!("IncomingStream error: {:?}", e)),
             );
         }
@@ -115,39 +124,67 @@ impl Peer {
     where
         S::Error: From<Error>,
     {
-        let name = service.name();
-        let local_service_id = self.peer_context.next_service_id();
-        let mut peer_context = self.peer_context.clone();
+        let stream = self
+            .create_connection_to_peer_handle
+            .create_connection_to_peer(peer)
+            .map_err(Error::from);
+
+        start_service(
+            stream,
+            protocol::PROTOCOL_VERSION.max,
+            service,
+            self.peer_context.clone(),
+        )
+    }
+
+    /// Connect to the given `Peer` and request the services it offers.
+    pub fn list_services(
+        &mut self,
+        peer: PubKeyHash,
+    ) -> impl SendFuture<Item = Vec<ServiceDescriptor>, Error = Error> {
+        request_service_list(
+            self.create_connection_to_peer_handle
+                .create_connection_to_peer(peer)
+                .map_err(Error::from),
+            protocol::PROTOCOL_VERSION.max,
+        )
+    }
+
+    /// Connect to the given `Peer` and keep the connection open.
+    /// The returned `RemotePeer` starts services over the connection, without connecting again.
+    pub fn connect(
+        &mut self,
+        peer: PubKeyHash,
+    ) -> impl SendFuture<Item = RemotePeer, Error = Error> {
+        let peer_context = self.peer_context.clone();
 
         self.create_connection_to_peer_handle
-            .create_connection_to_peer(peer)
+            .create_connection_to_peer(peer.clone())
             .map_err(Error::from)
-            .and_then(move |stream| {
-                let stream: ProtocolStream<Protocol> = stream.into();
-                stream
-                    .send(Protocol::RequestServiceStart {
-                        name: name.into(),
-                        local_id: local_service_id,
-                    })
-                    .and_then(|s| s.into_future().map_err(|e| e.0))
-                    .map_err(Into::into)
-            })
-            .and_then(move |(msg, stream)| match msg {
-                None => bail!("Stream closed while requesting service!"),
-                Some(Protocol::ServiceStarted { id }) => Ok((id, stream)),
-                Some(Protocol::ServiceNotFound) => bail!("Requested service({}) not found!", name),
-                _ => bail!("Received not expected message!"),
-            })
-            .map_err(Into::into)
-            .and_then(move |(id, stream)| {
-                peer_context.start_client_service_instance(
-                    service,
-                    local_service_id,
-                    id,
-                    stream.into(),
-                )
-            })
-            .flatten()
+            .and_then(move |stream| RemotePeer::new(stream, peer, peer_context))
+    }
+
+    /// Shut down the `Peer`.
+    /// New service instances are rejected and the running instances are notified by the
+    /// `ShutdownSignal` of their `Streams`. After all instances finished, or the timeout elapsed,
+    /// the `Peer` is dropped and its connections are closed.
+    pub fn shutdown(mut self, timeout: Duration) -> impl SendFuture<Item = (), Error = Error> {
+        let drained = match self.peer_context.shutdown() {
+            Some(drained) => Either::A(Timeout::new(drained, timeout).then(|res| {
+                if res.is_err() {
+                    warn!("Service instances did not finish before the shutdown timeout");
+                }
+                Ok(())
+            })),
+            None => Either::B(future::ok(())),
+        };
+
+        drained.map(move |_| drop(self))
+    }
+
+    /// Returns the current `ServiceMetrics`.
+    pub fn service_metrics(&self) -> ServiceMetrics {
+        self.peer_context.service_metrics()
     }
 
     /// The local address of the Quic backend.
@@ -169,23 +206,164 @@ impl Future for Peer {
     }
 }
 
+/// Starts the given service on the given `Stream` to the remote `Peer`.
+/// `version` is the protocol version of the connection, if it is already known, otherwise the
+/// highest supported version.
+pub(crate) fn start_service<S, F>(
+    stream: F,
+    version: u32,
+    service: S,
+    mut peer_context: PeerContext,
+) -> impl SendFuture<Item = <S::Future as Future>::Item, Error = S::Error>
+where
+    S: Client,
+    S::Error: From<Error>,
+    F: SendFuture<Item = hole_punch::Stream, Error = Error>,
+{
+    let name = service.name();
+    let args = serde_json::to_value(service.args());
+    let local_service_id = peer_context.next_service_id();
+    let certificate_chain = peer_context.local_certificate_chain();
+
+    stream
+        .and_then(move |stream| protocol::open::<ProtocolStream<Protocol>>(stream.into(), version))
+        .and_then(move |(stream, handshake)| {
+            Ok((stream, handshake.version, args?, local_service_id?))
+        })
+        .and_then(move |(stream, version, args, local_service_id)| {
+            stream
+                .send(Protocol::RequestServiceStart {
+                    name: name.into(),
+                    local_id: local_service_id,
+                    args,
+                    certificate_chain,
+                })
+                .and_then(|s| s.into_future().map_err(|e| e.0))
+                .map(move |(msg, stream)| (msg, stream, version, local_service_id))
+                .map_err(Into::into)
+        })
+        .and_then(move |(msg, stream, version, local_service_id)| match msg {
+            None => bail!("Stream closed while requesting service!"),
+            Some(Protocol::ServiceStarted {
+                id,
+                certificate_chain,
+            }) => Ok((id, certificate_chain, stream, version, local_service_id)),
+            Some(Protocol::Error { code, message }) => Err(code.into_error(message)),
+            Some(Protocol::ServiceNotFound) => {
+                Err(Error::NotFound(format!("Service `{}` not found.", name)))
+            }
+            _ => bail!("Received not expected message!"),
+        })
+        .map_err(Into::into)
+        .and_then(
+            move |(id, certificate_chain, stream, version, local_service_id)| {
+                peer_context.start_client_service_instance(
+                    service,
+                    local_service_id,
+                    id,
+                    certificate_chain,
+                    stream.into(),
+                    version,
+                )
+            },
+        )
+        .flatten()
+}
+
+/// Requests the services that are offered by the remote `Peer` on the given `Stream`.
+/// `version` is the protocol version of the connection, like for `start_service`.
+pub(crate) fn request_service_list<F>(
+    stream: F,
+    version: u32,
+) -> impl SendFuture<Item = Vec<ServiceDescriptor>, Error = Error>
+where
+    F: SendFuture<Item = hole_punch::Stream, Error = Error>,
+{
+    stream
+        .and_then(move |stream| protocol::open::<ProtocolStream<Protocol>>(stream.into(), version))
+        .and_then(|(stream, handshake)| {
+            if handshake
+                .features
+                .iter()
+                .any(|f| f == protocol::FEATURE_LIST_SERVICES)
+            {
+                Ok(stream)
+            } else {
+                Err(Error::FeatureNotSupported(protocol::FEATURE_LIST_SERVICES))
+            }
+        })
+        .and_then(|stream| {
+            stream
+                .send(Protocol::ListServices)
+                .and_then(|s| s.into_future().map_err(|e| e.0))
+                .map_err(Into::into)
+        })
+        .and_then(|(msg, _)| match msg {
+            None => bail!("Stream closed while listing services!"),
+            Some(Protocol::ServiceList { services }) => Ok(services),
+            _ => bail!("Received not expected message!"),
+        })
+}
+
 fn build_incoming_stream_future(
     stream: ProtocolStream<Protocol>,
+    remote_peer: PubKeyHash,
     mut context: PeerContext,
 ) -> impl SendFuture<Item = (), Error = Error> {
-    stream
-        .into_future()
-        .map_err(|e| e.0.into())
-        .and_then(move |(msg, stream)| match msg {
-            None => Ok(()),
+    protocol::accept_hello(stream)
+        .and_then(|(stream, handshake, request)| {
+            let version = handshake.version;
+
+            match request {
+                // Version 1 peers start directly with their request.
+                Some(request) => Either::A(future::ok((Some(request), stream, version))),
+                None => Either::B(
+                    stream
+                        .into_future()
+                        .map_err(|e| e.0.into())
+                        .map(move |(msg, stream)| (msg, stream, version)),
+                ),
+            }
+        })
+        .and_then(move |(msg, stream, version)| match msg {
+            None => Either::A(future::ok(())),
             Some(Protocol::ConnectToService { id }) => {
-                context.connect_stream_to_service_instance(stream, id);
-                Ok(())
+                context.connect_stream_to_service_instance(stream, id, &remote_peer, version);
+                Either::A(future::ok(()))
             }
-            Some(Protocol::RequestServiceStart { name, local_id }) => {
-                context.start_server_service_instance(&name, local_id, stream);
-                Ok(())
+            Some(Protocol::RequestServiceStart {
+                name,
+                local_id,
+                args,
+                certificate_chain,
+            }) => {
+                let request = ServiceStartRequest {
+                    name,
+                    remote_service_id: local_id,
+                    args,
+                    certificate_chain,
+                };
+                context.start_server_service_instance(request, &remote_peer, stream, version);
+                Either::A(future::ok(()))
+            }
+            Some(Protocol::ListServices) => Either::B(Either::A(
+                stream
+                    .send(Protocol::ServiceList {
+                        services: context.list_services(&remote_peer),
+                    })
+                    .map(|_| ())
+                    .map_err(Into::into),
+            )),
+            Some(Protocol::Ping { id }) => Either::B(Either::B(protocol::answer_pings(stream, id))),
+            Some(Protocol::Keepalive { id }) => {
+                context.answer_keepalive(stream, id, &remote_peer, version);
+                Either::A(future::ok(()))
+            }
+            Some(Protocol::CloseServiceInstance { id, reason }) => {
+                debug!("Remote peer closed service instance {}: {}", id, reason);
+                context.close_service_instance(id, &remote_peer, reason);
+                Either::A(future::ok(()))
             }
-            _ => bail!("Unexpected message at incoming Stream."),
+            _ => Either::A(future::err("Unexpected message at incoming Stream.".into())),
         })
 }
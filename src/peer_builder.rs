@@ -1,17 +1,23 @@
+use certificates::{self, Certificates};
 use context::PeerContext;
 use error::*;
+use limits::{ServiceMetricsHandle, StartRate};
 use peer::Peer;
-use service::Server;
+use service::{Server, ServiceAcl};
 
 use std::{
     fs::File,
     io::Read,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use hole_punch::{Config, ConfigBuilder, Context, FileFormat, PubKeyHash, Resolve};
 
-use openssl::pkey::{PKey, Private};
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
 
 use tokio::runtime::TaskExecutor;
 
@@ -21,6 +27,9 @@ pub struct PeerBuilder {
     peer_context: PeerContext,
     private_key: Option<(FileFormat, Vec<u8>)>,
     private_key_file: Option<PathBuf>,
+    certificate_chain: Option<(FileFormat, Vec<Vec<u8>>)>,
+    certificate_chain_file: Option<PathBuf>,
+    ca_cert_files: Vec<PathBuf>,
 }
 
 impl PeerBuilder {
@@ -34,6 +43,9 @@ impl PeerBuilder {
             peer_context,
             private_key: None,
             private_key_file: None,
+            certificate_chain: None,
+            certificate_chain_file: None,
+            ca_cert_files: Vec::new(),
         }
     }
 
@@ -44,7 +56,10 @@ impl PeerBuilder {
     }
 
     /// Set the TLS certificate chain filename.
+    /// The chain needs to be in `PEM` format.
     pub fn set_certificate_chain_file<C: Into<PathBuf>>(mut self, path: C) -> Self {
+        let path = path.into();
+        self.certificate_chain_file = Some(path.clone());
         self.config = self.config.set_certificate_chain_filename(path);
         self
     }
@@ -61,6 +76,7 @@ impl PeerBuilder {
     /// Set the TLS certificate chain for this peer from memory.
     /// This will overwrite any prior call to `set_cert_chain_filename`.
     pub fn set_certificate_chain(mut self, chain: Vec<Vec<u8>>, format: FileFormat) -> Self {
+        self.certificate_chain = Some((format, chain.clone()));
         self.config = self.config.set_certificate_chain(chain, format);
         self
     }
@@ -74,15 +90,93 @@ impl PeerBuilder {
     }
 
     /// Register the given service at this peer.
-    pub fn register_service<S: Server + 'static>(mut self, service: S) -> Self {
-        self.peer_context.register_service(service);
+    /// Every remote peer is allowed to start the service.
+    pub fn register_service<S: Server + 'static>(self, service: S) -> Self {
+        self.register_service_with_acl(service, ServiceAcl::allow_all())
+    }
+
+    /// Register the given service at this peer.
+    /// Only the remote peers that are allowed by the given `ServiceAcl` can start the service.
+    pub fn register_service_with_acl<S: Server + 'static>(
+        mut self,
+        service: S,
+        acl: ServiceAcl,
+    ) -> Self {
+        self.peer_context.register_service(service, acl);
+        self
+    }
+
+    /// Set the maximum number of open `Stream`s of one service instance (default 32).
+    /// Further `Stream`s that the remote peer creates for the instance are rejected as busy.
+    pub fn set_max_streams_per_service_instance(mut self, max: usize) -> Self {
+        self.peer_context.set_max_streams_per_service_instance(max);
+        self
+    }
+
+    /// Set the maximum number of running service instances that are started by remote peers.
+    /// Further starts are rejected as busy. An instance is running, until its last `Stream` is
+    /// closed.
+    pub fn set_max_service_instances(mut self, max: usize) -> Self {
+        self.peer_context
+            .set_service_limits(|l| l.max_instances = Some(max));
+        self
+    }
+
+    /// Set the maximum number of running instances of one service.
+    /// Further starts of the service are rejected as busy.
+    pub fn set_max_service_instances_per_service(mut self, max: usize) -> Self {
+        self.peer_context
+            .set_service_limits(|l| l.max_instances_per_service = Some(max));
+        self
+    }
+
+    /// Set the maximum number of running service instances that one remote peer started.
+    /// Further starts by this peer are rejected as busy.
+    pub fn set_max_service_instances_per_peer(mut self, max: usize) -> Self {
+        self.peer_context
+            .set_service_limits(|l| l.max_instances_per_peer = Some(max));
+        self
+    }
+
+    /// Set the rate at which one remote peer may start service instances.
+    /// A remote peer may start `burst` instances at once, afterwards `per_second` instances per
+    /// second. Further starts are rejected as busy.
+    pub fn set_service_start_rate(mut self, per_second: u32, burst: u32) -> Self {
+        self.peer_context
+            .set_service_limits(|l| l.start_rate = Some(StartRate { per_second, burst }));
+        self
+    }
+
+    /// Returns a handle to the `ServiceMetrics` of the `Peer` that will be build.
+    pub fn service_metrics_handle(&self) -> ServiceMetricsHandle {
+        ServiceMetricsHandle::new(self.peer_context.clone())
+    }
+
+    /// Set the idle timeout of the service with the given name.
+    /// An instance of the service is closed, when no data was send or received over its `Stream`s
+    /// for the given duration. Both sides of the instance fail with `Error::ServiceClosed`.
+    pub fn set_service_idle_timeout<N: Into<String>>(mut self, name: N, timeout: Duration) -> Self {
+        self.peer_context
+            .set_service_timeouts(name.into(), |t| t.idle = Some(timeout));
+        self
+    }
+
+    /// Enable keepalives for the service with the given name.
+    /// Every instance of the service checks in the given interval that the other side of the
+    /// instance is still running. If the check fails, the instance is closed.
+    pub fn set_service_keepalive<N: Into<String>>(mut self, name: N, interval: Duration) -> Self {
+        self.peer_context
+            .set_service_timeouts(name.into(), |t| t.keepalive = Some(interval));
         self
     }
 
     /// Set the incoming CA certificate files.
     /// These CAs will be used to authenticate incoming connections.
     /// When these CAs are not given, all incoming connections will be authenticated successfully.
+    /// The CAs are also used to verify the certificate chains of remote peers that are given to
+    /// the services in the `ServiceContext`.
     pub fn set_client_ca_cert_files(mut self, files: Vec<PathBuf>) -> Self {
+        self.ca_cert_files.extend(files.iter().cloned());
         self.config = self.config.set_incoming_ca_certificates(files);
         self
     }
@@ -90,7 +184,10 @@ impl PeerBuilder {
     /// Set the outgoing CA certificate files.
     /// These CAs will be used to authenticate outgoing connections.
     /// When these CAs are not given, all outgoing connections will be trusted.
+    /// The CAs are also used to verify the certificate chains of remote peers that are given to
+    /// the services in the `ServiceContext`.
     pub fn set_server_ca_cert_files(mut self, files: Vec<PathBuf>) -> Self {
+        self.ca_cert_files.extend(files.iter().cloned());
         self.config = self.config.set_outgoing_ca_certificates(files);
         self
     }
@@ -116,8 +213,10 @@ impl PeerBuilder {
     }
 
     /// Builds the `Peer` instance.
-    pub fn build(self) -> Result<Peer> {
+    pub fn build(mut self) -> Result<Peer> {
         let private_key = self.load_private_key()?;
+        let certificates = Certificates::new(self.load_certificate_chain()?, &self.ca_cert_files)?;
+        self.peer_context.set_certificates(certificates);
 
         let config = self.config.enable_mdns("carrier");
         let context = Context::new(
@@ -138,6 +237,16 @@ impl PeerBuilder {
         }
     }
 
+    fn load_certificate_chain(&self) -> Result<Vec<X509>> {
+        if let Some((format, ref chain)) = self.certificate_chain {
+            certificates::load_certificates(chain, format)
+        } else if let Some(ref path) = self.certificate_chain_file {
+            certificates::load_certificates_from_file(path)
+        } else {
+            bail!("No certificate chain given!")
+        }
+    }
+
     fn load_private_key_from_memory(
         &self,
         format: FileFormat,
@@ -1,21 +1,478 @@
-use service::ServiceId;
+use error::*;
+use service::{CloseReason, ServiceDescriptor, ServiceId};
+
+use hole_punch::{ProtocolStream, SendFuture};
+
+use futures::{
+    future::{self, Either},
+    Future, Sink, Stream,
+};
+
+use serde_json::Value;
+
+use std::{cmp, fmt};
+
+/// The range of carrier protocol versions that this build speaks.
+pub const PROTOCOL_VERSION: VersionRange = VersionRange { min: 1, max: 2 };
+
+/// The version of peers that do not send a `Hello`.
+pub const LEGACY_VERSION: u32 = 1;
+
+/// The version range of peers that do not send a `Hello`.
+const LEGACY_VERSION_RANGE: VersionRange = VersionRange {
+    min: LEGACY_VERSION,
+    max: LEGACY_VERSION,
+};
+
+/// The first version that refuses requests with `Error`, version 1 only knows `ServiceNotFound`.
+pub const ERROR_CODES_VERSION: u32 = 2;
+
+/// The remote peer answers `ListServices`.
+pub const FEATURE_LIST_SERVICES: &str = "list_services";
+
+/// The remote peer answers `Ping`.
+pub const FEATURE_PING: &str = "ping";
+
+/// The remote peer answers `Keepalive` and handles `CloseServiceInstance`.
+pub const FEATURE_SERVICE_TIMEOUTS: &str = "service_timeouts";
+
+/// The optional protocol features that this build supports.
+pub const FEATURES: &[&str] = &[
+    FEATURE_LIST_SERVICES,
+    FEATURE_PING,
+    FEATURE_SERVICE_TIMEOUTS,
+];
+
+/// An inclusive range of carrier protocol versions.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// Returns the highest version that is supported by both ranges.
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u32> {
+        let version = cmp::min(self.max, other.max);
+
+        if version >= cmp::max(self.min, other.min) {
+            Some(version)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// The result of the handshake on a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// The version that is spoken on the stream.
+    pub version: u32,
+    /// The features that are supported by both sides.
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    fn legacy() -> Handshake {
+        Handshake {
+            version: LEGACY_VERSION,
+            features: Vec::new(),
+        }
+    }
+}
+
+/// The reason why a request was refused by the remote peer.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The requested service or service instance does not exist.
+    NotFound,
+    /// The remote peer is not allowed to use the requested service.
+    Unauthorized,
+    /// The peer can currently not handle the request.
+    Busy,
+    /// The service rejected the given arguments.
+    BadArguments,
+}
+
+impl ErrorCode {
+    /// Converts the error that was received from the remote peer into an `Error`.
+    pub fn into_error(self, message: String) -> Error {
+        match self {
+            ErrorCode::NotFound => Error::NotFound(message),
+            ErrorCode::Unauthorized => Error::Unauthorized(message),
+            ErrorCode::Busy => Error::Busy(message),
+            ErrorCode::BadArguments => Error::BadArguments(message),
+        }
+    }
+}
 
 /// The carrier protocol that is used to communicate between the peers.
 #[derive(Deserialize, Serialize, Clone)]
 pub enum Protocol {
+    /// The first message on every stream, send by both sides. The side that opened the stream
+    /// sends its `Hello` first and the remote side answers with its own `Hello`. If the version
+    /// ranges do not overlap, the stream is closed.
+    Hello {
+        version: VersionRange,
+        features: Vec<String>,
+    },
     /// Request to start a the given service on the peer.
     /// If the service is available on the peer, a `ServiceStarted` will be send. The stream is
-    /// afterwards only usable by the service. If the service is not available, a `ServiceNotFound`
-    /// will be send.
-    RequestServiceStart { name: String, local_id: ServiceId },
-    /// The requested service could not be found on the peer.
+    /// afterwards only usable by the service. If the service can not be started, an `Error` will
+    /// be send.
+    RequestServiceStart {
+        name: String,
+        local_id: ServiceId,
+        #[serde(default)]
+        args: Value,
+        /// The certificate chain of the requesting peer in `PEM` format.
+        #[serde(default)]
+        certificate_chain: Vec<String>,
+    },
+    /// The request was refused by the peer. Version 1 peers receive `ServiceNotFound` instead.
+    Error { code: ErrorCode, message: String },
+    /// The request was refused by a peer that speaks version 1.
     ServiceNotFound,
     /// The requested Service was started on the peer with the given id.
-    ServiceStarted { id: ServiceId },
-    /// Connect a stream to the given service instance. Will response with `ServiceNotFound`, when
+    ServiceStarted {
+        id: ServiceId,
+        /// The certificate chain of the peer in `PEM` format.
+        #[serde(default)]
+        certificate_chain: Vec<String>,
+    },
+    /// Connect a stream to the given service instance. Will response with an `Error`, when
     /// a service with the given id is not available or with `ServiceConnected` when the given
-    /// service instance could be found.
+    /// service instance could be found. Service instances can only be accessed by the remote peer
+    /// they were created with, other peers receive the same `Error` as for unknown ids.
     ConnectToService { id: ServiceId },
     /// The stream could be connected to the given service.
     ServiceConnected,
+    /// Request the services that are offered by the peer. Will be answered with `ServiceList`.
+    /// Requires the `list_services` feature.
+    ListServices,
+    /// The services that are offered by the peer.
+    ServiceList { services: Vec<ServiceDescriptor> },
+    /// Check that the connection is alive. Will be answered with a `Pong` with the same id.
+    /// The stream is afterwards only used for `Ping`s and `Pong`s.
+    /// Requires the `ping` feature.
+    Ping { id: u64 },
+    /// The answer to the `Ping` with the given id.
+    Pong { id: u64 },
+    /// Check that the given service instance is still running. Will be answered with
+    /// `KeepaliveAck` or with an `Error`, when the service instance could not be found.
+    /// Requires the `service_timeouts` feature.
+    Keepalive { id: ServiceId },
+    /// The service instance of the `Keepalive` is running.
+    KeepaliveAck,
+    /// The other side closed the given service instance for the given reason.
+    /// Requires the `service_timeouts` feature.
+    CloseServiceInstance { id: ServiceId, reason: CloseReason },
+}
+
+impl Protocol {
+    pub fn error<T: Into<String>>(code: ErrorCode, message: T) -> Protocol {
+        Protocol::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Converts the message for a remote peer that speaks the given version.
+    pub fn for_version(self, version: u32) -> Protocol {
+        match self {
+            Protocol::Error { .. } if version < ERROR_CODES_VERSION => Protocol::ServiceNotFound,
+            msg => msg,
+        }
+    }
+
+    fn hello() -> Protocol {
+        Protocol::Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// Checks the `Hello` of the remote side.
+fn check_hello(version: VersionRange, features: Vec<String>) -> Result<Handshake> {
+    match PROTOCOL_VERSION.negotiate(&version) {
+        Some(negotiated) => {
+            trace!("Negotiated protocol version {}", negotiated);
+            Ok(Handshake {
+                version: negotiated,
+                features: features
+                    .into_iter()
+                    .filter(|f| FEATURES.contains(&f.as_str()))
+                    .collect(),
+            })
+        }
+        None => Err(Error::IncompatibleProtocol {
+            local: PROTOCOL_VERSION,
+            remote: version,
+        }),
+    }
+}
+
+/// Performs the handshake on a stream that was opened by the local side.
+/// A remote peer that closes the stream or does not answer with a `Hello`, does not know the
+/// handshake and speaks version 1.
+pub fn hello<S>(stream: S) -> impl SendFuture<Item = (S, Handshake), Error = Error>
+where
+    S: Stream<Item = Protocol> + Sink<SinkItem = Protocol> + Send,
+    Error: From<S::Error> + From<S::SinkError>,
+{
+    stream
+        .send(Protocol::hello())
+        .map_err(Error::from)
+        .and_then(|s| s.into_future().map_err(|e| Error::from(e.0)))
+        .and_then(|(msg, stream)| match msg {
+            Some(Protocol::Hello { version, features }) => {
+                check_hello(version, features).map(|handshake| (stream, handshake))
+            }
+            _ => Err(Error::IncompatibleProtocol {
+                local: PROTOCOL_VERSION,
+                remote: LEGACY_VERSION_RANGE,
+            }),
+        })
+}
+
+/// Opens a stream to a remote peer that speaks the given version, that was negotiated on an
+/// earlier stream of the same connection. Version 1 peers do not know the handshake, they do not
+/// receive a `Hello`.
+pub fn open<S>(stream: S, version: u32) -> impl SendFuture<Item = (S, Handshake), Error = Error>
+where
+    S: Stream<Item = Protocol> + Sink<SinkItem = Protocol> + Send,
+    Error: From<S::Error> + From<S::SinkError>,
+{
+    if version > LEGACY_VERSION {
+        Either::A(hello(stream))
+    } else {
+        Either::B(future::ok((stream, Handshake::legacy())))
+    }
+}
+
+/// Performs the handshake on a stream that was opened by the remote side.
+/// Version 1 peers do not send a `Hello` and start with their request, it is returned as third
+/// value, because it was already read from the stream.
+pub fn accept_hello<S>(
+    stream: S,
+) -> impl SendFuture<Item = (S, Handshake, Option<Protocol>), Error = Error>
+where
+    S: Stream<Item = Protocol> + Sink<SinkItem = Protocol> + Send,
+    Error: From<S::Error> + From<S::SinkError>,
+{
+    stream
+        .into_future()
+        .map_err(|e| Error::from(e.0))
+        .and_then(|(msg, stream)| match msg {
+            None => Either::A(future::err(
+                "Stream closed while waiting for `Hello`!".into(),
+            )),
+            Some(Protocol::Hello { version, features }) => Either::B(
+                // Always answer, so the remote side can report the incompatibility as well.
+                stream
+                    .send(Protocol::hello())
+                    .map_err(Error::from)
+                    .and_then(move |stream| {
+                        check_hello(version, features).map(|handshake| (stream, handshake, None))
+                    }),
+            ),
+            Some(msg @ Protocol::RequestServiceStart { .. })
+            | Some(msg @ Protocol::ConnectToService { .. }) => {
+                trace!(
+                    "Remote peer speaks legacy protocol version {}",
+                    LEGACY_VERSION
+                );
+                Either::A(future::ok((stream, Handshake::legacy(), Some(msg))))
+            }
+            _ => Either::A(future::err("Expected `Hello` as first message!".into())),
+        })
+}
+
+/// Answers the `Ping`s on a stream that was opened by the remote side, until it is closed.
+/// `id` is the id of the first `Ping`, that was already received.
+pub fn answer_pings(
+    stream: ProtocolStream<Protocol>,
+    id: u64,
+) -> impl SendFuture<Item = (), Error = Error> {
+    stream
+        .send(Protocol::Pong { id })
+        .map_err(Error::from)
+        .and_then(|stream| {
+            let (sink, stream) = stream.split();
+
+            stream
+                .map_err(Error::from)
+                .and_then(|msg| match msg {
+                    Protocol::Ping { id } => Ok(Protocol::Pong { id }),
+                    _ => bail!("Expected `Ping` on ping stream!"),
+                })
+                .forward(sink.sink_map_err(Error::from))
+                .map(|_| ())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{
+        sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        Poll, StartSend,
+    };
+
+    use std::io;
+
+    /// One side of an in-memory stream.
+    struct TestStream {
+        sender: UnboundedSender<Protocol>,
+        receiver: UnboundedReceiver<Protocol>,
+    }
+
+    impl Stream for TestStream {
+        type Item = Protocol;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Protocol>, io::Error> {
+            self.receiver
+                .poll()
+                .map_err(|_| io::ErrorKind::Other.into())
+        }
+    }
+
+    impl Sink for TestStream {
+        type SinkItem = Protocol;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Protocol) -> StartSend<Protocol, io::Error> {
+            self.sender
+                .start_send(item)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.sender
+                .poll_complete()
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    /// Returns both sides of an in-memory stream.
+    fn test_streams() -> (TestStream, TestStream) {
+        let (local_sender, remote_receiver) = unbounded();
+        let (remote_sender, local_receiver) = unbounded();
+
+        (
+            TestStream {
+                sender: local_sender,
+                receiver: local_receiver,
+            },
+            TestStream {
+                sender: remote_sender,
+                receiver: remote_receiver,
+            },
+        )
+    }
+
+    fn service_start_request() -> Protocol {
+        Protocol::RequestServiceStart {
+            name: "testservice".into(),
+            local_id: 1,
+            args: Value::Null,
+            certificate_chain: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version() {
+        let local = VersionRange { min: 1, max: 3 };
+
+        assert_eq!(Some(3), local.negotiate(&VersionRange { min: 2, max: 4 }));
+        assert_eq!(Some(2), local.negotiate(&VersionRange { min: 2, max: 2 }));
+        assert_eq!(Some(3), local.negotiate(&VersionRange { min: 3, max: 5 }));
+        assert_eq!(Some(3), local.negotiate(&local));
+        assert_eq!(Some(1), local.negotiate(&VersionRange { min: 1, max: 1 }));
+    }
+
+    #[test]
+    fn negotiate_fails_for_disjoint_ranges() {
+        let local = VersionRange { min: 2, max: 3 };
+
+        assert_eq!(None, local.negotiate(&VersionRange { min: 1, max: 1 }));
+        assert_eq!(None, local.negotiate(&VersionRange { min: 4, max: 5 }));
+    }
+
+    #[test]
+    fn hello_negotiates_version_and_features() {
+        let (local, remote) = test_streams();
+
+        let ((_, local), (_, remote, request)) =
+            hello(local).join(accept_hello(remote)).wait().unwrap();
+
+        assert_eq!(PROTOCOL_VERSION.max, local.version);
+        assert_eq!(local, remote);
+        assert_eq!(FEATURES.len(), local.features.len());
+        assert!(request.is_none());
+    }
+
+    #[test]
+    fn hello_fails_with_incompatible_protocol_without_answer() {
+        let (local, remote) = test_streams();
+
+        // Like a version 1 peer, the remote side closes the stream on the unknown `Hello`.
+        let close = remote
+            .into_future()
+            .map(|(_, remote)| drop(remote))
+            .map_err(|e| Error::from(e.0));
+
+        match hello(local).join(close).wait() {
+            Err(Error::IncompatibleProtocol { local, remote }) => {
+                assert_eq!(PROTOCOL_VERSION, local);
+                assert_eq!(LEGACY_VERSION_RANGE, remote);
+            }
+            _ => panic!("Handshake did not fail with `IncompatibleProtocol`"),
+        }
+    }
+
+    #[test]
+    fn accept_hello_returns_legacy_request() {
+        let (local, remote) = test_streams();
+
+        let (local, handshake) = open(local, LEGACY_VERSION).wait().unwrap();
+        assert_eq!(Handshake::legacy(), handshake);
+        let _local = local.send(service_start_request()).wait().unwrap();
+
+        let (_, handshake, request) = accept_hello(remote).wait().unwrap();
+        assert_eq!(Handshake::legacy(), handshake);
+        match request {
+            Some(Protocol::RequestServiceStart { name, .. }) => assert_eq!("testservice", name),
+            _ => panic!("Legacy request was not returned"),
+        }
+    }
+
+    #[test]
+    fn legacy_peers_receive_service_not_found() {
+        match Protocol::error(ErrorCode::Busy, "busy").for_version(LEGACY_VERSION) {
+            Protocol::ServiceNotFound => {}
+            _ => panic!("Error was not converted for version 1"),
+        }
+
+        match Protocol::error(ErrorCode::Busy, "busy").for_version(ERROR_CODES_VERSION) {
+            Protocol::Error {
+                code: ErrorCode::Busy,
+                ..
+            } => {}
+            _ => panic!("Error was converted for version 2"),
+        }
+    }
 }
@@ -0,0 +1,190 @@
+use context::PeerContext;
+use error::*;
+use peer::{request_service_list, start_service};
+use protocol::{self, Protocol};
+use service::{Client, ServiceDescriptor};
+use PubKeyHash;
+
+use hole_punch::{self, SendFuture};
+
+use tokio::{self, timer::Interval};
+
+use futures::{sync::oneshot, Future, Sink, Stream as FStream};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The interval between two `Ping`s.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The number of `Ping` intervals without a `Pong`, after that the connection is not alive
+/// anymore.
+const MAX_MISSED_PONGS: u32 = 3;
+
+/// The state of the ping `Stream`.
+struct Liveness {
+    next_id: u64,
+    /// The id of the last `Ping` and when it was send.
+    last_ping: Option<(u64, Instant)>,
+    last_pong: Instant,
+    rtt: Option<Duration>,
+    closed: bool,
+}
+
+impl Liveness {
+    fn ping(&mut self) -> Protocol {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.last_ping = Some((id, Instant::now()));
+        Protocol::Ping { id }
+    }
+
+    fn pong(&mut self, id: u64) {
+        // Late `Pong`s still show that the connection is alive, but would falsify the rtt.
+        if let Some((ping_id, send)) = self.last_ping {
+            if ping_id == id {
+                self.rtt = Some(send.elapsed());
+            }
+        }
+
+        self.last_pong = Instant::now();
+    }
+}
+
+/// A connection to a remote `Peer`, created by `Peer::connect`.
+///
+/// All services are started over the same connection. The connection is checked with `Ping`s
+/// on its first `Stream`, that is closed when the `RemotePeer` is dropped. Services that are still
+/// running keep their `Stream`s.
+pub struct RemotePeer {
+    peer: PubKeyHash,
+    /// The protocol version that was negotiated on the first `Stream` of the connection.
+    version: u32,
+    peer_context: PeerContext,
+    new_stream_handle: hole_punch::NewStreamHandle,
+    liveness: Arc<Mutex<Liveness>>,
+    /// Stops the `Ping`s, when dropped.
+    _close: oneshot::Sender<()>,
+}
+
+impl RemotePeer {
+    pub(crate) fn new(
+        stream: hole_punch::Stream,
+        peer: PubKeyHash,
+        peer_context: PeerContext,
+    ) -> impl SendFuture<Item = RemotePeer, Error = Error> {
+        let new_stream_handle = stream.new_stream_handle().clone();
+        let stream: hole_punch::ProtocolStream<Protocol> = stream.into();
+
+        protocol::hello(stream).and_then(move |(stream, handshake)| {
+            if !handshake
+                .features
+                .iter()
+                .any(|f| f == protocol::FEATURE_PING)
+            {
+                return Err(Error::FeatureNotSupported(protocol::FEATURE_PING));
+            }
+
+            let liveness = Arc::new(Mutex::new(Liveness {
+                next_id: 0,
+                last_ping: None,
+                last_pong: Instant::now(),
+                rtt: None,
+                closed: false,
+            }));
+            let (close, closed) = oneshot::channel();
+            spawn_pings(stream, liveness.clone(), closed);
+
+            Ok(RemotePeer {
+                peer,
+                version: handshake.version,
+                peer_context,
+                new_stream_handle,
+                liveness,
+                _close: close,
+            })
+        })
+    }
+
+    /// The identifier of the remote `Peer`.
+    pub fn peer(&self) -> &PubKeyHash {
+        &self.peer
+    }
+
+    /// Returns if the connection is alive, the remote `Peer` answered one of the last `Ping`s.
+    pub fn is_alive(&self) -> bool {
+        let liveness = self.liveness.lock().unwrap();
+        !liveness.closed && liveness.last_pong.elapsed() < PING_INTERVAL * MAX_MISSED_PONGS
+    }
+
+    /// The round trip time of the last answered `Ping`, `None` if no `Ping` was answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.liveness.lock().unwrap().rtt
+    }
+
+    /// Run the given `Service` (locally and remotely) over this connection.
+    pub fn run_service<S: Client>(
+        &mut self,
+        service: S,
+    ) -> impl SendFuture<Item = <S::Future as Future>::Item, Error = S::Error>
+    where
+        S::Error: From<Error>,
+    {
+        let stream = self.new_stream_handle.new_stream().map_err(Error::from);
+        start_service(stream, self.version, service, self.peer_context.clone())
+    }
+
+    /// Request the services that are offered by the remote `Peer`.
+    pub fn list_services(
+        &mut self,
+    ) -> impl SendFuture<Item = Vec<ServiceDescriptor>, Error = Error> {
+        request_service_list(
+            self.new_stream_handle.new_stream().map_err(Error::from),
+            self.version,
+        )
+    }
+}
+
+/// Sends the `Ping`s and receives the `Pong`s, until the `RemotePeer` is dropped or the `Stream`
+/// is closed.
+fn spawn_pings(
+    stream: hole_punch::ProtocolStream<Protocol>,
+    liveness: Arc<Mutex<Liveness>>,
+    closed: oneshot::Receiver<()>,
+) {
+    let (sink, stream) = stream.split();
+    let ping_liveness = liveness.clone();
+    let pong_liveness = liveness.clone();
+
+    let send_pings = Interval::new(Instant::now(), PING_INTERVAL)
+        .map_err(|_| Error::from("Ping timer failed"))
+        .map(move |_| ping_liveness.lock().unwrap().ping())
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let receive_pongs = stream.map_err(Error::from).for_each(move |msg| match msg {
+        Protocol::Pong { id } => {
+            pong_liveness.lock().unwrap().pong(id);
+            Ok(())
+        }
+        _ => bail!("Expected `Pong` on ping stream!"),
+    });
+
+    tokio::spawn(
+        send_pings
+            .select(receive_pongs)
+            .map(|_| ())
+            .map_err(|e| e.0)
+            .select(closed.then(|_| Ok(())))
+            .then(move |res| {
+                if let Err((e, _)) = res {
+                    debug!("Ping stream error: {:?}", e);
+                }
+
+                liveness.lock().unwrap().closed = true;
+                Ok(())
+            }),
+    );
+}
@@ -0,0 +1,77 @@
+use PubKeyHash;
+
+use std::{collections::HashSet, fmt};
+
+enum Rule {
+    AllowAll,
+    AllowPeers(HashSet<PubKeyHash>),
+    Custom(Box<dyn Fn(&PubKeyHash) -> bool + Send>),
+}
+
+/// Decides which remote peers are allowed to start a service.
+pub struct ServiceAcl {
+    rule: Rule,
+}
+
+impl ServiceAcl {
+    /// Every remote peer is allowed to start the service.
+    pub fn allow_all() -> ServiceAcl {
+        ServiceAcl {
+            rule: Rule::AllowAll,
+        }
+    }
+
+    /// No remote peer is allowed to start the service, until it is added with `allow_peer`.
+    pub fn deny_all() -> ServiceAcl {
+        ServiceAcl {
+            rule: Rule::AllowPeers(HashSet::new()),
+        }
+    }
+
+    /// The given function decides which remote peers are allowed to start the service.
+    pub fn from_fn<F>(check: F) -> ServiceAcl
+    where
+        F: Fn(&PubKeyHash) -> bool + Send + 'static,
+    {
+        ServiceAcl {
+            rule: Rule::Custom(Box::new(check)),
+        }
+    }
+
+    /// Allow the given remote peer to start the service.
+    /// This replaces an `allow_all` or `from_fn` rule with an allow list.
+    pub fn allow_peer(mut self, peer: PubKeyHash) -> ServiceAcl {
+        match self.rule {
+            Rule::AllowPeers(ref mut peers) => {
+                peers.insert(peer);
+            }
+            _ => self.rule = Rule::AllowPeers(Some(peer).into_iter().collect()),
+        }
+        self
+    }
+
+    /// Returns if the given remote peer is allowed to start the service.
+    pub fn is_allowed(&self, peer: &PubKeyHash) -> bool {
+        match self.rule {
+            Rule::AllowAll => true,
+            Rule::AllowPeers(ref peers) => peers.contains(peer),
+            Rule::Custom(ref check) => check(peer),
+        }
+    }
+}
+
+impl Default for ServiceAcl {
+    fn default() -> ServiceAcl {
+        ServiceAcl::allow_all()
+    }
+}
+
+impl fmt::Debug for ServiceAcl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rule {
+            Rule::AllowAll => write!(f, "ServiceAcl::AllowAll"),
+            Rule::AllowPeers(ref peers) => write!(f, "ServiceAcl::AllowPeers({:?})", peers),
+            Rule::Custom(_) => write!(f, "ServiceAcl::Custom"),
+        }
+    }
+}
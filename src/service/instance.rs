@@ -0,0 +1,299 @@
+use super::{CloseReason, ServiceId};
+use context::PeerContext;
+use error::*;
+use protocol::{self, Protocol};
+use PubKeyHash;
+
+use hole_punch::{self, SendFuture};
+
+use tokio::{
+    self,
+    timer::{Delay, Interval, Timeout},
+};
+
+use futures::{
+    future::{self, Either, Loop, Shared},
+    sync::{mpsc::Sender, oneshot},
+    Async::Ready,
+    Future, Sink, Stream as FStream,
+};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The state of a service instance that is shared with its `Stream`s.
+/// It records the last activity on the `Stream`s and signals when the instance is closed.
+#[derive(Clone)]
+pub(crate) struct Instance {
+    last_activity: Arc<Mutex<Instant>>,
+    closed: Shared<oneshot::Receiver<CloseReason>>,
+}
+
+impl Instance {
+    pub fn new() -> (oneshot::Sender<CloseReason>, Instance) {
+        let (sender, receiver) = oneshot::channel();
+
+        (
+            sender,
+            Instance {
+                last_activity: Arc::new(Mutex::new(Instant::now())),
+                closed: receiver.shared(),
+            },
+        )
+    }
+
+    /// Records that data was send or received.
+    pub fn active(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    /// Returns an error, if the instance was closed.
+    pub fn poll_closed(&mut self) -> Result<()> {
+        match self.closed.poll() {
+            Ok(Ready(reason)) => Err(Error::ServiceClosed(*reason)),
+            // The sender is dropped with the instance, that is not a close.
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolves, when the instance is closed or dropped.
+    fn closed(&self) -> impl SendFuture<Item = (), Error = ()> {
+        self.closed.clone().then(|_| Ok(()))
+    }
+}
+
+/// Keeps a service instance registered at the `PeerContext`.
+/// The guard is shared by the `Streams` and all `Stream`s of the instance, the instance is dropped
+/// after the last of them was dropped. Services may drop their `Streams`, after they took the
+/// `Stream`s they use.
+pub(crate) struct InstanceGuard {
+    service_id: ServiceId,
+    dropped: Sender<ServiceId>,
+}
+
+impl InstanceGuard {
+    pub fn new(service_id: ServiceId, dropped: Sender<ServiceId>) -> Arc<InstanceGuard> {
+        Arc::new(InstanceGuard {
+            service_id,
+            dropped,
+        })
+    }
+}
+
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        let _ = self.dropped.try_send(self.service_id);
+    }
+}
+
+/// The timeouts of the instances of a service.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ServiceTimeouts {
+    /// The instance is closed, if no data was send or received for this duration.
+    pub idle: Option<Duration>,
+    /// The interval in which the other side of the instance needs to answer a keepalive.
+    pub keepalive: Option<Duration>,
+}
+
+impl ServiceTimeouts {
+    pub fn is_enabled(&self) -> bool {
+        self.idle.is_some() || self.keepalive.is_some()
+    }
+}
+
+/// Closes a service instance, when it is idle or the other side does not answer the keepalives.
+pub(crate) struct Watchdog {
+    pub id: ServiceId,
+    pub remote_peer: PubKeyHash,
+    pub instance: Instance,
+    pub timeouts: ServiceTimeouts,
+    pub new_stream_handle: hole_punch::NewStreamHandle,
+    /// The protocol version that was negotiated with the remote peer.
+    pub version: u32,
+    pub remote_service_id: ServiceId,
+}
+
+impl Watchdog {
+    /// Spawns the watchdog, it runs until the instance is closed or dropped.
+    pub fn spawn(self, mut context: PeerContext) {
+        let Watchdog {
+            id,
+            remote_peer,
+            instance,
+            timeouts,
+            new_stream_handle,
+            version,
+            remote_service_id,
+        } = self;
+
+        let idle = match timeouts.idle {
+            Some(timeout) => Either::A(idle(instance.clone(), timeout)),
+            None => Either::B(future::empty()),
+        };
+
+        let keepalive = match timeouts.keepalive {
+            Some(interval) => Either::A(keepalive(
+                new_stream_handle.clone(),
+                version,
+                remote_service_id,
+                interval,
+            )),
+            None => Either::B(future::empty()),
+        };
+
+        let reason = idle
+            .select(keepalive)
+            .map(|(reason, _)| reason)
+            .map_err(|(e, _)| e);
+
+        tokio::spawn(reason.select2(instance.closed()).then(move |res| {
+            match res {
+                Ok(Either::A((reason, _))) => {
+                    debug!("Closing service instance {}: {}", id, reason);
+                    if context.close_service_instance(id, &remote_peer, reason) {
+                        tokio::spawn(
+                            notify_close(new_stream_handle, version, remote_service_id, reason)
+                                .map_err(|e| debug!("Could not send close reason: {:?}", e)),
+                        );
+                    }
+                }
+                Err(Either::A((e, _))) => error!("Service instance watchdog error: {:?}", e),
+                Ok(Either::B(_)) | Err(Either::B(_)) => {}
+            }
+
+            Ok(())
+        }));
+    }
+}
+
+/// Resolves, when the instance was idle for the given timeout.
+fn idle(
+    instance: Instance,
+    timeout: Duration,
+) -> impl SendFuture<Item = CloseReason, Error = Error> {
+    future::loop_fn(Instant::now() + timeout, move |deadline| {
+        let instance = instance.clone();
+
+        Delay::new(deadline)
+            .map_err(|_| Error::from("Idle timer failed"))
+            .map(move |_| {
+                let last_activity = instance.last_activity();
+
+                if last_activity.elapsed() >= timeout {
+                    Loop::Break(CloseReason::Idle)
+                } else {
+                    Loop::Continue(last_activity + timeout)
+                }
+            })
+    })
+}
+
+/// Resolves, when the other side of the instance did not answer a keepalive in time.
+/// Never resolves, if the remote peer does not support keepalives.
+fn keepalive(
+    new_stream_handle: hole_punch::NewStreamHandle,
+    version: u32,
+    remote_service_id: ServiceId,
+    interval: Duration,
+) -> impl SendFuture<Item = CloseReason, Error = Error> {
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|_| None)
+        .for_each(move |_| {
+            let keepalive = send_keepalive(new_stream_handle.clone(), version, remote_service_id);
+
+            Timeout::new(keepalive, interval).then(|res| match res {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    debug!("Remote peer does not support keepalives");
+                    Err(None)
+                }
+                Err(e) => {
+                    debug!("Keepalive failed: {:?}", e);
+                    Err(Some(CloseReason::KeepaliveFailed))
+                }
+            })
+        })
+        .then(|res| match res {
+            Err(Some(reason)) => Either::A(future::ok(reason)),
+            _ => Either::B(future::empty()),
+        })
+}
+
+/// Sends a keepalive over a new `Stream`.
+/// Resolves to `false`, if the remote peer does not support keepalives.
+fn send_keepalive(
+    mut new_stream_handle: hole_punch::NewStreamHandle,
+    version: u32,
+    remote_service_id: ServiceId,
+) -> impl SendFuture<Item = bool, Error = Error> {
+    new_stream_handle
+        .new_stream()
+        .map_err(Error::from)
+        .and_then(move |stream| {
+            protocol::open::<hole_punch::ProtocolStream<Protocol>>(stream.into(), version)
+        })
+        .and_then(move |(stream, handshake)| {
+            if !handshake
+                .features
+                .iter()
+                .any(|f| f == protocol::FEATURE_SERVICE_TIMEOUTS)
+            {
+                return Either::A(future::ok(false));
+            }
+
+            Either::B(
+                stream
+                    .send(Protocol::Keepalive {
+                        id: remote_service_id,
+                    })
+                    .and_then(|s| s.into_future().map_err(|e| e.0))
+                    .map_err(Error::from)
+                    .and_then(|(msg, _)| match msg {
+                        Some(Protocol::KeepaliveAck) => Ok(true),
+                        Some(Protocol::Error { code, message }) => Err(code.into_error(message)),
+                        None => bail!("Stream closed while waiting for `KeepaliveAck`!"),
+                        _ => bail!("Received unexpected message!"),
+                    }),
+            )
+        })
+}
+
+/// Sends the reason why the instance was closed to the other side of the instance.
+fn notify_close(
+    mut new_stream_handle: hole_punch::NewStreamHandle,
+    version: u32,
+    remote_service_id: ServiceId,
+    reason: CloseReason,
+) -> impl SendFuture<Item = (), Error = Error> {
+    new_stream_handle
+        .new_stream()
+        .map_err(Error::from)
+        .and_then(move |stream| {
+            protocol::open::<hole_punch::ProtocolStream<Protocol>>(stream.into(), version)
+        })
+        .and_then(move |(stream, handshake)| {
+            if !handshake
+                .features
+                .iter()
+                .any(|f| f == protocol::FEATURE_SERVICE_TIMEOUTS)
+            {
+                return Either::A(future::ok(()));
+            }
+
+            Either::B(
+                stream
+                    .send(Protocol::CloseServiceInstance {
+                        id: remote_service_id,
+                        reason,
+                    })
+                    .map(|_| ())
+                    .map_err(Error::from),
+            )
+        })
+}
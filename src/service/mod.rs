@@ -5,35 +5,235 @@ be running over `Carrier`.
 
 `Carrier` will call `Server::start` whenever a remote `Peer` requests the service from the local
 `Peer`. The remote `Peer` needs to run an instance of the `Client` service implementation.
+
+Both sides of a service instance receive a `ServiceContext` that identifies the remote `Peer`.
+The certificate chain of the remote `Peer` is only given, if it is signed by one of the CAs that
+were given to the local `Peer`.
+
+Which remote `Peer`s are allowed to start a service is decided by the `ServiceAcl` that is given
+when registering the service. By default, every remote `Peer` is allowed to start the service.
+
+The `Client` can send arguments to the `Server` when requesting the service. The arguments are
+checked by the `Server` before the service instance is started, invalid arguments are reported
+back to the `Client` with the reason of the rejection. Resources of a new instance, like a
+listening socket, are reserved in `Server::prepare`, after the arguments and the limits of the
+`Peer` were checked.
+
+When the local `Peer` is shut down, new service instances are rejected and the running instances
+are notified by the `ShutdownSignal` of their `Streams`.
+
+A service instance runs, as long as its `Streams` or one of its `Stream`s exists. Services may
+drop the `Streams`, after they took the `Stream`s they use.
+
+Services can be configured with an idle timeout and keepalives. An instance that is closed by
+them fails on both sides with `Error::ServiceClosed` and the `CloseReason`.
+
+Services that only answer requests can implement `RpcServer` and use an `RpcClient`, instead of
+handling the `Stream`s themselves.
 */
-use NewStreamHandle;
+use error::Error;
+use {NewStreamHandle, PubKeyHash, Stream};
 
 use futures::Future;
 
-use std::result;
+use openssl::x509::X509;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use serde_json::{self, Value};
 
+use std::{any::Any, fmt, net::SocketAddr, result};
+
+mod acl;
+mod instance;
+mod rpc;
 mod streams;
 
-pub use self::streams::Streams;
+pub use self::acl::ServiceAcl;
+pub(crate) use self::instance::{Instance, InstanceGuard, ServiceTimeouts, Watchdog};
+pub use self::rpc::{RpcCall, RpcClient, RpcClientFuture, RpcHandle, RpcServer};
+pub(crate) use self::streams::ShutdownSender;
+pub use self::streams::{ShutdownSignal, Streams};
 
+/// Identifies a service instance at a `Peer`. The ids are random and an instance can only be
+/// accessed by the remote `Peer` it was created with.
 pub type ServiceId = u64;
 
+/// The reason why a service instance was closed by `Carrier`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// No data was send or received over the `Stream`s of the instance for the idle timeout.
+    Idle,
+    /// The other side of the instance did not answer a keepalive.
+    KeepaliveFailed,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::Idle => write!(f, "idle timeout"),
+            CloseReason::KeepaliveFailed => write!(f, "keepalive failed"),
+        }
+    }
+}
+
+/// Describes a service that is offered by a `Peer`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServiceDescriptor {
+    /// The unique name of the service.
+    pub name: String,
+}
+
+/// Information about the remote `Peer` of a service instance.
+#[derive(Clone, Debug)]
+pub struct ServiceContext {
+    remote_peer: PubKeyHash,
+    remote_addr: SocketAddr,
+    certificate_chain: Option<Vec<X509>>,
+}
+
+impl ServiceContext {
+    pub(crate) fn new(stream: &Stream, certificate_chain: Option<Vec<X509>>) -> ServiceContext {
+        ServiceContext {
+            remote_peer: stream.peer_identifier().clone(),
+            remote_addr: stream.peer_addr(),
+            certificate_chain,
+        }
+    }
+
+    /// The identifier of the remote `Peer`, the hash of its public key.
+    pub fn remote_peer(&self) -> &PubKeyHash {
+        &self.remote_peer
+    }
+
+    /// The address of the remote `Peer`, as seen by the local `Peer`.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// The verified certificate chain of the remote `Peer`, starting with its own certificate.
+    /// `None`, if the chain is not signed by one of the CAs of the local `Peer`.
+    pub fn remote_certificate_chain(&self) -> Option<&[X509]> {
+        self.certificate_chain.as_ref().map(|c| &c[..])
+    }
+
+    /// The subject of the certificate of the remote `Peer`, as one line of comma separated
+    /// entries, like `CN=controller, O=korhal`.
+    /// `None`, if the certificate chain could not be verified.
+    pub fn remote_subject(&self) -> Option<String> {
+        let cert = self.remote_certificate_chain()?.first()?;
+
+        let entries = cert
+            .subject_name()
+            .entries()
+            .map(|e| {
+                format!(
+                    "{}={}",
+                    e.object().nid().short_name().unwrap_or("?"),
+                    String::from_utf8_lossy(e.data().as_slice())
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Some(entries.join(", "))
+    }
+}
+
 /// Server side of a service.
 pub trait Server: Send {
+    /// The arguments that are send by the `Client` to start a new instance.
+    type Args: DeserializeOwned + Send + 'static;
+    /// Checks the arguments of a new instance, before the instance is started.
+    /// Returning an error rejects the start and the reason is send to the `Client`.
+    /// When it returns `Ok`, the instance is prepared and started with the same arguments.
+    /// It should not have side effects, use `prepare` to reserve resources.
+    fn check_args(&self, _args: &Self::Args) -> result::Result<(), String> {
+        Ok(())
+    }
+    /// Reserves the resources of a new instance, after its arguments and the limits of the `Peer`
+    /// were checked. The service is locked until the instance is started or aborted.
+    /// Returning `Error::Busy` rejects the start as busy, other errors reject the arguments.
+    /// When it returns `Ok`, either `start` or `abort` is called with the same arguments.
+    fn prepare(&mut self, _args: &Self::Args) -> result::Result<(), Error> {
+        Ok(())
+    }
+    /// Releases the resources that were reserved by `prepare`, when the instance is not started,
+    /// because the `Client` went away before it was informed about the start.
+    fn abort(&mut self, _args: Self::Args) {}
     /// Start a new server instance of the service.
-    fn start(&mut self, streams: Streams, new_stream_handle: NewStreamHandle);
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: Self::Args,
+    );
     /// Returns the unique name of the service. The name will be used to identify this service.
     fn name(&self) -> &'static str;
 }
 
+/// Object safe version of `Server` that parses the arguments from their serialized form.
+pub(crate) trait DynServer: Send {
+    fn parse_args(&self, args: Value) -> result::Result<Box<dyn Any + Send>, String>;
+    fn prepare(&mut self, args: &(dyn Any + Send)) -> result::Result<(), Error>;
+    fn abort(&mut self, args: Box<dyn Any + Send>);
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: Box<dyn Any + Send>,
+    );
+}
+
+impl<S: Server> DynServer for S {
+    fn parse_args(&self, args: Value) -> result::Result<Box<dyn Any + Send>, String> {
+        let args: S::Args = serde_json::from_value(args).map_err(|e| e.to_string())?;
+        self.check_args(&args)?;
+        Ok(Box::new(args))
+    }
+
+    fn prepare(&mut self, args: &(dyn Any + Send)) -> result::Result<(), Error> {
+        let args = args
+            .downcast_ref::<S::Args>()
+            .expect("Arguments are created by `parse_args`");
+        Server::prepare(self, args)
+    }
+
+    fn abort(&mut self, args: Box<dyn Any + Send>) {
+        let args = args
+            .downcast::<S::Args>()
+            .expect("Arguments are created by `parse_args`");
+        Server::abort(self, *args);
+    }
+
+    fn start(
+        &mut self,
+        context: ServiceContext,
+        streams: Streams,
+        new_stream_handle: NewStreamHandle,
+        args: Box<dyn Any + Send>,
+    ) {
+        let args = args
+            .downcast::<S::Args>()
+            .expect("Arguments are created by `parse_args`");
+        Server::start(self, context, streams, new_stream_handle, *args);
+    }
+}
+
 /// Client side of a service.
 pub trait Client: Send {
     type Error: Send;
     type Future: Future<Error = Self::Error> + Send;
+    /// The arguments that are send to the `Server` to start a new instance.
+    type Args: Serialize;
+    /// Returns the arguments for starting the service at the remote `Peer`.
+    fn args(&self) -> Self::Args;
     /// Starts a new client instance.
     /// The returned `Future` should resolve, when the service is finished.
     fn start(
         self,
+        context: ServiceContext,
         streams: Streams,
         new_stream_handle: NewStreamHandle,
     ) -> result::Result<Self::Future, Self::Error>;
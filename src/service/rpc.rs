@@ -0,0 +1,353 @@
+/*!
+A typed request/response layer on top of `ProtocolStream`.
+
+A service that implements `RpcServer` only needs to handle single requests, it implements
+`Server` automatically. The remote `Peer` uses an `RpcClient` and calls the service through the
+`RpcHandle` that is created with it. Multiple calls can be in flight at the same time, the
+responses are matched to their calls by a request id.
+*/
+use super::{Client, Server, ServiceContext, Streams};
+use error::*;
+use {NewStreamHandle, ProtocolStream};
+
+use tokio::{self, timer::Timeout};
+
+use futures::{
+    future,
+    sync::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    Async::{NotReady, Ready},
+    AsyncSink, Future, Poll, Sink, Stream as FStream,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::{collections::HashMap, result, time::Duration};
+
+/// The maximum number of requests a server instance handles at the same time.
+const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// The default time an `RpcHandle` waits for a response.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The messages that are send over the `Stream` of an RPC service instance.
+#[derive(Deserialize, Serialize)]
+enum RpcMessage<Req, Resp> {
+    Request {
+        id: u64,
+        request: Req,
+    },
+    Response {
+        id: u64,
+        result: result::Result<Resp, String>,
+    },
+}
+
+/// Server side of an RPC service.
+///
+/// Every service instance uses its own clone of the server. The requests of an instance are
+/// handled concurrently, up to `MAX_CONCURRENT_REQUESTS` at a time. An error is send to the
+/// client as the result of the call.
+pub trait RpcServer: Clone + Send + 'static {
+    type Request: Serialize + DeserializeOwned + Send + 'static;
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+    type Future: Future<Item = Self::Response, Error = Error> + Send + 'static;
+    /// The unique name of the service.
+    const NAME: &'static str;
+    /// Handles a request of the remote `Peer`.
+    fn handle(&mut self, context: &ServiceContext, request: Self::Request) -> Self::Future;
+}
+
+impl<S: RpcServer> Server for S {
+    type Args = ();
+
+    fn start(&mut self, context: ServiceContext, streams: Streams, _: NewStreamHandle, _: ()) {
+        let mut server = self.clone();
+
+        tokio::spawn(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(move |(stream, _)| {
+                    let stream: ProtocolStream<RpcMessage<S::Request, S::Response>> = match stream {
+                        Some(stream) => stream.into(),
+                        None => bail!("No `Stream` for RPC service"),
+                    };
+                    let (sink, requests) = stream.split();
+
+                    Ok(requests
+                        .map_err(Error::from)
+                        .filter_map(|msg| match msg {
+                            RpcMessage::Request { id, request } => Some((id, request)),
+                            RpcMessage::Response { .. } => None,
+                        })
+                        .map(move |(id, request)| {
+                            server.handle(&context, request).then(move |result| {
+                                Ok(RpcMessage::Response {
+                                    id,
+                                    result: result.map_err(|e| e.to_string()),
+                                })
+                            })
+                        })
+                        .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+                        .forward(sink.sink_map_err(Error::from))
+                        .map(|_| ()))
+                })
+                .flatten()
+                .map_err(move |e| error!("RPC service `{}` error: {:?}", S::NAME, e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        S::NAME
+    }
+}
+
+struct Call<Req, Resp> {
+    request: Req,
+    response: oneshot::Sender<Result<Resp>>,
+}
+
+/// Client side of an RPC service.
+///
+/// The returned `Future` of the service resolves, when all `RpcHandle`s are dropped and all
+/// calls are finished.
+pub struct RpcClient<Req, Resp> {
+    name: &'static str,
+    calls: UnboundedReceiver<Call<Req, Resp>>,
+}
+
+impl<Req, Resp> RpcClient<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Creates a client for the RPC service with the given name and the handle to call it.
+    /// Calls that are made before the service is started are send when it is started.
+    pub fn new(name: &'static str) -> (RpcClient<Req, Resp>, RpcHandle<Req, Resp>) {
+        let (sender, calls) = unbounded();
+
+        (
+            RpcClient { name, calls },
+            RpcHandle {
+                calls: sender,
+                timeout: DEFAULT_TIMEOUT,
+            },
+        )
+    }
+}
+
+impl<Req, Resp> Client for RpcClient<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = Error;
+    type Future = RpcClientFuture<Req, Resp>;
+    type Args = ();
+
+    fn args(&self) -> Self::Args {}
+
+    fn start(
+        self,
+        _: ServiceContext,
+        mut streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let stream = match streams.poll()? {
+            Ready(Some(stream)) => stream.into(),
+            _ => bail!("No `Stream` for RPC service"),
+        };
+
+        Ok(RpcClientFuture {
+            stream,
+            calls: Some(self.calls),
+            sending: None,
+            pending: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Sends the calls of the `RpcHandle`s and dispatches the responses.
+pub struct RpcClientFuture<Req, Resp> {
+    stream: ProtocolStream<RpcMessage<Req, Resp>>,
+    /// `None`, when all `RpcHandle`s are dropped.
+    calls: Option<UnboundedReceiver<Call<Req, Resp>>>,
+    /// The request that could not be send yet.
+    sending: Option<RpcMessage<Req, Resp>>,
+    pending: HashMap<u64, oneshot::Sender<Result<Resp>>>,
+    next_id: u64,
+}
+
+impl<Req, Resp> RpcClientFuture<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+{
+    fn poll_calls(&mut self) -> Result<()> {
+        loop {
+            if let Some(msg) = self.sending.take() {
+                if let AsyncSink::NotReady(msg) = self.stream.start_send(msg)? {
+                    self.sending = Some(msg);
+                    break;
+                }
+            }
+
+            let call = match self.calls.as_mut().map(|c| c.poll()) {
+                Some(Ok(Ready(Some(call)))) => call,
+                Some(Ok(Ready(None))) | Some(Err(_)) => {
+                    self.calls = None;
+                    break;
+                }
+                Some(Ok(NotReady)) | None => break,
+            };
+
+            // The call timed out before it was send.
+            if call.response.is_canceled() {
+                continue;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+            self.pending.insert(id, call.response);
+            self.sending = Some(RpcMessage::Request {
+                id,
+                request: call.request,
+            });
+        }
+
+        self.stream.poll_complete()?;
+        Ok(())
+    }
+
+    fn poll_responses(&mut self) -> Poll<(), Error> {
+        loop {
+            match self.stream.poll()? {
+                Ready(Some(RpcMessage::Response { id, result })) => {
+                    if let Some(response) = self.pending.remove(&id) {
+                        let _ = response
+                            .send(result.map_err(|e| format_err!("RPC call failed: {}", e).into()));
+                    }
+                }
+                Ready(Some(RpcMessage::Request { .. })) => {
+                    bail!("Received unexpected RPC request")
+                }
+                Ready(None) => return Ok(Ready(())),
+                NotReady => return Ok(NotReady),
+            }
+        }
+    }
+}
+
+impl<Req, Resp> Future for RpcClientFuture<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Resp: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_calls()?;
+        let closed = self.poll_responses()?.is_ready();
+
+        // Forget the calls that timed out, this also wakes up the task when a call times out.
+        self.pending.retain(|_, response| {
+            response
+                .poll_cancel()
+                .map(|r| r.is_not_ready())
+                .unwrap_or(false)
+        });
+
+        let finished = self.calls.is_none() && self.sending.is_none() && self.pending.is_empty();
+        if finished {
+            Ok(Ready(()))
+        } else if closed {
+            // Dropping the pending calls fails them.
+            bail!("RPC `Stream` closed")
+        } else {
+            Ok(NotReady)
+        }
+    }
+}
+
+/// Calls the RPC service of an `RpcClient`.
+/// The handle can be cloned, to make calls from multiple places.
+pub struct RpcHandle<Req, Resp> {
+    calls: UnboundedSender<Call<Req, Resp>>,
+    timeout: Duration,
+}
+
+impl<Req, Resp> RpcHandle<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Set the time a call waits for its response (default 30s).
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Calls the service with the given request.
+    pub fn call(&self, request: Req) -> RpcCall<Resp> {
+        let (response, receiver) = oneshot::channel();
+
+        if self
+            .calls
+            .unbounded_send(Call { request, response })
+            .is_err()
+        {
+            return RpcCall {
+                future: Box::new(future::err(Error::from("RPC client is finished"))),
+            };
+        }
+
+        let future = receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => bail!("RPC client dropped the call"),
+        });
+
+        RpcCall {
+            future: Box::new(Timeout::new(future, self.timeout).map_err(|e| {
+                if e.is_elapsed() {
+                    Error::Timeout("RPC call".into())
+                } else if e.is_timer() {
+                    Error::from("RPC timer failed")
+                } else {
+                    e.into_inner().expect("Error is not elapsed or timer")
+                }
+            })),
+        }
+    }
+}
+
+impl<Req, Resp> Clone for RpcHandle<Req, Resp> {
+    fn clone(&self) -> Self {
+        RpcHandle {
+            calls: self.calls.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// The response of a call, fails with `Error::Timeout` if the response does not arrive in time.
+pub struct RpcCall<Resp> {
+    future: Box<dyn Future<Item = Resp, Error = Error> + Send>,
+}
+
+impl<Resp> Future for RpcCall<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
@@ -1,44 +1,136 @@
-use super::ServiceId;
+use super::{Instance, InstanceGuard};
 use error::*;
 use stream::Stream;
 
 use futures::{
-    sync::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender},
-    Async::Ready,
-    Poll, Stream as FStream,
+    future::Shared,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    Async::{NotReady, Ready},
+    Future, Poll, Stream as FStream,
 };
 
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Resolves, when the local `Peer` is shut down.
+/// Service instances should finish their work and drop their `Streams` afterwards.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    signal: Shared<oneshot::Receiver<()>>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    pub(crate) fn new() -> (ShutdownSender, ShutdownSignal) {
+        let (sender, receiver) = oneshot::channel();
+        let shutting_down = Arc::new(AtomicBool::new(false));
+
+        (
+            ShutdownSender {
+                sender,
+                shutting_down: shutting_down.clone(),
+            },
+            ShutdownSignal {
+                signal: receiver.shared(),
+                shutting_down,
+            },
+        )
+    }
+
+    /// Returns if the local `Peer` is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Runs the given future, until it finishes or the local `Peer` is shut down.
+    /// Only for work that can be interrupted, like forwarding connections. Work that should be
+    /// finished, like a file transfer, keeps running until the shutdown timeout.
+    pub fn run_until_shutdown<F>(self, future: F) -> impl Future<Item = (), Error = Error> + Send
+    where
+        F: Future<Error = Error> + Send,
+    {
+        future.map(|_| ()).select(self).map(|_| ()).map_err(|e| e.0)
+    }
+}
+
+/// Triggers the `ShutdownSignal`.
+pub(crate) struct ShutdownSender {
+    sender: oneshot::Sender<()>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl ShutdownSender {
+    pub fn send(self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let _ = self.sender.send(());
+    }
+}
+
+impl Future for ShutdownSignal {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The sender is also dropped, when the `Peer` is dropped.
+        match self.signal.poll() {
+            Ok(NotReady) => Ok(NotReady),
+            _ => Ok(Ready(())),
+        }
+    }
+}
+
 /// Returns the `Stream`s for a service instance.
 /// It returns at least one `Stream`, the initial one.
 /// Other `Stream`s are returned, when the other side of the service instance creates a new
 /// `Stream` by using the `NewStreamHandle`.
 pub struct Streams {
     first_stream: Option<Stream>,
-    streams: UnboundedReceiver<Stream>,
-    /// Send that the `Streams` instance is dropped.
-    close_send: Sender<ServiceId>,
-    /// The service id of the service instance this `Streams` instance belongs to.
-    service_id: ServiceId,
+    streams: Receiver<Stream>,
+    /// Keeps the service instance registered, while new `Stream`s can be received.
+    _guard: Arc<InstanceGuard>,
+    shutdown: ShutdownSignal,
+    instance: Instance,
 }
 
 impl Streams {
     pub(crate) fn new(
         first_stream: Stream,
-        close_send: Sender<ServiceId>,
-        service_id: ServiceId,
-    ) -> (Streams, UnboundedSender<Stream>) {
-        let (sender, streams) = unbounded();
+        guard: Arc<InstanceGuard>,
+        shutdown: ShutdownSignal,
+        instance: Instance,
+        max_pending_streams: usize,
+    ) -> (Streams, Sender<Stream>) {
+        let (sender, streams) = channel(max_pending_streams);
 
         (
             Streams {
                 first_stream: Some(first_stream),
                 streams,
-                close_send,
-                service_id,
+                _guard: guard,
+                shutdown,
+                instance,
             },
             sender,
         )
     }
+
+    /// Returns the signal that resolves, when the local `Peer` is shut down.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    /// Maps the first `Stream`, before it is returned.
+    pub(crate) fn map_first_stream<F>(&mut self, map: F)
+    where
+        F: FnOnce(Stream) -> Stream,
+    {
+        self.first_stream = self.first_stream.take().map(map);
+    }
 }
 
 impl FStream for Streams {
@@ -46,6 +138,8 @@ impl FStream for Streams {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.instance.poll_closed()?;
+
         match self.first_stream.take() {
             Some(stream) => Ok(Ready(Some(stream))),
             None => self
@@ -55,9 +149,3 @@ impl FStream for Streams {
         }
     }
 }
-
-impl Drop for Streams {
-    fn drop(&mut self) {
-        let _ = self.close_send.try_send(self.service_id);
-    }
-}
@@ -1,39 +1,113 @@
 use error::*;
-use protocol::Protocol;
-use service::ServiceId;
+use protocol::{self, Protocol};
+use service::{Instance, InstanceGuard, ServiceId};
+use PubKeyHash;
 
 use hole_punch::{self, SendFuture, StreamWithProtocol};
 
-use futures::{Future, Poll, Sink, StartSend, Stream as FStream};
+use futures::{
+    future::{self, Either},
+    Async::Ready,
+    AsyncSink, Future, Poll, Sink, StartSend, Stream as FStream,
+};
 
 use tokio::{
     codec::{Framed, LengthDelimitedCodec},
     io::{AsyncRead, AsyncWrite},
 };
 
-use std::io::{self, Read, Write};
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
 
 use serde::{Deserialize, Serialize};
 
 use tokio_serde_json::{ReadJson, WriteJson};
 
+/// Counts the open `Stream`s of a service instance.
+/// The count is decremented, when the `Stream` that holds the counter is dropped.
+pub(crate) struct StreamCounter {
+    count: Arc<AtomicUsize>,
+}
+
+impl StreamCounter {
+    pub fn new(count: Arc<AtomicUsize>) -> StreamCounter {
+        count.fetch_add(1, Ordering::SeqCst);
+        StreamCounter { count }
+    }
+}
+
+impl Drop for StreamCounter {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 pub struct Stream {
     stream: hole_punch::Stream,
+    counter: Option<StreamCounter>,
+    /// The service instance this `Stream` belongs to.
+    instance: Option<Instance>,
+    /// Keeps the service instance registered, while the `Stream` is open.
+    guard: Option<Arc<InstanceGuard>>,
 }
 
 impl Stream {
-    fn get_ref(&self) -> &hole_punch::Stream {
+    pub(crate) fn get_ref(&self) -> &hole_punch::Stream {
         &self.stream
     }
 
+    /// The identifier of the remote peer.
+    pub fn peer_identifier(&self) -> &PubKeyHash {
+        self.stream.peer_identifier()
+    }
+
+    /// The address of the remote peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.peer_addr()
+    }
+
     pub fn set_send_channel_size(&mut self, size: usize) {
         self.stream.set_send_channel_size(size);
     }
+
+    pub(crate) fn set_counter(&mut self, counter: StreamCounter) {
+        self.counter = Some(counter);
+    }
+
+    pub(crate) fn set_instance(&mut self, instance: Instance, guard: Arc<InstanceGuard>) {
+        self.instance = Some(instance);
+        self.guard = Some(guard);
+    }
+
+    /// Returns an error, if the service instance was closed.
+    fn poll_closed(&mut self) -> Result<()> {
+        match self.instance {
+            Some(ref mut instance) => instance.poll_closed(),
+            None => Ok(()),
+        }
+    }
+
+    fn active(&self) {
+        if let Some(ref instance) = self.instance {
+            instance.active();
+        }
+    }
 }
 
 impl From<hole_punch::Stream> for Stream {
     fn from(stream: hole_punch::Stream) -> Stream {
-        Stream { stream }
+        Stream {
+            stream,
+            counter: None,
+            instance: None,
+            guard: None,
+        }
     }
 }
 
@@ -41,6 +115,9 @@ impl From<hole_punch::ProtocolStream<Protocol>> for Stream {
     fn from(stream: hole_punch::ProtocolStream<Protocol>) -> Self {
         Self {
             stream: stream.into(),
+            counter: None,
+            instance: None,
+            guard: None,
         }
     }
 }
@@ -73,7 +150,13 @@ impl FStream for Stream {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.stream.poll().map_err(|e| e.into())
+        self.poll_closed()?;
+
+        let res = self.stream.poll()?;
+        if let Ready(Some(_)) = res {
+            self.active();
+        }
+        Ok(res)
     }
 }
 
@@ -82,23 +165,42 @@ impl Sink for Stream {
     type SinkError = Error;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        self.stream.start_send(item).map_err(|e| e.into())
+        self.poll_closed()?;
+
+        let res = self.stream.start_send(item)?;
+        if let AsyncSink::Ready = res {
+            self.active();
+        }
+        Ok(res)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.poll_closed()?;
         self.stream.poll_complete().map_err(|e| e.into())
     }
 }
 
 impl Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.stream.read(buf)
+        self.poll_closed()?;
+
+        let len = self.stream.read(buf)?;
+        if len > 0 {
+            self.active();
+        }
+        Ok(len)
     }
 }
 
 impl Write for Stream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.stream.write(buf)
+        self.poll_closed()?;
+
+        let len = self.stream.write(buf)?;
+        if len > 0 {
+            self.active();
+        }
+        Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -118,36 +220,75 @@ impl AsyncWrite for Stream {
 pub struct NewStreamHandle {
     new_stream_handle: hole_punch::NewStreamHandle,
     service_id: ServiceId,
+    /// The protocol version that was negotiated with the remote peer, when the instance was
+    /// started.
+    version: u32,
+    instance: Instance,
+    /// The handle does not keep the service instance registered, only its `Stream`s do.
+    guard: Weak<InstanceGuard>,
 }
 
 impl NewStreamHandle {
-    pub(crate) fn new(service_id: ServiceId, stream: &Stream) -> NewStreamHandle {
+    pub(crate) fn new(
+        service_id: ServiceId,
+        version: u32,
+        stream: &Stream,
+        instance: Instance,
+        guard: Weak<InstanceGuard>,
+    ) -> NewStreamHandle {
         let new_stream_handle = stream.get_ref().new_stream_handle().clone();
 
         NewStreamHandle {
             new_stream_handle,
             service_id,
+            version,
+            instance,
+            guard,
         }
     }
 
     pub fn new_stream(&mut self) -> impl SendFuture<Item = Stream, Error = Error> {
         let service_id = self.service_id;
-        self.new_stream_handle
+        let version = self.version;
+        let instance = self.instance.clone();
+        let guard = match self.guard.upgrade() {
+            Some(guard) => guard,
+            None => {
+                return Either::A(future::err(
+                    "Service instance was dropped, after all its `Stream`s were closed.".into(),
+                ))
+            }
+        };
+
+        let new_stream = self
+            .new_stream_handle
             .new_stream()
             .map_err(|e| e.into())
             .and_then(move |stream| {
-                let stream = hole_punch::ProtocolStream::from(stream.into());
+                protocol::open::<hole_punch::ProtocolStream<Protocol>>(stream.into(), version)
+            })
+            .and_then(move |(stream, _)| {
                 stream
                     .send(Protocol::ConnectToService { id: service_id })
                     .map_err(|e| e.into())
                     .and_then(|s| s.into_future().map_err(|e| e.0.into()))
             })
-            .and_then(|(msg, stream)| match msg {
+            .and_then(move |(msg, stream)| match msg {
                 None => bail!("Stream closed!"),
-                Some(Protocol::ServiceConnected) => Ok(stream.into()),
-                Some(Protocol::ServiceNotFound) => bail!("Could not find requested service!"),
+                Some(Protocol::ServiceConnected) => {
+                    let mut stream: Stream = stream.into();
+                    stream.set_instance(instance, guard);
+                    Ok(stream)
+                }
+                Some(Protocol::Error { code, message }) => Err(code.into_error(message)),
+                Some(Protocol::ServiceNotFound) => Err(Error::NotFound(format!(
+                    "Service instance `{}` not found.",
+                    service_id
+                ))),
                 _ => bail!("Received unexpected message!"),
-            })
+            });
+
+        Either::B(new_stream)
     }
 }
 
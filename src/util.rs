@@ -1,9 +1,27 @@
+use error::*;
+
 use std::{fmt::Display, path::PathBuf, result};
 
 use glob;
 
+use hole_punch::SendFuture;
+
+use futures::{Future, Stream};
+
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
 pub fn glob_for_certificates<T: Display>(
     path: &T,
 ) -> result::Result<Vec<PathBuf>, glob::PatternError> {
     glob::glob(&format!("{}/*.pem", path)).map(|v| v.filter_map(|v| v.ok()).collect())
 }
+
+/// Resolves, when the process receives `SIGTERM` or `SIGINT`.
+pub fn termination_signal() -> impl SendFuture<Item = (), Error = Error> {
+    Signal::new(SIGTERM)
+        .flatten_stream()
+        .select(Signal::new(SIGINT).flatten_stream())
+        .into_future()
+        .map(|_| ())
+        .map_err(|e| e.0.into())
+}
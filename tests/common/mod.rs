@@ -1,19 +1,50 @@
 use carrier::{
-    self,
-    service::{Client, Server, Streams},
-    Error, FileFormat, NewStreamHandle, PubKeyHash, SendFuture,
+    self, builtin_services,
+    service::{
+        Client, RpcClient, RpcHandle, RpcServer, Server, ServiceAcl, ServiceContext,
+        ServiceDescriptor, Streams,
+    },
+    Error, FileFormat, NewStreamHandle, PeerBuilder, ProtocolStream, PubKeyHash, RemotePeer,
+    SendFuture,
 };
 
-use std::{net::SocketAddr, result, thread, time::Duration};
+use hole_punch::{self, CreateConnectionToPeerHandle};
 
-use tokio::runtime::{Runtime, TaskExecutor};
+use std::{
+    env, fs,
+    io::{Read, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process, result, thread,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{self, AsyncRead},
+    net::TcpListener,
+    runtime::{Runtime, TaskExecutor},
+    timer::{Delay, Timeout},
+};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    rsa::Rsa,
+    sign::Signer,
+    x509::{X509NameBuilder, X509},
+};
 
 use futures::{
-    future, future::FutureResult, stream::futures_unordered, sync::mpsc::unbounded, Future, Sink,
-    Stream as FStream,
+    future,
+    future::{Either, FutureResult},
+    stream::futures_unordered,
+    sync::mpsc::unbounded,
+    Future, Sink, Stream as FStream,
 };
 
-const TEST_SERVICE_DATA: &[u8] = b"HERP!DERP!TEST!SERVICE";
+pub const TEST_SERVICE_DATA: &[u8] = b"HERP!DERP!TEST!SERVICE";
 
 type Result<T> = result::Result<T, Error>;
 
@@ -46,6 +77,140 @@ pub fn start_bearer(executor: TaskExecutor) -> u16 {
 /// stream_num - The number of `Stream`s to start, 1 is minimum.
 /// bearer_port - The port of the bearer.
 pub fn start_peer(stream_num: u16, bearer_port: u16, send_data: bool, executor: TaskExecutor) {
+    PeerOptions::new()
+        .test_service(stream_num, send_data)
+        .start(bearer_port, executor);
+}
+
+/// The options of a peer that is started for a test.
+/// By default, the peer offers the `TestService`, the `AddService`, the `ContextService` and the
+/// builtin services with their default `Config`.
+pub struct PeerOptions {
+    stream_num: u16,
+    send_data: bool,
+    acl: ServiceAcl,
+    builtin_services: Option<builtin_services::Config>,
+    slow_start: Option<Duration>,
+    stream_limit_service: bool,
+    configure: Vec<Box<dyn FnOnce(PeerBuilder) -> PeerBuilder>>,
+}
+
+impl PeerOptions {
+    pub fn new() -> PeerOptions {
+        PeerOptions {
+            stream_num: 1,
+            send_data: true,
+            acl: ServiceAcl::allow_all(),
+            builtin_services: None,
+            slow_start: None,
+            stream_limit_service: false,
+            configure: Vec::new(),
+        }
+    }
+
+    /// Only offer the builtin services of the given `Config`.
+    pub fn builtin_services(config: builtin_services::Config) -> PeerOptions {
+        PeerOptions {
+            builtin_services: Some(config),
+            ..PeerOptions::new()
+        }
+    }
+
+    /// The `TestService` starts `stream_num` `Stream`s, 1 is minimum.
+    pub fn test_service(mut self, stream_num: u16, send_data: bool) -> Self {
+        self.stream_num = stream_num;
+        self.send_data = send_data;
+        self
+    }
+
+    /// The `TestService` is only startable by the peers allowed by the given acl.
+    pub fn test_service_acl(mut self, acl: ServiceAcl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Offer the `SlowService`, its start blocks for the given delay.
+    pub fn slow_service(mut self, delay: Duration) -> Self {
+        self.slow_start = Some(delay);
+        self
+    }
+
+    /// Offer the `StreamLimitService`.
+    pub fn stream_limit_service(mut self) -> Self {
+        self.stream_limit_service = true;
+        self
+    }
+
+    /// Every remote peer may only run the given number of service instances.
+    pub fn max_instances_per_peer(self, max: usize) -> Self {
+        self.configure(move |builder| builder.set_max_service_instances_per_peer(max))
+    }
+
+    /// Service instances may only have the given number of open `Stream`s.
+    pub fn max_streams_per_service_instance(self, max: usize) -> Self {
+        self.configure(move |builder| builder.set_max_streams_per_service_instance(max))
+    }
+
+    /// The instances of the given service are closed after the given idle timeout.
+    pub fn idle_timeout(self, service: &'static str, timeout: Duration) -> Self {
+        self.configure(move |builder| builder.set_service_idle_timeout(service, timeout))
+    }
+
+    /// Configure the `PeerBuilder`, after the services were registered.
+    pub fn configure<F>(mut self, configure: F) -> Self
+    where
+        F: FnOnce(PeerBuilder) -> PeerBuilder + 'static,
+    {
+        self.configure.push(Box::new(configure));
+        self
+    }
+
+    /// Start the peer.
+    /// bearer_port - The port of the bearer.
+    pub fn start(self, bearer_port: u16, executor: TaskExecutor) {
+        let builder = peer_builder(bearer_port, executor.clone());
+
+        let builder = match self.builtin_services {
+            Some(config) => builtin_services::register_with_config(builder, config),
+            None => {
+                let builder = builder
+                    .register_service_with_acl(
+                        TestService::new(self.stream_num, 0, self.send_data),
+                        self.acl,
+                    )
+                    .register_service(AddService)
+                    .register_service(ContextService);
+                builtin_services::register(builder)
+            }
+        };
+        let builder = match self.slow_start {
+            Some(delay) => builder.register_service(SlowService { delay }),
+            None => builder,
+        };
+        let builder = if self.stream_limit_service {
+            builder.register_service(StreamLimitService)
+        } else {
+            builder
+        };
+        let builder = self
+            .configure
+            .into_iter()
+            .fold(builder, |builder, configure| configure(builder));
+
+        let peer = builder.build().unwrap();
+        executor.spawn(peer.map_err(|e| panic!(e)));
+    }
+}
+
+impl Default for PeerOptions {
+    fn default() -> PeerOptions {
+        PeerOptions::new()
+    }
+}
+
+/// The builder of the peer, with its certificate and the bearer as remote peer.
+/// bearer_port - The port of the bearer.
+fn peer_builder(bearer_port: u16, executor: TaskExecutor) -> PeerBuilder {
     let bearer_addr: SocketAddr = ([127, 0, 0, 1], bearer_port).into();
 
     let cert = include_bytes!("../../test_certs/peer.cert.pem");
@@ -63,18 +228,144 @@ pub fn start_peer(stream_num: u16, bearer_port: u16, send_data: bool, executor:
     ))
     .expect("Globbing for bearer certificate authorities(*.pem).");
 
-    let builder = carrier::Peer::builder(executor.clone())
+    carrier::Peer::builder(executor)
         .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
         .set_private_key(key.to_vec(), FileFormat::PEM)
         .set_client_ca_cert_files(peer_ca_vec)
         .set_server_ca_cert_files(bearer_ca_vec)
-        .register_service(TestService::new(stream_num, 0, send_data))
-        .add_remote_peer(bearer_addr);
+        .add_remote_peer(bearer_addr)
+}
+
+/// Build the client peer that connects to the bearer with the lifeline certificate.
+/// bearer_port - The port of the bearer.
+fn build_client_peer(bearer_port: u16, runtime: &mut Runtime) -> carrier::Peer {
+    let bearer_addr: SocketAddr = ([127, 0, 0, 1], bearer_port).into();
+
+    let cert = include_bytes!("../../test_certs/lifeline.cert.pem");
+    let key = include_bytes!("../../test_certs/lifeline.key.pem");
+
+    carrier::Peer::builder(runtime.executor())
+        .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
+        .set_private_key(key.to_vec(), FileFormat::PEM)
+        .add_remote_peer(bearer_addr)
+        .build()
+        .unwrap()
+}
+
+/// Create a certificate and its private key in `PEM` format, signed by the peer CA.
+fn create_client_certificate() -> (Vec<u8>, Vec<u8>) {
+    let ca_cert = X509::from_pem(include_bytes!(
+        "../../test_certs/trusted_peer_cas/peer_ca.pem"
+    ))
+    .expect("Reads peer CA certificate");
+    let ca_key = PKey::private_key_from_pem(include_bytes!(
+        "../../test_certs/trusted_peer_cas/peer_ca.key"
+    ))
+    .expect("Reads peer CA key");
 
-    let builder = carrier::builtin_services::register(builder);
+    let rsa = Rsa::generate(2048).unwrap();
+    let key = PKey::from_rsa(rsa.clone()).unwrap();
 
-    let peer = builder.build().unwrap();
-    executor.spawn(peer.map_err(|e| panic!(e)));
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "OtherClient").unwrap();
+    let name = name.build();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    let serial = serial.to_asn1_integer().unwrap();
+    cert.set_serial_number(&serial).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(ca_cert.subject_name()).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    let not_before = Asn1Time::days_from_now(0).unwrap();
+    let not_after = Asn1Time::days_from_now(1).unwrap();
+    cert.set_not_before(&not_before).unwrap();
+    cert.set_not_after(&not_after).unwrap();
+    cert.sign(&ca_key, MessageDigest::sha256()).unwrap();
+
+    (
+        cert.build().to_pem().unwrap(),
+        rsa.private_key_to_pem().unwrap(),
+    )
+}
+
+/// The public key of the peer that is started by `start_peer`.
+fn peer_key() -> PubKeyHash {
+    let peer_cert = include_bytes!("../../test_certs/peer.cert.pem");
+    PubKeyHash::from_x509_pem(peer_cert, false).expect("Create peer key from peer cert.")
+}
+
+/// The public key of the client peer that is build by `build_client_peer`.
+pub fn client_key() -> PubKeyHash {
+    let cert = include_bytes!("../../test_certs/lifeline.cert.pem");
+    PubKeyHash::from_x509_pem(cert, false).expect("Create client key from lifeline cert.")
+}
+
+/// Run `f` again, while it fails with `Error::PeerNotFound`.
+/// The peer is not known by the bearer, until it finished connecting to it.
+fn retry_peer_not_found<T, F>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    for _ in 0..3 {
+        match f() {
+            Err(Error::PeerNotFound(_)) => {
+                // Sleep and retry to connect to the peer afterwards
+                thread::sleep(Duration::from_secs(5));
+            }
+            res => return res,
+        }
+    }
+
+    panic!("Could not find requested peer");
+}
+
+/// Connect to the peer that is started by `start_peer`.
+/// Returns the client peer, that needs to be kept alive while the connection is used.
+fn connect(bearer_port: u16, runtime: &mut Runtime) -> (carrier::Peer, RemotePeer) {
+    let peer = build_client_peer(bearer_port, runtime);
+    connect_peer(peer, runtime)
+}
+
+/// Connect the given client peer to the peer that is started by `start_peer`.
+/// Returns the client peer, that needs to be kept alive while the connection is used.
+fn connect_peer(mut peer: carrier::Peer, runtime: &mut Runtime) -> (carrier::Peer, RemotePeer) {
+    let peer_key = peer_key();
+
+    let remote_peer = retry_peer_not_found(|| runtime.block_on(peer.connect(peer_key.clone())))
+        .unwrap_or_else(|e| panic!(e));
+
+    (peer, remote_peer)
+}
+
+/// Start an instance of the `AddService` that keeps running, as long as the returned handle exists.
+fn start_running_add_service(
+    peer: &mut carrier::Peer,
+    runtime: &mut Runtime,
+) -> RpcHandle<(u64, u64), u64> {
+    let peer_key = peer_key();
+
+    retry_peer_not_found(|| {
+        let (client, handle) = RpcClient::<(u64, u64), u64>::new(AddService::NAME);
+        let call = handle.call((1, 1));
+
+        let service = peer
+            .run_service(client, peer_key.clone())
+            .select2(call)
+            .map_err(|e| e.split().0)
+            .map(|res| match res {
+                Either::A(_) => panic!("Service finished before the call"),
+                Either::B((_, service)) => {
+                    tokio::spawn(service.map_err(|_| ()));
+                }
+            });
+
+        runtime.block_on(service).map(|_| handle)
+    })
+    .expect("Starts service instance")
 }
 
 /// Run the client.
@@ -89,58 +380,31 @@ pub fn run_client_with_test(
 ) {
     let total_stream_num = (stream_num + remote_stream_num - 1) as usize;
 
-    let bearer_addr: SocketAddr = ([127, 0, 0, 1], bearer_port).into();
-
-    let cert = include_bytes!("../../test_certs/lifeline.cert.pem");
-    let key = include_bytes!("../../test_certs/lifeline.key.pem");
-
-    let peer_cert = include_bytes!("../../test_certs/peer.cert.pem");
-    let peer_key =
-        PubKeyHash::from_x509_pem(peer_cert, false).expect("Create peer key from peer cert.");
+    let peer_key = peer_key();
     println!("PEER: {}", peer_key);
 
-    let builder = carrier::Peer::builder(runtime.executor())
-        .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
-        .set_private_key(key.to_vec(), FileFormat::PEM)
-        .add_remote_peer(bearer_addr);
+    let mut peer = build_client_peer(bearer_port, runtime);
 
-    let mut peer = builder.build().unwrap();
-
-    for _ in 0..3 {
-        let res = runtime.block_on(
+    let data = retry_peer_not_found(|| {
+        runtime.block_on(
             peer.run_service(
                 TestService::new(stream_num, total_stream_num, false),
                 peer_key.clone(),
             )
             .flatten(),
-        );
+        )
+    })
+    .unwrap_or_else(|e| panic!(e));
 
-        let data = match res {
-            Ok(data) => data,
-            Err(e) => match e {
-                Error::PeerNotFound(_) => {
-                    // Sleep and retry to connect to the peer afterwards
-                    thread::sleep(Duration::from_secs(5));
-                    continue;
-                }
-                e @ _ => panic!(e),
-            },
-        };
-
-        assert_eq!(
-            TEST_SERVICE_DATA
-                .iter()
-                .cloned()
-                .cycle()
-                .take(TEST_SERVICE_DATA.len() * total_stream_num)
-                .collect::<Vec<_>>(),
-            data
-        );
-
-        return;
-    }
-
-    panic!("Could not find requested peer!");
+    assert_eq!(
+        TEST_SERVICE_DATA
+            .iter()
+            .cloned()
+            .cycle()
+            .take(TEST_SERVICE_DATA.len() * total_stream_num)
+            .collect::<Vec<_>>(),
+        data
+    );
 }
 
 pub fn run_client(
@@ -151,47 +415,886 @@ pub fn run_client(
 ) -> (carrier::Peer, impl Future<Item = Vec<u8>, Error = Error>) {
     let total_stream_num = (stream_num + remote_stream_num - 1) as usize;
 
+    let peer_key = peer_key();
+    println!("PEER: {}", peer_key);
+
+    let mut peer = build_client_peer(bearer_port, runtime);
+
+    let service = retry_peer_not_found(|| {
+        runtime.block_on(peer.run_service(
+            TestService::new(stream_num, total_stream_num, false),
+            peer_key.clone(),
+        ))
+    })
+    .unwrap_or_else(|e| panic!(e));
+
+    (peer, service)
+}
+
+/// Request the services that are offered by the peer.
+/// bearer_port - The port of the bearer.
+pub fn list_services(bearer_port: u16, runtime: &mut Runtime) -> Vec<ServiceDescriptor> {
+    let peer_key = peer_key();
+    let mut peer = build_client_peer(bearer_port, runtime);
+
+    retry_peer_not_found(|| runtime.block_on(peer.list_services(peer_key.clone())))
+        .unwrap_or_else(|e| panic!(e))
+}
+
+/// Call the `AddService` of the peer with concurrent requests.
+/// Returns the responses of the calls.
+/// bearer_port - The port of the bearer.
+pub fn run_rpc_client(bearer_port: u16, runtime: &mut Runtime) -> Vec<u64> {
+    let peer_key = peer_key();
+    let mut peer = build_client_peer(bearer_port, runtime);
+
+    retry_peer_not_found(|| {
+        let (client, handle) = RpcClient::new(AddService::NAME);
+        let calls = future::join_all((0..5).map(|i| handle.call((i, i))).collect::<Vec<_>>());
+        // The client finishes, when all calls are answered.
+        drop(handle);
+
+        runtime.block_on(peer.run_service(client, peer_key.clone()).join(calls))
+    })
+    .map(|(_, responses)| responses)
+    .unwrap_or_else(|e| panic!(e))
+}
+
+/// Call the `ContextService` of the peer.
+/// Returns the remote peer, the remote address and the subject of the remote certificate that
+/// the service found in its `ServiceContext`.
+/// bearer_port - The port of the bearer.
+pub fn call_context_service(
+    bearer_port: u16,
+    runtime: &mut Runtime,
+) -> (String, SocketAddr, Option<String>) {
+    let peer_key = peer_key();
+    let mut peer = build_client_peer(bearer_port, runtime);
+
+    retry_peer_not_found(|| {
+        let (client, handle) = RpcClient::new(ContextService::NAME);
+        let call = handle.call(());
+        drop(handle);
+
+        runtime.block_on(peer.run_service(client, peer_key.clone()).join(call))
+    })
+    .map(|(_, context)| context)
+    .unwrap_or_else(|e| panic!(e))
+}
+
+/// Connect to the peer and run the `AddService` twice over the same connection.
+/// Returns the services offered by the peer and the responses of the calls.
+/// bearer_port - The port of the bearer.
+pub fn run_services_over_connection(
+    bearer_port: u16,
+    runtime: &mut Runtime,
+) -> (Vec<ServiceDescriptor>, Vec<u64>) {
+    let (_peer, mut remote_peer) = connect(bearer_port, runtime);
+
+    let services = runtime
+        .block_on(remote_peer.list_services())
+        .expect("Lists services");
+
+    let mut responses = Vec::new();
+    for i in 0..2 {
+        let (client, handle) = RpcClient::new(AddService::NAME);
+        let call = handle.call((i, 1));
+        drop(handle);
+
+        let (_, response) = runtime
+            .block_on(remote_peer.run_service(client).join(call))
+            .expect("Runs service over connection");
+        responses.push(response);
+    }
+
+    assert!(remote_peer.is_alive());
+    (services, responses)
+}
+
+/// Shut down a peer, while one of its service instances is still running.
+/// Returns the time the shutdown took.
+/// bearer_port - The port of the bearer.
+/// timeout - The shutdown timeout.
+pub fn shutdown_peer_with_running_service(
+    bearer_port: u16,
+    timeout: Duration,
+    runtime: &mut Runtime,
+) -> Duration {
+    let mut peer = build_client_peer(bearer_port, runtime);
+    let handle = start_running_add_service(&mut peer, runtime);
+
+    let start = Instant::now();
+    runtime
+        .block_on(peer.shutdown(timeout))
+        .expect("Shuts down peer");
+    drop(handle);
+
+    start.elapsed()
+}
+
+/// Shut down a client peer, while its service instance is finishing.
+/// Returns the time the shutdown took.
+/// bearer_port - The port of the bearer.
+pub fn shutdown_peer_with_finishing_service(
+    bearer_port: u16,
+    timeout: Duration,
+    runtime: &mut Runtime,
+) -> Duration {
+    let mut peer = build_client_peer(bearer_port, runtime);
+    let handle = start_running_add_service(&mut peer, runtime);
+
+    let start = Instant::now();
+    let shutdown = peer.shutdown(timeout);
+    // The service instance finishes, after its last handle is dropped.
+    drop(handle);
+    runtime.block_on(shutdown).expect("Shuts down peer");
+
+    start.elapsed()
+}
+
+/// Shut down a client peer without service instances.
+/// Returns the time the shutdown took.
+/// bearer_port - The port of the bearer.
+pub fn shutdown_peer_without_service(
+    bearer_port: u16,
+    timeout: Duration,
+    runtime: &mut Runtime,
+) -> Duration {
+    let peer = build_client_peer(bearer_port, runtime);
+
+    let start = Instant::now();
+    runtime
+        .block_on(peer.shutdown(timeout))
+        .expect("Shuts down peer");
+
+    start.elapsed()
+}
+
+/// Start a service instance at a peer that is shutting down.
+/// The peer keeps another service instance running, so that it does not finish its shutdown.
+/// Returns the error of the start.
+/// bearer_port - The port of the bearer.
+pub fn run_service_while_peer_shuts_down(bearer_port: u16, runtime: &mut Runtime) -> Error {
+    let server = peer_builder(bearer_port, runtime.executor())
+        .register_service(AddService)
+        .build()
+        .unwrap();
+
+    let mut peer = build_client_peer(bearer_port, runtime);
+    let handle = start_running_add_service(&mut peer, runtime);
+
+    let shutdown = server.shutdown(Duration::from_secs(30));
+
+    let (client, _second_handle) = RpcClient::<(u64, u64), u64>::new(AddService::NAME);
+    let res = runtime.block_on(peer.run_service(client, peer_key()));
+    drop(handle);
+    runtime.block_on(shutdown).expect("Shuts down peer");
+
+    match res {
+        Ok(_) => panic!("Service instance was started while the peer shuts down"),
+        Err(e) => e,
+    }
+}
+
+/// Start a second service instance, while the first one is still running.
+/// Returns the error of the second start.
+/// bearer_port - The port of the bearer.
+pub fn run_second_service_instance(bearer_port: u16, runtime: &mut Runtime) -> Error {
+    let mut peer = build_client_peer(bearer_port, runtime);
+    let handle = start_running_add_service(&mut peer, runtime);
+
+    let (client, _second_handle) = RpcClient::<(u64, u64), u64>::new(AddService::NAME);
+    let res = runtime.block_on(peer.run_service(client, peer_key()));
+    drop(handle);
+
+    match res {
+        Ok(_) => panic!("Second service instance was started"),
+        Err(e) => e,
+    }
+}
+
+/// Run an `RpcClient` that stays idle after its first call.
+/// Returns the error the client fails with.
+/// bearer_port - The port of the bearer.
+pub fn run_idle_rpc_client(bearer_port: u16, runtime: &mut Runtime) -> Error {
+    let peer_key = peer_key();
+    let mut peer = build_client_peer(bearer_port, runtime);
+
+    let res = retry_peer_not_found(|| {
+        // The service instance keeps running, as long as the handle exists.
+        let (client, handle) = RpcClient::<(u64, u64), u64>::new(AddService::NAME);
+        let call = handle.call((1, 1));
+
+        runtime.block_on(peer.run_service(client, peer_key.clone()).join(call))
+    });
+
+    match res {
+        Ok(_) => panic!("Idle service instance was not closed"),
+        Err(e) => e,
+    }
+}
+
+/// Call the `AddService`, while the start of the `SlowService` blocks.
+/// Returns the time the call took.
+/// bearer_port - The port of the bearer.
+pub fn call_service_during_slow_start(bearer_port: u16, runtime: &mut Runtime) -> Duration {
+    let (_peer, mut remote_peer) = connect(bearer_port, runtime);
+
+    runtime.spawn(
+        remote_peer
+            .run_service(SlowService {
+                delay: Duration::from_secs(0),
+            })
+            .map_err(|e| panic!(e)),
+    );
+    // Give the peer time to enter `Server::start` of the `SlowService`.
+    thread::sleep(Duration::from_millis(500));
+
+    let (client, handle) = RpcClient::<(u64, u64), u64>::new(AddService::NAME);
+    let call = handle.call((1, 1));
+    drop(handle);
+
+    let start = Instant::now();
+    let (_, response) = runtime
+        .block_on(remote_peer.run_service(client).join(call))
+        .expect("Calls service during slow start");
+    assert_eq!(2, response);
+
+    start.elapsed()
+}
+
+/// Connect to the peer that is started by `start_peer` directly over `hole_punch`, like a peer
+/// that does not use carrier. The `Stream`s that are opened with the returned handle start
+/// without a `Hello`.
+/// bearer_port - The port of the bearer.
+/// certificate - The certificate and private key of the raw peer in `PEM` format.
+fn connect_raw(
+    bearer_port: u16,
+    (cert, key): (Vec<u8>, Vec<u8>),
+    runtime: &mut Runtime,
+) -> CreateConnectionToPeerHandle {
     let bearer_addr: SocketAddr = ([127, 0, 0, 1], bearer_port).into();
+    let private_key = PKey::private_key_from_pem(&key).expect("Reads private key");
 
-    let cert = include_bytes!("../../test_certs/lifeline.cert.pem");
-    let key = include_bytes!("../../test_certs/lifeline.key.pem");
+    let config = hole_punch::Config::builder()
+        .set_certificate_chain(vec![cert], FileFormat::PEM)
+        .set_private_key(key, FileFormat::PEM)
+        .add_remote_peer(bearer_addr)
+        .enable_mdns("carrier")
+        .build()
+        .expect("Builds hole_punch config");
+    let context = hole_punch::Context::new(
+        PubKeyHash::from_private_key(private_key, true).expect("Creates raw peer key"),
+        runtime.executor(),
+        config,
+    )
+    .expect("Creates hole_punch context");
 
-    let peer_cert = include_bytes!("../../test_certs/peer.cert.pem");
-    let peer_key =
-        PubKeyHash::from_x509_pem(peer_cert, false).expect("Create peer key from peer cert.");
-    println!("PEER: {}", peer_key);
+    let handle = context.create_connection_to_peer_handle();
+    // Incoming `Stream`s are not expected and dropped.
+    runtime.spawn(
+        context
+            .for_each(|_| Ok(()))
+            .map_err(|e| panic!("Raw peer failed: {:?}", e)),
+    );
+    handle
+}
 
-    let builder = carrier::Peer::builder(runtime.executor())
-        .set_certificate_chain(vec![cert.to_vec()], FileFormat::PEM)
-        .set_private_key(key.to_vec(), FileFormat::PEM)
-        .add_remote_peer(bearer_addr);
+/// The certificate and private key of the client peer that is build by `build_client_peer`.
+fn client_certificate() -> (Vec<u8>, Vec<u8>) {
+    (
+        include_bytes!("../../test_certs/lifeline.cert.pem").to_vec(),
+        include_bytes!("../../test_certs/lifeline.key.pem").to_vec(),
+    )
+}
 
-    let mut peer = builder.build().unwrap();
+/// Open a new `Stream` to the peer that is started by `start_peer`.
+fn open_raw_stream(
+    handle: &mut CreateConnectionToPeerHandle,
+    runtime: &mut Runtime,
+) -> hole_punch::ProtocolStream<serde_json::Value> {
+    retry_peer_not_found(|| {
+        runtime.block_on(
+            handle
+                .create_connection_to_peer(peer_key())
+                .map_err(Error::from),
+        )
+    })
+    .map(Into::into)
+    .expect("Opens raw stream")
+}
 
-    for _ in 0..3 {
-        let service = runtime.block_on(peer.run_service(
-            TestService::new(stream_num, total_stream_num, false),
-            peer_key.clone(),
-        ));
-
-        let service = match service {
-            Ok(data) => data,
-            Err(e) => match e {
-                Error::PeerNotFound(_) => {
-                    // Sleep and retry to connect to the peer afterwards
-                    thread::sleep(Duration::from_secs(5));
-                    continue;
-                }
-                e @ _ => panic!(e),
-            },
-        };
+/// Send the given request as first message on a new stream, like a peer that speaks version 1
+/// of the protocol and does not send a `Hello`.
+/// Returns the answer of the peer.
+/// bearer_port - The port of the bearer.
+pub fn send_legacy_request(
+    bearer_port: u16,
+    request: serde_json::Value,
+    runtime: &mut Runtime,
+) -> Option<serde_json::Value> {
+    let mut handle = connect_raw(bearer_port, client_certificate(), runtime);
+
+    let answer = open_raw_stream(&mut handle, runtime)
+        .send(request)
+        .and_then(|stream| stream.into_future().map_err(|e| e.0))
+        .map(|(answer, _)| answer);
+
+    runtime.block_on(answer).expect("Sends legacy request")
+}
+
+/// Open a raw `Stream` to the peer and exchange the `Hello`s.
+fn open_hello_stream(
+    handle: &mut CreateConnectionToPeerHandle,
+    runtime: &mut Runtime,
+) -> hole_punch::ProtocolStream<serde_json::Value> {
+    let hello =
+        json!({"Hello": {"version": {"min": 2, "max": 2}, "features": ["service_timeouts"]}});
+
+    let stream = open_raw_stream(handle, runtime)
+        .send(hello)
+        .and_then(|stream| stream.into_future().map_err(|e| e.0))
+        .map(|(answer, stream)| {
+            assert!(
+                answer.as_ref().and_then(|a| a.get("Hello")).is_some(),
+                "Unexpected answer: {:?}",
+                answer
+            );
+            stream
+        });
+
+    runtime.block_on(stream).expect("Exchanges hello")
+}
+
+/// Send the request on a new `Stream`, after the `Hello`s were exchanged.
+/// Returns the answer and the `Stream`, that needs to be kept alive for a started service
+/// instance.
+fn send_request(
+    handle: &mut CreateConnectionToPeerHandle,
+    request: serde_json::Value,
+    runtime: &mut Runtime,
+) -> (
+    Option<serde_json::Value>,
+    hole_punch::ProtocolStream<serde_json::Value>,
+) {
+    let answer = open_hello_stream(handle, runtime)
+        .send(request)
+        .and_then(|stream| stream.into_future().map_err(|e| e.0));
+
+    runtime.block_on(answer).expect("Sends request")
+}
+
+/// Start an instance of the `AddService` and access it from another client peer.
+/// The other peer sends `ConnectToService`, `Keepalive` and `CloseServiceInstance` for the
+/// instance, the peer that started it sends `Keepalive` and `ConnectToService` afterwards.
+/// Returns the answers to the other peer and to the peer that started the instance.
+/// bearer_port - The port of the bearer.
+pub fn access_service_instance_from_other_peer(
+    bearer_port: u16,
+    runtime: &mut Runtime,
+) -> (
+    Vec<Option<serde_json::Value>>,
+    Vec<Option<serde_json::Value>>,
+) {
+    let mut owner = connect_raw(bearer_port, client_certificate(), runtime);
+
+    let request = json!({"RequestServiceStart": {"name": AddService::NAME, "local_id": 1}});
+    let (answer, _instance) = send_request(&mut owner, request, runtime);
+    let id = answer
+        .as_ref()
+        .and_then(|a| a["ServiceStarted"]["id"].as_u64())
+        .expect("Starts service instance");
+
+    let connect_to_service = json!({"ConnectToService": {"id": id}});
+    let keepalive = json!({"Keepalive": {"id": id}});
+
+    let mut other = connect_raw(bearer_port, create_client_certificate(), runtime);
+    let other_answers = vec![
+        send_request(&mut other, connect_to_service.clone(), runtime).0,
+        send_request(&mut other, keepalive.clone(), runtime).0,
+    ];
+
+    // `CloseServiceInstance` is not answered, give the peer some time to handle it.
+    let close = open_hello_stream(&mut other, runtime)
+        .send(json!({"CloseServiceInstance": {"id": id, "reason": "Idle"}}));
+    let _close = runtime.block_on(close).expect("Sends close");
+    thread::sleep(Duration::from_secs(1));
+
+    let owner_answers = vec![
+        send_request(&mut owner, keepalive, runtime).0,
+        send_request(&mut owner, connect_to_service, runtime).0,
+    ];
 
-        return (peer, service);
+    (other_answers, owner_answers)
+}
+
+/// Request a service that the peer does not offer.
+/// Returns the error of the request.
+/// bearer_port - The port of the bearer.
+pub fn run_unknown_service(bearer_port: u16, runtime: &mut Runtime) -> Error {
+    run_client_expect_error(UnknownService, bearer_port, runtime)
+}
+
+/// Request the test service from a peer that does not allow the client to start it.
+/// Returns the error of the request.
+/// bearer_port - The port of the bearer.
+pub fn run_unauthorized_client(bearer_port: u16, runtime: &mut Runtime) -> Error {
+    run_client_expect_error(TestService::new(1, 1, false), bearer_port, runtime)
+}
+
+/// Run the given service at the peer.
+/// Returns the result of the client.
+/// bearer_port - The port of the bearer.
+pub fn run_service<C>(
+    service: C,
+    bearer_port: u16,
+    runtime: &mut Runtime,
+) -> Result<<C::Future as Future>::Item>
+where
+    C: Client<Error = Error> + 'static,
+    <C::Future as Future>::Item: Send + 'static,
+{
+    let (_peer, mut remote_peer) = connect(bearer_port, runtime);
+    runtime.block_on(remote_peer.run_service(service))
+}
+
+/// Spawn the given service at the peer, the client runs in the background.
+/// Returns the client peer, the client stops when it is dropped.
+/// bearer_port - The port of the bearer.
+pub fn spawn_service<C>(service: C, bearer_port: u16, runtime: &mut Runtime) -> carrier::Peer
+where
+    C: Client<Error = Error> + 'static,
+    <C::Future as Future>::Item: Send + 'static,
+{
+    let (peer, mut remote_peer) = connect(bearer_port, runtime);
+    runtime.spawn(
+        remote_peer
+            .run_service(service)
+            .map(|_| ())
+            .map_err(|e| panic!(e)),
+    );
+    peer
+}
+
+fn run_client_expect_error<C>(service: C, bearer_port: u16, runtime: &mut Runtime) -> Error
+where
+    C: Client<Error = Error> + 'static,
+    <C::Future as Future>::Item: Send + 'static,
+{
+    match run_service(service, bearer_port, runtime) {
+        Ok(_) => panic!("Service was started"),
+        Err(e) => e,
     }
+}
 
-    panic!("Could not find requested peer");
+/// Start a TCP server that sends back everything it receives.
+/// Returns the address the server is listening on.
+pub fn start_tcp_echo_server(executor: TaskExecutor) -> SocketAddr {
+    let listener = TcpListener::bind(&([127, 0, 0, 1], 0).into()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    executor.spawn(listener.incoming().map_err(|e| panic!(e)).for_each(|tcp| {
+        let (read, write) = AsyncRead::split(tcp);
+        tokio::spawn(io::copy(read, write).map(|_| ()).map_err(|_| ()));
+        Ok(())
+    }));
+
+    addr
+}
+
+/// Create an empty directory for the given test.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("carrier-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Creates test directory");
+    dir
+}
+
+/// The public key that verifies the images that are signed by `sign_update_image`.
+pub fn update_public_key() -> PKey<Public> {
+    let cert = include_bytes!("../../test_certs/peer.cert.pem");
+    X509::from_pem(cert)
+        .and_then(|cert| cert.public_key())
+        .expect("Reads public key of peer cert.")
+}
+
+/// Sign the given update image.
+pub fn sign_update_image(image: &[u8]) -> Vec<u8> {
+    let key = include_bytes!("../../test_certs/peer.key.pem");
+    let key = PKey::private_key_from_pem(key).expect("Reads peer key.");
+
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer.update(image).unwrap();
+    signer.sign_to_vec().expect("Signs image")
+}
+
+/// Returns a local address that is currently not used.
+pub fn unused_local_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Send the test data over a new TCP connection to the given address.
+/// Retries to connect, while the address is not listening yet.
+/// Returns the answer of the remote side.
+pub fn send_over_tcp(addr: SocketAddr) -> Vec<u8> {
+    let mut tcp = (0..10)
+        .filter_map(|_| {
+            std::net::TcpStream::connect(addr)
+                .map_err(|_| thread::sleep(Duration::from_millis(500)))
+                .ok()
+        })
+        .next()
+        .expect("Connects to address");
+    tcp.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+    tcp.write_all(TEST_SERVICE_DATA).expect("Sends test data");
+    let mut answer = vec![0; TEST_SERVICE_DATA.len()];
+    tcp.read_exact(&mut answer).expect("Receives answer");
+    answer
 }
 
+/// Start a UDP server that sends back every datagram it receives.
+/// Returns the address the server is listening on.
+pub fn start_udp_echo_server() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok((len, source)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..len], source);
+        }
+    });
+
+    addr
+}
+
+/// Send the test data as datagram to the given address.
+/// Sends the datagram again, while the address does not answer.
+/// Returns the answer of the remote side.
+pub fn send_over_udp(addr: SocketAddr) -> Vec<u8> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+
+    let mut buf = [0; 1024];
+    for _ in 0..20 {
+        socket
+            .send_to(TEST_SERVICE_DATA, addr)
+            .expect("Sends test data");
+
+        if let Ok((len, _)) = socket.recv_from(&mut buf) {
+            return buf[..len].to_vec();
+        }
+    }
+
+    panic!("No answer from {}", addr);
+}
+
+/// A `lifeline` client that sends the test data to the target of the peer.
+/// Resolves to the first answer of the target.
+#[derive(Clone)]
+pub struct LifelineTestClient {
+    /// The target that is requested from the peer.
+    pub target: Option<SocketAddr>,
+}
+
+impl Client for LifelineTestClient {
+    type Error = Error;
+    type Future = Box<SendFuture<Item = Vec<u8>, Error = Error>>;
+    type Args = Option<SocketAddr>;
+
+    fn args(&self) -> Self::Args {
+        self.target
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        Ok(Box::new(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(|(stream, _)| {
+                    stream.ok_or_else(|| Error::from("No `Stream` for lifeline"))
+                })
+                .and_then(|stream| stream.send(TEST_SERVICE_DATA.into()))
+                .and_then(|stream| stream.into_future().map_err(|e| e.0))
+                .map(|(data, _)| data.map(|d| d.to_vec()).unwrap_or_default()),
+        ))
+    }
+
+    fn name(&self) -> &'static str {
+        "lifeline"
+    }
+}
+
+/// A `lifeline` client that stays idle, after it received the first answer of the target.
+/// Resolves to the error the `Stream` failed with, `None` if it was closed without an error.
+/// Fails, if the `Stream` is still open after the given timeout.
+pub struct IdleLifelineTestClient {
+    pub timeout: Duration,
+}
+
+impl Client for IdleLifelineTestClient {
+    type Error = Error;
+    type Future = Box<SendFuture<Item = Option<Error>, Error = Error>>;
+    type Args = Option<SocketAddr>;
+
+    fn args(&self) -> Self::Args {
+        None
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        _: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let session = streams
+            .into_future()
+            .map_err(|e| e.0)
+            .and_then(|(stream, _)| stream.ok_or_else(|| Error::from("No `Stream` for lifeline")))
+            .and_then(|stream| stream.send(TEST_SERVICE_DATA.into()))
+            .and_then(|stream| stream.into_future().map_err(|e| e.0))
+            .and_then(|(_, stream)| stream.for_each(|_| Ok(())).then(|res| Ok(res.err())));
+
+        Ok(Box::new(Timeout::new(session, self.timeout).map_err(
+            |_| Error::from("Idle lifeline session was not closed"),
+        )))
+    }
+
+    fn name(&self) -> &'static str {
+        "lifeline"
+    }
+}
+
+/// A `shell` client that writes the given input to the shell.
+/// Resolves to the first message the server sends over the control `Stream`.
+pub struct ShellTestClient {
+    /// The input of the shell.
+    pub input: &'static [u8],
+}
+
+impl Client for ShellTestClient {
+    type Error = Error;
+    type Future = Box<SendFuture<Item = serde_json::Value, Error = Error>>;
+    type Args = builtin_services::ShellArgs;
+
+    fn args(&self) -> Self::Args {
+        builtin_services::ShellArgs {
+            term: None,
+            size: Default::default(),
+        }
+    }
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        mut new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        let input = self.input;
+        let data = new_stream_handle
+            .new_stream()
+            .and_then(move |stream| stream.send(input.into()));
+
+        Ok(Box::new(
+            streams
+                .into_future()
+                .map_err(|e| e.0)
+                .and_then(|(control, _)| {
+                    control.ok_or_else(|| Error::from("No control `Stream` for shell"))
+                })
+                .join(data)
+                .and_then(|(control, data)| {
+                    let control: ProtocolStream<serde_json::Value> = control.into();
+                    control
+                        .into_future()
+                        .map_err(|e| e.0.into())
+                        .map(move |(msg, _)| {
+                            // The terminal is kept open, until the shell exited.
+                            drop(data);
+                            msg.unwrap_or_default()
+                        })
+                }),
+        ))
+    }
+
+    fn name(&self) -> &'static str {
+        "shell"
+    }
+}
+
+/// A service that is not registered at any peer.
+#[derive(Clone)]
+struct UnknownService;
+
+impl Client for UnknownService {
+    type Error = Error;
+    type Future = FutureResult<(), Error>;
+    type Args = ();
+
+    fn args(&self) -> Self::Args {}
+
+    fn start(self, _: ServiceContext, _: Streams, _: NewStreamHandle) -> Result<Self::Future> {
+        Ok(future::ok(()))
+    }
+
+    fn name(&self) -> &'static str {
+        "unknownservice"
+    }
+}
+
+/// A service that blocks in `Server::start` for the given delay.
+struct SlowService {
+    delay: Duration,
+}
+
+impl SlowService {
+    const NAME: &'static str = "slowservice";
+}
+
+impl Server for SlowService {
+    type Args = ();
+
+    fn start(&mut self, _: ServiceContext, _: Streams, _: NewStreamHandle, _: ()) {
+        thread::sleep(self.delay);
+    }
+
+    fn name(&self) -> &'static str {
+        SlowService::NAME
+    }
+}
+
+impl Client for SlowService {
+    type Error = Error;
+    type Future = FutureResult<(), Error>;
+    type Args = ();
+
+    fn args(&self) -> Self::Args {}
+
+    fn start(self, _: ServiceContext, _: Streams, _: NewStreamHandle) -> Result<Self::Future> {
+        Ok(future::ok(()))
+    }
+
+    fn name(&self) -> &'static str {
+        SlowService::NAME
+    }
+}
+
+/// Keeps every `Stream` open, until the other side closes it.
+/// The client opens `Stream`s up to the maximum of the peer that is started by
+/// `PeerOptions::max_streams_per_service_instance(2)`, checks that the next one is rejected and that a `Stream`
+/// can be opened again after one was closed.
+pub struct StreamLimitService;
+
+impl StreamLimitService {
+    const NAME: &'static str = "streamlimitservice";
+}
+
+impl Server for StreamLimitService {
+    type Args = ();
+
+    fn start(&mut self, _: ServiceContext, streams: Streams, _: NewStreamHandle, _: ()) {
+        tokio::spawn(
+            streams
+                .for_each(|stream| {
+                    tokio::spawn(stream.for_each(|_| Ok(())).map_err(|_| ()));
+                    Ok(())
+                })
+                .map_err(|e| println!("{:?}", e)),
+        );
+    }
+
+    fn name(&self) -> &'static str {
+        StreamLimitService::NAME
+    }
+}
+
+impl Client for StreamLimitService {
+    type Error = Error;
+    type Future = Box<SendFuture<Item = (), Error = Error>>;
+    type Args = ();
+
+    fn args(&self) -> Self::Args {}
+
+    fn start(
+        self,
+        _: ServiceContext,
+        streams: Streams,
+        mut new_stream_handle: NewStreamHandle,
+    ) -> Result<Self::Future> {
+        // The initial `Stream` is the first one, the second one reaches the maximum.
+        let future = new_stream_handle
+            .new_stream()
+            .and_then(move |second| {
+                new_stream_handle.new_stream().then(move |res| match res {
+                    Err(Error::Busy(_)) => {
+                        drop(second);
+                        Ok(new_stream_handle)
+                    }
+                    Ok(_) => Err("Stream above the maximum was accepted".into()),
+                    Err(e) => Err(e),
+                })
+            })
+            // Give the peer some time to see that the second `Stream` was closed.
+            .and_then(|handle| {
+                Delay::new(Instant::now() + Duration::from_secs(2))
+                    .map_err(|_| Error::from("Delay failed"))
+                    .map(|_| handle)
+            })
+            .and_then(|mut handle| handle.new_stream())
+            .map(move |_| drop(streams));
+
+        Ok(Box::new(future))
+    }
+
+    fn name(&self) -> &'static str {
+        StreamLimitService::NAME
+    }
+}
+
+/// An RPC service that adds two numbers.
+#[derive(Clone)]
+struct AddService;
+
+impl RpcServer for AddService {
+    type Request = (u64, u64);
+    type Response = u64;
+    type Future = FutureResult<u64, Error>;
+    const NAME: &'static str = "addservice";
+
+    fn handle(&mut self, _: &ServiceContext, (a, b): (u64, u64)) -> Self::Future {
+        future::ok(a + b)
+    }
+}
+
+/// An RPC service that answers with the values of its `ServiceContext`.
+#[derive(Clone)]
+struct ContextService;
+
+impl RpcServer for ContextService {
+    type Request = ();
+    type Response = (String, SocketAddr, Option<String>);
+    type Future = FutureResult<Self::Response, Error>;
+    const NAME: &'static str = "contextservice";
+
+    fn handle(&mut self, context: &ServiceContext, _: ()) -> Self::Future {
+        future::ok((
+            context.remote_peer().to_string(),
+            context.remote_addr(),
+            context.remote_subject(),
+        ))
+    }
+}
+
+#[derive(Clone)]
 struct TestService {
     stream_num: u16,
     total_stream_num: usize,
@@ -209,7 +1312,15 @@ impl TestService {
 }
 
 impl Server for TestService {
-    fn start(&mut self, streams: Streams, mut new_stream_handle: NewStreamHandle) {
+    type Args = ();
+
+    fn start(
+        &mut self,
+        _: ServiceContext,
+        streams: Streams,
+        mut new_stream_handle: NewStreamHandle,
+        _: (),
+    ) {
         let new_streams = (1..self.stream_num).map(|_| new_stream_handle.new_stream());
         let send_data = self.send_data;
 
@@ -237,9 +1348,13 @@ impl Server for TestService {
 impl Client for TestService {
     type Error = Error;
     type Future = FutureResult<Box<SendFuture<Item = Vec<u8>, Error = Self::Error>>, Error>;
+    type Args = ();
+
+    fn args(&self) -> Self::Args {}
 
     fn start(
         self,
+        _: ServiceContext,
         streams: Streams,
         mut new_stream_handle: NewStreamHandle,
     ) -> Result<Self::Future> {
@@ -1,9 +1,41 @@
 extern crate carrier;
 extern crate futures;
+extern crate hole_punch;
+extern crate libc;
+extern crate openssl;
+#[macro_use]
+extern crate serde_json;
 extern crate tokio;
 
+use carrier::{
+    builtin_services::{
+        self, ConfigChange, ConfigEntry, ConfigStore, ConfigStoreClient, ConfigStoreResult, Events,
+        EventsClient, Exec, ExecClient, FileTransfer, FileTransferClient, FileTransferResult,
+        Lifeline, LogSource, Logs, LogsClient, PortForward, PortForwardClient, Shell, Telemetry,
+        TelemetryClient, UdpForward, UdpForwardClient, Update, UpdateClient, UpdateStatus,
+    },
+    service::ServiceAcl,
+    Error,
+};
+
+use common::{LifelineTestClient, ShellTestClient};
+
+use futures::{sync::mpsc::unbounded, Stream};
+
 use tokio::runtime::Runtime;
 
+use std::{
+    fs::{self, File},
+    io::Write,
+    os::unix::{
+        fs::{symlink, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+    thread,
+    time::Duration,
+};
+
 mod common;
 
 #[test]
@@ -36,6 +68,83 @@ fn peer_connects_to_peer_with_2_peer_streams() {
     common::run_client_with_test(1, peer_streams, port, &mut runtime);
 }
 
+#[test]
+fn peer_lists_services() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let services = common::list_services(port, &mut runtime)
+        .into_iter()
+        .map(|s| s.name)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "addservice",
+            "contextservice",
+            "lifeline",
+            "port_forward",
+            "testservice",
+            "udp_forward"
+        ],
+        services
+    );
+}
+
+#[test]
+fn rpc_client_calls_peer() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    assert_eq!(
+        vec![0, 2, 4, 6, 8],
+        common::run_rpc_client(port, &mut runtime)
+    );
+}
+
+#[test]
+fn remote_peer_runs_services_over_one_connection() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let (services, responses) = common::run_services_over_connection(port, &mut runtime);
+    assert!(services.iter().any(|s| s.name == "addservice"));
+    assert_eq!(vec![1, 2], responses);
+}
+
+#[test]
+fn requesting_unknown_service_fails() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    match common::run_unknown_service(port, &mut runtime) {
+        carrier::Error::NotFound(_) => {}
+        e => panic!("Unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn peer_rejects_unauthorized_client() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::new()
+        .test_service_acl(carrier::service::ServiceAcl::deny_all())
+        .start(port, runtime.executor());
+
+    match common::run_unauthorized_client(port, &mut runtime) {
+        carrier::Error::Unauthorized(_) => {}
+        e => panic!("Unexpected error: {:?}", e),
+    }
+}
+
 #[test]
 fn dropping_peer_drops_connection() {
     let mut runtime = Runtime::new().expect("Creates runtime");
@@ -49,3 +158,1023 @@ fn dropping_peer_drops_connection() {
 
     assert_eq!(runtime.block_on(service).unwrap().len(), 0);
 }
+
+#[test]
+fn peer_shutdown_waits_for_running_services() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let timeout = Duration::from_secs(2);
+    let elapsed = common::shutdown_peer_with_running_service(port, timeout, &mut runtime);
+    assert!(elapsed >= timeout);
+}
+
+#[test]
+fn peer_shutdown_returns_when_services_finished() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let timeout = Duration::from_secs(10);
+    let elapsed = common::shutdown_peer_without_service(port, timeout, &mut runtime);
+    assert!(elapsed < timeout);
+
+    let elapsed = common::shutdown_peer_with_finishing_service(port, timeout, &mut runtime);
+    assert!(elapsed < timeout);
+}
+
+#[test]
+fn peer_rejects_service_instances_while_shutting_down() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+
+    match common::run_service_while_peer_shuts_down(port, &mut runtime) {
+        carrier::Error::Busy(_) => {}
+        e => panic!("Unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn peer_rejects_service_instances_above_peer_limit() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::new()
+        .max_instances_per_peer(1)
+        .start(port, runtime.executor());
+
+    match common::run_second_service_instance(port, &mut runtime) {
+        carrier::Error::Busy(_) => {}
+        e => panic!("Unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn lifeline_session_counts_against_peer_limit() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let config = builtin_services::Config::new().set_lifeline(Lifeline::new().set_target(echo));
+    common::PeerOptions::builtin_services(config)
+        .max_instances_per_peer(1)
+        .start(port, runtime.executor());
+
+    // The lifeline drops its `Streams` after the first `Stream`, the session still counts.
+    let _peer = common::spawn_service(
+        common::IdleLifelineTestClient {
+            timeout: Duration::from_secs(30),
+        },
+        port,
+        &mut runtime,
+    );
+    // Give the session time to start.
+    thread::sleep(Duration::from_secs(1));
+
+    let second = LifelineTestClient { target: None };
+    match common::run_service(second, port, &mut runtime) {
+        Err(Error::Busy(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn peer_rejects_streams_above_instance_limit() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::new()
+        .stream_limit_service()
+        .max_streams_per_service_instance(2)
+        .start(port, runtime.executor());
+
+    common::run_service(common::StreamLimitService, port, &mut runtime)
+        .expect("Rejects the stream above the limit and accepts it after one was closed");
+}
+
+#[test]
+fn idle_service_instance_is_closed() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::new()
+        .idle_timeout("addservice", Duration::from_secs(2))
+        .start(port, runtime.executor());
+
+    match common::run_idle_rpc_client(port, &mut runtime) {
+        carrier::Error::ServiceClosed(carrier::service::CloseReason::Idle) => {}
+        e => panic!("Unexpected error: {:?}", e),
+    }
+}
+
+#[test]
+fn slow_service_start_does_not_block_other_services() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let delay = Duration::from_secs(5);
+    common::PeerOptions::new()
+        .slow_service(delay)
+        .start(port, runtime.executor());
+
+    let elapsed = common::call_service_during_slow_start(port, &mut runtime);
+    assert!(elapsed < delay);
+}
+
+#[test]
+fn peer_accepts_legacy_requests_without_hello() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let request = json!({"RequestServiceStart": {"name": "addservice", "local_id": 1}});
+    let answer = common::send_legacy_request(port, request, &mut runtime).expect("Answers request");
+    assert!(
+        answer.get("ServiceStarted").is_some(),
+        "Unexpected answer: {}",
+        answer
+    );
+}
+
+#[test]
+fn legacy_peer_receives_service_not_found() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let request = json!({"RequestServiceStart": {"name": "unknownservice", "local_id": 1}});
+    let answer = common::send_legacy_request(port, request, &mut runtime);
+    assert_eq!(Some(json!("ServiceNotFound")), answer);
+
+    let request = json!({"ConnectToService": {"id": 1}});
+    let answer = common::send_legacy_request(port, request, &mut runtime);
+    assert_eq!(Some(json!("ServiceNotFound")), answer);
+}
+
+#[test]
+fn service_instance_is_only_accessible_by_its_remote_peer() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let (other, owner) = common::access_service_instance_from_other_peer(port, &mut runtime);
+    for answer in other {
+        assert_eq!(
+            Some("NotFound"),
+            answer.as_ref().and_then(|a| a["Error"]["code"].as_str()),
+            "Unexpected answer: {:?}",
+            answer
+        );
+    }
+    assert_eq!(
+        vec![Some(json!("KeepaliveAck")), Some(json!("ServiceConnected"))],
+        owner
+    );
+}
+
+#[test]
+fn service_receives_context_of_remote_peer() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::start_peer(1, port, true, runtime.executor());
+
+    let (remote_peer, remote_addr, subject) = common::call_context_service(port, &mut runtime);
+    assert_eq!(common::client_key().to_string(), remote_peer);
+    assert!(remote_addr.ip().is_loopback());
+    assert_eq!(
+        Some("C=DE, ST=DE, L=Berlin, O=LifelineCarrier, OU=LifelineCarrier, CN=Lifeline".into()),
+        subject
+    );
+}
+
+#[test]
+fn lifeline_connects_to_target() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let config = builtin_services::Config::new().set_lifeline(Lifeline::new().set_target(echo));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let data = common::run_service(LifelineTestClient { target: None }, port, &mut runtime)
+        .expect("Runs lifeline");
+    assert_eq!(common::TEST_SERVICE_DATA, &data[..]);
+}
+
+#[test]
+fn idle_lifeline_session_is_closed() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let config = builtin_services::Config::new().set_lifeline(Lifeline::new().set_target(echo));
+    common::PeerOptions::builtin_services(config)
+        .idle_timeout("lifeline", Duration::from_secs(2))
+        .start(port, runtime.executor());
+
+    // The `Stream` is either closed by the peer or fails with the close reason, depending on
+    // what arrives first.
+    let client = common::IdleLifelineTestClient {
+        timeout: Duration::from_secs(10),
+    };
+    match common::run_service(client, port, &mut runtime) {
+        Ok(None) | Ok(Some(Error::ServiceClosed(carrier::service::CloseReason::Idle))) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn lifeline_only_connects_to_allowed_targets() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let lifeline = Lifeline::new().allow_target(echo);
+    let config = builtin_services::Config::new().set_lifeline(lifeline);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let allowed = LifelineTestClient { target: Some(echo) };
+    let data = common::run_service(allowed, port, &mut runtime).expect("Runs lifeline");
+    assert_eq!(common::TEST_SERVICE_DATA, &data[..]);
+
+    let denied = LifelineTestClient {
+        target: Some(([127, 0, 0, 1], 1).into()),
+    };
+    match common::run_service(denied, port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn port_forward_forwards_local_connections_to_target() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let config =
+        builtin_services::Config::new().set_port_forward(PortForward::new().allow_target(echo));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let listen = common::unused_local_addr();
+    let _peer = common::spawn_service(PortForwardClient::local(listen, echo), port, &mut runtime);
+    assert_eq!(
+        common::TEST_SERVICE_DATA,
+        &common::send_over_tcp(listen)[..]
+    );
+}
+
+#[test]
+fn port_forward_forwards_remote_connections_to_target() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let bind = common::unused_local_addr();
+    let config =
+        builtin_services::Config::new().set_port_forward(PortForward::new().allow_bind(bind));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let _peer = common::spawn_service(PortForwardClient::remote(bind, echo), port, &mut runtime);
+    assert_eq!(common::TEST_SERVICE_DATA, &common::send_over_tcp(bind)[..]);
+}
+
+#[test]
+fn port_forward_rejects_disallowed_addresses() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    common::PeerOptions::builtin_services(builtin_services::Config::new())
+        .start(port, runtime.executor());
+
+    let listen = common::unused_local_addr();
+    match common::run_service(PortForwardClient::local(listen, echo), port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let bind = common::unused_local_addr();
+    match common::run_service(PortForwardClient::remote(bind, echo), port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn port_forward_reports_bind_failure() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let used = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let bind = used.local_addr().unwrap();
+    let config =
+        builtin_services::Config::new().set_port_forward(PortForward::new().allow_bind(bind));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    match common::run_service(PortForwardClient::remote(bind, echo), port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn port_forward_does_not_listen_for_rejected_starts() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_tcp_echo_server(runtime.executor());
+    let bind = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config =
+        builtin_services::Config::new().set_port_forward(PortForward::new().allow_bind(bind));
+    common::PeerOptions::builtin_services(config)
+        .configure(|builder| builder.set_max_service_instances(0))
+        .start(port, runtime.executor());
+
+    match common::run_service(PortForwardClient::remote(bind, echo), port, &mut runtime) {
+        Err(Error::Busy(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    std::net::TcpListener::bind(bind).expect("Address is not used by the peer");
+}
+
+#[test]
+fn udp_forward_forwards_datagrams_to_target() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_udp_echo_server();
+    let config =
+        builtin_services::Config::new().set_udp_forward(UdpForward::new().allow_target(echo));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let listen = common::unused_local_addr();
+    let client = UdpForwardClient::new(listen, echo).set_idle_timeout(Duration::from_secs(1));
+    let _peer = common::spawn_service(client, port, &mut runtime);
+    assert_eq!(
+        common::TEST_SERVICE_DATA,
+        &common::send_over_udp(listen)[..]
+    );
+
+    // A new source after the idle timeout replaces the idle one.
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(
+        common::TEST_SERVICE_DATA,
+        &common::send_over_udp(listen)[..]
+    );
+}
+
+#[test]
+fn udp_forward_rejects_disallowed_target() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let echo = common::start_udp_echo_server();
+    common::PeerOptions::builtin_services(builtin_services::Config::new())
+        .start(port, runtime.executor());
+
+    let listen = common::unused_local_addr();
+    match common::run_service(UdpForwardClient::new(listen, echo), port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn exec_runs_allowed_program() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let exec = Exec::new()
+        .allow_program("sh")
+        .allow_env("CARRIER_TEST")
+        .set_working_dir(env!("CARGO_MANIFEST_DIR"));
+    let config = builtin_services::Config::new().enable_exec(exec);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    // The process only receives the allowed variables and runs in the requested directory.
+    let client = ExecClient::new(vec![
+        "sh",
+        "-c",
+        "test \"$CARRIER_TEST\" = value && test -z \"$CARGO_MANIFEST_DIR\" && test -f lib.rs",
+    ])
+    .env("CARRIER_TEST", "value")
+    .current_dir("src");
+    let status = common::run_service(client, port, &mut runtime).expect("Runs exec");
+    assert!(status.success(), "Unexpected status: {:?}", status);
+}
+
+#[test]
+fn exec_rejects_disallowed_arguments() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let exec = Exec::new()
+        .allow_program("true")
+        .set_working_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src"));
+    let config = builtin_services::Config::new().enable_exec(exec);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let clients = vec![
+        ExecClient::new(vec!["false"]),
+        ExecClient::new(vec!["true"]).env("PATH", "/tmp"),
+        ExecClient::new(vec!["true"]).env("LD_PRELOAD", "/tmp/preload.so"),
+        ExecClient::new(vec!["true"]).current_dir(".."),
+        ExecClient::new(vec!["true"]).current_dir("/"),
+    ];
+
+    for client in clients {
+        match common::run_service(client, port, &mut runtime) {
+            Err(Error::BadArguments(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
+
+#[test]
+fn shell_runs_input_and_sends_exit_status() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_shell(Shell::new().set_shell("/bin/sh"));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let client = ShellTestClient { input: b"exit 3\n" };
+    let exited = common::run_service(client, port, &mut runtime).expect("Runs shell");
+    assert_eq!(json!({"Exited": {"code": 3, "signal": null}}), exited);
+}
+
+#[test]
+fn shell_is_only_started_when_enabled_and_allowed() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::builtin_services(builtin_services::Config::new())
+        .start(port, runtime.executor());
+
+    match common::run_service(ShellTestClient { input: b"exit\n" }, port, &mut runtime) {
+        Err(Error::NotFound(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new()
+        .enable_shell(Shell::new().set_shell("/bin/sh"))
+        .set_acl("shell", ServiceAcl::deny_all());
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    match common::run_service(ShellTestClient { input: b"exit\n" }, port, &mut runtime) {
+        Err(Error::Unauthorized(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+/// The content of the files that are transferred, larger than one chunk.
+fn file_transfer_data() -> Vec<u8> {
+    (0..200_000u32).map(|i| i as u8).collect()
+}
+
+#[test]
+fn file_transfer_resumes_interrupted_transfers() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let root = common::test_dir("file_transfer_resume_root");
+    let local = common::test_dir("file_transfer_resume_local");
+    let data = file_transfer_data();
+    fs::write(local.join("file"), &data).unwrap();
+    fs::write(root.join("file.part"), &data[..100_000]).unwrap();
+    fs::write(local.join("copy.part"), &data[..50_000]).unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_file_transfer(FileTransfer::new(&root));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let put = FileTransferClient::put(local.join("file"), "file");
+    assert_eq!(
+        FileTransferResult::Transferred { size: 200_000 },
+        common::run_service(put, port, &mut runtime).expect("Uploads file")
+    );
+    assert_eq!(data, fs::read(root.join("file")).unwrap());
+    assert!(!root.join("file.part").exists());
+
+    let get = FileTransferClient::get("file", local.join("copy"));
+    assert_eq!(
+        FileTransferResult::Transferred { size: 200_000 },
+        common::run_service(get, port, &mut runtime).expect("Downloads file")
+    );
+    assert_eq!(data, fs::read(local.join("copy")).unwrap());
+    assert!(!local.join("copy.part").exists());
+}
+
+#[test]
+fn file_transfer_restarts_downloads_with_longer_part_file() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let root = common::test_dir("file_transfer_restart_root");
+    let local = common::test_dir("file_transfer_restart_local");
+    let data = file_transfer_data();
+    fs::write(root.join("file"), &data).unwrap();
+    fs::write(local.join("copy.part"), vec![0xff; 300_000]).unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_file_transfer(FileTransfer::new(&root));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let get = FileTransferClient::get("file", local.join("copy"));
+    assert_eq!(
+        FileTransferResult::Transferred { size: 200_000 },
+        common::run_service(get, port, &mut runtime).expect("Downloads file")
+    );
+    assert_eq!(data, fs::read(local.join("copy")).unwrap());
+    assert!(!local.join("copy.part").exists());
+}
+
+#[test]
+fn file_transfer_removes_part_file_with_wrong_checksum() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let root = common::test_dir("file_transfer_checksum_root");
+    let local = common::test_dir("file_transfer_checksum_local");
+    let data = file_transfer_data();
+    fs::write(local.join("file"), &data).unwrap();
+    fs::write(root.join("file"), &data).unwrap();
+    fs::write(root.join("upload.part"), vec![0xff; 1000]).unwrap();
+    fs::write(local.join("copy.part"), vec![0xff; 1000]).unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_file_transfer(FileTransfer::new(&root));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let put = || FileTransferClient::put(local.join("file"), "upload");
+    assert!(common::run_service(put(), port, &mut runtime).is_err());
+    assert!(!root.join("upload.part").exists());
+    common::run_service(put(), port, &mut runtime).expect("Uploads file");
+    assert_eq!(data, fs::read(root.join("upload")).unwrap());
+
+    let get = || FileTransferClient::get("file", local.join("copy"));
+    match common::run_service(get(), port, &mut runtime) {
+        Err(Error::ChecksumMismatch) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(!local.join("copy.part").exists());
+    common::run_service(get(), port, &mut runtime).expect("Downloads file");
+    assert_eq!(data, fs::read(local.join("copy")).unwrap());
+}
+
+#[test]
+fn file_transfer_rejects_paths_outside_of_root() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let root = common::test_dir("file_transfer_confine_root");
+    let outside = common::test_dir("file_transfer_confine_outside");
+    fs::write(outside.join("secret"), b"secret").unwrap();
+    symlink(&outside, root.join("dir_link")).unwrap();
+    symlink(outside.join("secret"), root.join("file_link")).unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_file_transfer(FileTransfer::new(&root));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let clients = vec![
+        FileTransferClient::stat("../file_transfer_confine_outside/secret"),
+        FileTransferClient::list("dir_link"),
+        FileTransferClient::stat("dir_link/secret"),
+        FileTransferClient::get("file_link", outside.join("copy")),
+        FileTransferClient::put(outside.join("secret"), "dir_link/upload"),
+    ];
+
+    for client in clients {
+        match common::run_service(client, port, &mut runtime) {
+            Err(Error::BadArguments(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+    assert!(!outside.join("upload").exists());
+}
+
+#[test]
+fn file_transfer_rejects_concurrent_uploads_of_the_same_file() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let root = common::test_dir("file_transfer_concurrent_root");
+    let local = common::test_dir("file_transfer_concurrent_local");
+    let data = file_transfer_data();
+    fs::write(local.join("file"), &data).unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_file_transfer(FileTransfer::new(&root));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    // Lock the `.part` file, like a running upload.
+    let part = File::create(root.join("file.part")).unwrap();
+    assert_eq!(0, unsafe { libc::flock(part.as_raw_fd(), libc::LOCK_EX) });
+
+    let put = || FileTransferClient::put(local.join("file"), "file");
+    assert!(common::run_service(put(), port, &mut runtime).is_err());
+    assert!(!root.join("file").exists());
+
+    drop(part);
+    common::run_service(put(), port, &mut runtime).expect("Uploads file");
+    assert_eq!(data, fs::read(root.join("file")).unwrap());
+}
+
+/// Start a peer with `Update`, the apply hook checks that its stdin is the staged image and
+/// copies the image to `applied`.
+fn start_peer_with_update(staging: &Path, applied: &Path, runtime: &mut Runtime) -> u16 {
+    let port = common::start_bearer(runtime.executor());
+    let hook = format!(
+        "cmp - \"$CARRIER_UPDATE_IMAGE\" && cp \"$CARRIER_UPDATE_IMAGE\" {}",
+        applied.display()
+    );
+    let update = Update::new(common::update_public_key())
+        .set_staging_dir(staging)
+        .set_apply_hook(vec!["sh".into(), "-c".into(), hook]);
+    let config = builtin_services::Config::new().enable_update(update);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+    port
+}
+
+#[test]
+fn update_applies_signed_image() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("update_signed");
+    let staging = dir.join("staging");
+    let applied = dir.join("applied");
+    let port = start_peer_with_update(&staging, &applied, &mut runtime);
+
+    let data = b"carrier update image";
+    fs::write(dir.join("image"), data).unwrap();
+    let client = UpdateClient::install(dir.join("image"), common::sign_update_image(data)).unwrap();
+    assert_eq!(
+        UpdateStatus::Applied,
+        common::run_service(client, port, &mut runtime).expect("Installs image")
+    );
+    assert_eq!(&data[..], &fs::read(&applied).unwrap()[..]);
+    assert_eq!(0o700, fs::metadata(&staging).unwrap().mode() & 0o777);
+}
+
+#[test]
+fn update_rejects_image_with_bad_signature() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("update_bad_signature");
+    let staging = dir.join("staging");
+    let applied = dir.join("applied");
+    let port = start_peer_with_update(&staging, &applied, &mut runtime);
+
+    fs::write(dir.join("image"), b"carrier update image").unwrap();
+    let signature = common::sign_update_image(b"other image");
+    let client = UpdateClient::install(dir.join("image"), signature).unwrap();
+    assert!(common::run_service(client, port, &mut runtime).is_err());
+    assert!(!applied.exists());
+    assert!(!staging.join("image").exists());
+}
+
+#[test]
+fn update_rejects_staging_dir_that_is_accessible_by_others() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("update_public_staging");
+    let staging = dir.join("staging");
+    let applied = dir.join("applied");
+    fs::create_dir(&staging).unwrap();
+    fs::set_permissions(&staging, fs::Permissions::from_mode(0o777)).unwrap();
+    let port = start_peer_with_update(&staging, &applied, &mut runtime);
+
+    let data = b"carrier update image";
+    fs::write(dir.join("image"), data).unwrap();
+    let client = UpdateClient::install(dir.join("image"), common::sign_update_image(data)).unwrap();
+    assert!(common::run_service(client, port, &mut runtime).is_err());
+    assert!(!applied.exists());
+    assert!(!staging.join("image").exists());
+}
+
+#[test]
+fn update_rejects_concurrent_operations_as_busy() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("update_concurrent");
+    let port = common::start_bearer(runtime.executor());
+    let update = Update::new(common::update_public_key())
+        .set_staging_dir(dir.join("staging"))
+        .set_commit_hook(vec!["sleep".into(), "3".into()]);
+    let config = builtin_services::Config::new().enable_update(update);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let _peer = common::spawn_service(UpdateClient::commit(), port, &mut runtime);
+    // Give the first commit time to start.
+    thread::sleep(Duration::from_secs(1));
+
+    match common::run_service(UpdateClient::commit(), port, &mut runtime) {
+        Err(Error::Busy(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // The operation is released, when the first commit finished.
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(
+        UpdateStatus::Committed,
+        common::run_service(UpdateClient::commit(), port, &mut runtime).expect("Commits image")
+    );
+}
+
+#[test]
+fn telemetry_sends_samples() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let telemetry = Telemetry::new().add_gauge("test", || 42.0);
+    let config = builtin_services::Config::new().enable_telemetry(telemetry);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let (sender, samples) = unbounded();
+    let client = TelemetryClient::new(Duration::from_secs(1), sender);
+    let _peer = common::spawn_service(client, port, &mut runtime);
+
+    let sample = match runtime.block_on(samples.into_future()) {
+        Ok((Some(sample), _)) => sample,
+        _ => panic!("Receives no sample"),
+    };
+    assert_eq!(Some(&42.0), sample.gauges.get("test"));
+    assert!(sample.timestamp > 0);
+}
+
+#[test]
+fn telemetry_is_only_started_when_enabled_and_allowed() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::builtin_services(builtin_services::Config::new())
+        .start(port, runtime.executor());
+
+    let client = TelemetryClient::new(Duration::from_secs(1), unbounded().0);
+    match common::run_service(client, port, &mut runtime) {
+        Err(Error::NotFound(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new()
+        .enable_telemetry(Telemetry::new())
+        .set_acl("telemetry", ServiceAcl::deny_all());
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let client = TelemetryClient::new(Duration::from_secs(1), unbounded().0);
+    match common::run_service(client, port, &mut runtime) {
+        Err(Error::Unauthorized(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn telemetry_rejects_interval_below_minimum() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_telemetry(Telemetry::new());
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let client = TelemetryClient::new(Duration::from_millis(10), unbounded().0);
+    match common::run_service(client, port, &mut runtime) {
+        Err(Error::BadArguments(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn logs_sends_appended_lines() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("logs_lines");
+    let log = dir.join("log");
+    fs::write(&log, b"existing line\n").unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_logs(Logs::new().allow_file(&log));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let (sender, entries) = unbounded();
+    let client = LogsClient::new(vec![LogSource::File(log.clone())], sender);
+    let _peer = common::spawn_service(client, port, &mut runtime);
+    // Give the server time to open the file, the existing content is skipped.
+    thread::sleep(Duration::from_secs(2));
+
+    // Lines longer than 64KiB are split.
+    let long_line = vec![b'a'; 100 * 1024];
+    let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+    file.write_all(b"first line\n").unwrap();
+    file.write_all(&long_line).unwrap();
+    file.write_all(b"\nlast line\n").unwrap();
+
+    let messages = runtime
+        .block_on(entries.take(4).map(|e| e.message).collect())
+        .expect("Receives log entries");
+    assert_eq!(
+        vec![
+            "first line".to_string(),
+            "a".repeat(64 * 1024),
+            "a".repeat(36 * 1024),
+            "last line".to_string(),
+        ],
+        messages
+    );
+}
+
+#[test]
+fn logs_rejects_disallowed_sources() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let dir = common::test_dir("logs_rejects");
+    let log = dir.join("log");
+    fs::write(&log, b"").unwrap();
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_logs(Logs::new().allow_file(&log));
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let clients = vec![
+        LogsClient::new(vec![LogSource::File(dir.join("other"))], unbounded().0),
+        LogsClient::new(vec![LogSource::Journal], unbounded().0),
+        LogsClient::new(vec![LogSource::File(log)], unbounded().0).pattern("("),
+    ];
+
+    for client in clients {
+        match common::run_service(client, port, &mut runtime) {
+            Err(Error::BadArguments(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
+
+/// Start a peer with a `ConfigStore` that is persisted to the given file.
+fn start_peer_with_config_store(path: &Path, runtime: &mut Runtime) -> u16 {
+    let port = common::start_bearer(runtime.executor());
+    let config_store = ConfigStore::new(path).expect("Creates config store");
+    let config = builtin_services::Config::new().enable_config_store(config_store);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+    port
+}
+
+fn config_entry(value: &str, version: u64) -> ConfigEntry {
+    ConfigEntry {
+        value: value.into(),
+        version,
+    }
+}
+
+#[test]
+fn config_store_rejects_writes_with_outdated_version() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let path = common::test_dir("config_store_versions").join("config.json");
+    let port = start_peer_with_config_store(&path, &mut runtime);
+
+    let mut run = |client| common::run_service(client, port, &mut runtime);
+    assert_eq!(
+        ConfigStoreResult::Written(config_entry("1", 1)),
+        run(ConfigStoreClient::compare_and_set("key", "1", 0)).unwrap()
+    );
+    match run(ConfigStoreClient::compare_and_set("key", "2", 0)) {
+        Err(Error::VersionConflict {
+            ref key,
+            current: 1,
+        }) if key == "key" => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        ConfigStoreResult::Written(config_entry("2", 2)),
+        run(ConfigStoreClient::compare_and_set("key", "2", 1)).unwrap()
+    );
+    match run(ConfigStoreClient::compare_and_delete("key", 1)) {
+        Err(Error::VersionConflict { current: 2, .. }) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        ConfigStoreResult::Entry(Some(config_entry("2", 2))),
+        run(ConfigStoreClient::get("key")).unwrap()
+    );
+
+    // The store is persisted.
+    let port = start_peer_with_config_store(&path, &mut runtime);
+    assert_eq!(
+        ConfigStoreResult::Entry(Some(config_entry("2", 2))),
+        common::run_service(ConfigStoreClient::get("key"), port, &mut runtime).unwrap()
+    );
+}
+
+#[test]
+fn config_store_sends_changes_to_watchers() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let path = common::test_dir("config_store_watch").join("config.json");
+    let port = start_peer_with_config_store(&path, &mut runtime);
+    common::run_service(ConfigStoreClient::set("watched/a", "1"), port, &mut runtime).unwrap();
+
+    let (sender, changes) = unbounded();
+    let _peer = common::spawn_service(
+        ConfigStoreClient::watch("watched/", sender),
+        port,
+        &mut runtime,
+    );
+    // Give the watcher time to register.
+    thread::sleep(Duration::from_secs(1));
+
+    for client in vec![
+        ConfigStoreClient::set("other/b", "2"),
+        ConfigStoreClient::set("watched/b", "3"),
+        ConfigStoreClient::delete("watched/a"),
+    ] {
+        common::run_service(client, port, &mut runtime).unwrap();
+    }
+
+    let changes = runtime
+        .block_on(changes.take(3).collect())
+        .expect("Receives changes");
+    assert_eq!(
+        vec![
+            ConfigChange {
+                key: "watched/a".into(),
+                entry: Some(config_entry("1", 1)),
+            },
+            ConfigChange {
+                key: "watched/b".into(),
+                entry: Some(config_entry("3", 3)),
+            },
+            ConfigChange {
+                key: "watched/a".into(),
+                entry: None,
+            },
+        ],
+        changes
+    );
+}
+
+#[test]
+fn events_sends_buffered_and_new_events_of_subscribed_topics() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let events = Events::new();
+    let publisher = events.publisher();
+    for topic in &["a/1", "b/1", "a/2"] {
+        publisher.publish(*topic, &topic.to_string()).unwrap();
+    }
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new().enable_events(events);
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    let (sender, received) = unbounded();
+    let client = EventsClient::new(vec!["a/*".into()], sender).since(0);
+    let _peer = common::spawn_service(client, port, &mut runtime);
+    // Events that are published before the subscription are send from the buffer.
+    publisher.publish("a/3", &"a/3").unwrap();
+
+    let received = runtime
+        .block_on(received.take(3).collect())
+        .expect("Receives events");
+    assert_eq!(
+        vec![(1, "a/1".to_string()), (3, "a/2".into()), (4, "a/3".into())],
+        received
+            .into_iter()
+            .map(|e| (e.id, e.topic))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn events_is_only_started_when_enabled_and_allowed() {
+    let mut runtime = Runtime::new().expect("Creates runtime");
+
+    let port = common::start_bearer(runtime.executor());
+    common::PeerOptions::builtin_services(builtin_services::Config::new())
+        .start(port, runtime.executor());
+
+    match common::run_service(EventsClient::new(vec![], unbounded().0), port, &mut runtime) {
+        Err(Error::NotFound(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let port = common::start_bearer(runtime.executor());
+    let config = builtin_services::Config::new()
+        .enable_events(Events::new())
+        .set_acl("events", ServiceAcl::deny_all());
+    common::PeerOptions::builtin_services(config).start(port, runtime.executor());
+
+    match common::run_service(EventsClient::new(vec![], unbounded().0), port, &mut runtime) {
+        Err(Error::Unauthorized(_)) => {}
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}